@@ -9,9 +9,6 @@ use bindgen::Builder;
 
 extern crate selfe_config;
 use selfe_config::build_helpers::*;
-use selfe_config::compilation::{
-    build_sel4, resolve_sel4_sources, ResolvedSeL4Source, SeL4BuildMode, SeL4BuildOutcome,
-};
 use selfe_config::model::{self, Arch, SeL4Arch};
 
 extern crate proc_macro2;
@@ -446,41 +443,27 @@ fn main() {
 
     let config = load_config_from_env_or_default();
     config.print_boolean_feature_flags();
-    let is_verbose = false;
-
-    let ResolvedSeL4Source {
-        kernel_dir,
-        tools_dir,
-        util_libs_dir,
-    } = resolve_sel4_sources(
-        &config.sel4_sources,
-        &out_dir.join("sel4_source"),
-        is_verbose,
-    )
-    .expect("resolve sel4 source");
-
-    let build_dir = if let SeL4BuildOutcome::StaticLib { build_dir } = build_sel4(
-        &out_dir,
-        &kernel_dir,
-        &tools_dir,
-        &util_libs_dir,
-        &config,
-        SeL4BuildMode::Lib,
-    ) {
-        build_dir
-    } else {
-        panic!("build_sel4 built us something other than a static library");
-    };
+
+    // Built in the shared cache, so that every crate in the workspace
+    // with the same configuration links against the same libsel4
+    let LibSel4Build {
+        sources,
+        build_dir,
+        libraries,
+    } = build_libsel4(&config);
 
     println!("cargo:rustc-link-lib=static=sel4");
     println!(
         "cargo:rustc-link-search=native={}/libsel4",
         build_dir.display()
     );
+    for library in libraries.iter() {
+        library.print_cargo_link_directives();
+    }
 
     gen_bindings(
         &out_dir,
-        &kernel_dir,
+        &sources.kernel_dir,
         &build_dir,
         config.context.arch,
         config.context.sel4_arch,
@@ -0,0 +1,239 @@
+//! Discovery of the files produced by a kernel build
+//!
+//! Rather than predicting file names from the configuration, the build tree is
+//! read after the build, so that whatever seL4's build system produced is found.
+
+use serde_json::{json, Value};
+use std::ffi::OsStr;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Dirs of generated headers, relative to the build dir
+const GENERATED_HEADER_DIRS: &[&str] = &[
+    "kernel/gen_config",
+    "kernel/gen_headers",
+    "libsel4/autoconf",
+    "libsel4/gen_config",
+    "libsel4/include",
+    "libsel4/arch_include",
+    "libsel4/sel4_arch_include",
+];
+
+/// How deep within the build dir to look for CapDL specs
+const CAPDL_SEARCH_DEPTH: usize = 4;
+
+/// What a build artifact is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ArtifactKind {
+    /// A bootable kernel image, from `images/kernel-*`
+    KernelImage,
+    /// A bootable image holding the root task, from `images/`, which on
+    /// ARM is the elfloader image bundling the kernel as well
+    RootImage,
+    /// The kernel ELF, with symbols
+    KernelElf,
+    /// The elfloader ELF
+    Elfloader,
+    /// A device tree blob
+    Dtb,
+    /// A dir of generated headers
+    GeneratedHeaders,
+    /// A CapDL specification
+    CapDl,
+}
+
+impl ArtifactKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ArtifactKind::KernelImage => "kernel_image",
+            ArtifactKind::RootImage => "root_image",
+            ArtifactKind::KernelElf => "kernel_elf",
+            ArtifactKind::Elfloader => "elfloader",
+            ArtifactKind::Dtb => "dtb",
+            ArtifactKind::GeneratedHeaders => "generated_headers",
+            ArtifactKind::CapDl => "capdl",
+        }
+    }
+}
+
+impl Display for ArtifactKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A file or dir produced by a kernel build
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    pub path: PathBuf,
+    /// For dirs, the total size of their files
+    pub size_bytes: u64,
+}
+
+impl Artifact {
+    fn new(kind: ArtifactKind, path: PathBuf) -> Artifact {
+        Artifact {
+            kind,
+            size_bytes: crate::build_cache::dir_size(&path),
+            path,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "kind": self.kind.as_str(),
+            "path": self.path.display().to_string(),
+            "size_bytes": self.size_bytes,
+        })
+    }
+}
+
+/// Find the artifacts of a kernel build in `build_dir`, ordered by kind and then path
+pub fn kernel_artifacts(build_dir: &Path) -> io::Result<Vec<Artifact>> {
+    let mut artifacts = Vec::new();
+
+    let images_dir = build_dir.join("images");
+    if images_dir.is_dir() {
+        for entry in fs::read_dir(&images_dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let kind = if name.starts_with("kernel-") {
+                ArtifactKind::KernelImage
+            } else {
+                ArtifactKind::RootImage
+            };
+            artifacts.push(Artifact::new(kind, entry.path()));
+        }
+    }
+
+    for (kind, candidates) in [
+        (
+            ArtifactKind::KernelElf,
+            &["kernel/kernel.elf", "kernel.elf"][..],
+        ),
+        (ArtifactKind::Elfloader, &["elfloader/elfloader"][..]),
+    ]
+    .iter()
+    {
+        if let Some(path) = candidates
+            .iter()
+            .map(|c| build_dir.join(c))
+            .find(|p| p.is_file())
+        {
+            artifacts.push(Artifact::new(*kind, path));
+        }
+    }
+
+    let kernel_dir = build_dir.join("kernel");
+    if kernel_dir.is_dir() {
+        for entry in fs::read_dir(&kernel_dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension() == Some(OsStr::new("dtb")) {
+                artifacts.push(Artifact::new(ArtifactKind::Dtb, path));
+            }
+        }
+    }
+
+    for dir in GENERATED_HEADER_DIRS.iter() {
+        let path = build_dir.join(dir);
+        if path.is_dir() {
+            artifacts.push(Artifact::new(ArtifactKind::GeneratedHeaders, path));
+        }
+    }
+
+    find_capdl_specs(build_dir, CAPDL_SEARCH_DEPTH, &mut artifacts)?;
+
+    artifacts.sort_by(|a, b| (a.kind, &a.path).cmp(&(b.kind, &b.path)));
+    Ok(artifacts)
+}
+
+fn find_capdl_specs(dir: &Path, depth: usize, artifacts: &mut Vec<Artifact>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() && depth > 0 {
+            find_capdl_specs(&path, depth - 1, artifacts)?;
+        } else if file_type.is_file() && path.extension() == Some(OsStr::new("cdl")) {
+            artifacts.push(Artifact::new(ArtifactKind::CapDl, path));
+        }
+    }
+    Ok(())
+}
+
+/// The first artifact of the given kind
+pub fn find_artifact(artifacts: &[Artifact], kind: ArtifactKind) -> Option<&Artifact> {
+    artifacts.iter().find(|a| a.kind == kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn touch(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"content").unwrap();
+    }
+
+    #[test]
+    fn finds_arm_build_artifacts() {
+        let dir = tempdir().expect("Could not make a temp dir");
+        let build_dir = dir.path();
+        touch(&build_dir.join("images/root_task-image-arm-imx6"));
+        touch(&build_dir.join("kernel/kernel.elf"));
+        touch(&build_dir.join("kernel/kernel.dtb"));
+        touch(&build_dir.join("elfloader/elfloader"));
+        touch(&build_dir.join("kernel/gen_config/kernel/gen_config.h"));
+        touch(&build_dir.join("capdl/spec.cdl"));
+        touch(&build_dir.join("CMakeCache.txt"));
+
+        let artifacts = kernel_artifacts(build_dir).unwrap();
+        let kinds: Vec<ArtifactKind> = artifacts.iter().map(|a| a.kind).collect();
+        assert_eq!(
+            vec![
+                ArtifactKind::RootImage,
+                ArtifactKind::KernelElf,
+                ArtifactKind::Elfloader,
+                ArtifactKind::Dtb,
+                ArtifactKind::GeneratedHeaders,
+                ArtifactKind::CapDl,
+            ],
+            kinds
+        );
+        assert_eq!(
+            build_dir.join("images/root_task-image-arm-imx6"),
+            find_artifact(&artifacts, ArtifactKind::RootImage)
+                .unwrap()
+                .path
+        );
+        assert_eq!(7, artifacts[0].size_bytes);
+        assert!(find_artifact(&artifacts, ArtifactKind::KernelImage).is_none());
+    }
+
+    #[test]
+    fn finds_x86_kernel_and_root_images() {
+        let dir = tempdir().expect("Could not make a temp dir");
+        let build_dir = dir.path();
+        touch(&build_dir.join("images/kernel-x86_64-pc99"));
+        touch(&build_dir.join("images/root_task-image-x86_64-pc99"));
+
+        let artifacts = kernel_artifacts(build_dir).unwrap();
+        assert_eq!(
+            build_dir.join("images/kernel-x86_64-pc99"),
+            find_artifact(&artifacts, ArtifactKind::KernelImage)
+                .unwrap()
+                .path
+        );
+        assert_eq!(
+            "root_image",
+            artifacts[1].to_json()["kind"].as_str().unwrap()
+        );
+    }
+}
@@ -0,0 +1,494 @@
+//! Location, locking, inspection and garbage collection of the shared seL4 cache
+//!
+//! Both `selfe build` and the `selfe-sys` build script resolve seL4 sources into
+//! `<cache>/source` and build into `<cache>/build`, where the cache defaults to
+//! `sel4` within the cargo target dir and can be moved with `SEL4_CACHE_DIR`.
+//! Each source checkout and build dir is guarded by a sibling `.lock` file, so
+//! concurrent builds of the same configuration wait for one another rather than
+//! racing, and the second build finds the work already done.
+//!
+//! Every distinct set of build inputs gets its own build dir, so old dirs pile up
+//! as configurations change. The `build-info.json` recorded in each dir says when
+//! it was last used and which configuration it was built from, which is enough
+//! to decide what can be deleted.
+
+use crate::build_info::{sha256_hex, BuildInfo, KERNEL_PATH_PLACEHOLDER};
+use crate::compilation::{
+    cmake_lists_template, cmake_options, configured_source_identity, input_context, SeL4BuildMode,
+};
+use crate::model::contextualized::Contextualized;
+use crate::model::{full, Arch, Platform, SeL4Arch};
+use fs2::FileExt;
+use std::cmp::Reverse;
+use std::env;
+use std::fmt::{self, Display};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// Environment variable which overrides the location of the shared seL4 cache
+pub const CACHE_DIR_ENV_VAR: &str = "SEL4_CACHE_DIR";
+
+/// The shared seL4 cache dir: `SEL4_CACHE_DIR` when set, otherwise `sel4`
+/// within the given cargo target dir
+pub fn shared_cache_dir(target_dir: &Path) -> PathBuf {
+    match env::var_os(CACHE_DIR_ENV_VAR) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => target_dir.join("sel4"),
+    }
+}
+
+/// The cargo target dir that a build script's `OUT_DIR` lives within.
+///
+/// That is the nearest ancestor holding cargo's `CACHEDIR.TAG`, or failing that
+/// the dir above `<profile>/build/<package>-<hash>/out`.
+pub fn target_dir_for_out_dir(out_dir: &Path) -> PathBuf {
+    out_dir
+        .ancestors()
+        .find(|d| d.join("CACHEDIR.TAG").is_file())
+        .or_else(|| out_dir.ancestors().nth(4))
+        .unwrap_or(out_dir)
+        .to_path_buf()
+}
+
+/// The lock file guarding a source or build dir within the cache
+pub fn lock_path(dir: &Path) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    dir.with_file_name(name)
+}
+
+/// An exclusive lock on part of the shared cache, released when dropped
+#[derive(Debug)]
+pub struct CacheLock {
+    _file: File,
+}
+
+impl CacheLock {
+    fn open(path: &Path) -> io::Result<File> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+    }
+
+    /// Lock the file at `path`, creating it if need be, and waiting for
+    /// any other holder to release it
+    pub fn acquire(path: &Path) -> io::Result<CacheLock> {
+        loop {
+            let file = CacheLock::open(path)?;
+            if file.try_lock_exclusive().is_err() {
+                eprintln!("Blocking waiting for file lock on {}", path.display());
+                file.lock_exclusive()?;
+            }
+            if is_current(&file, path) {
+                return Ok(CacheLock { _file: file });
+            }
+        }
+    }
+
+    /// Lock the file at `path` only if no one else holds it
+    pub fn try_acquire(path: &Path) -> io::Result<Option<CacheLock>> {
+        loop {
+            let file = CacheLock::open(path)?;
+            match file.try_lock_exclusive() {
+                Ok(()) if is_current(&file, path) => return Ok(Some(CacheLock { _file: file })),
+                Ok(()) => continue,
+                Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Whether the locked `file` is still the one at `path`, rather than one
+/// `remove_build` deleted while it was being waited on
+#[cfg(unix)]
+fn is_current(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(locked), Ok(current)) => locked.dev() == current.dev() && locked.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_current(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
+
+/// Remove a build dir and then its lock file, while holding that lock.
+/// `Ok(false)` when the dir is left alone as another build holds the lock.
+pub fn remove_build(dir: &Path) -> io::Result<bool> {
+    let lock = lock_path(dir);
+    let _held = match CacheLock::try_acquire(&lock)? {
+        Some(held) => held,
+        None => return Ok(false),
+    };
+    fs::remove_dir_all(dir)?;
+    fs::remove_file(&lock)?;
+    Ok(true)
+}
+
+/// A single build dir within a `sel4-build` cache dir
+#[derive(Debug, Clone)]
+pub struct CachedBuild {
+    pub dir: PathBuf,
+    /// Absent for dirs without a readable `build-info.json`
+    pub info: Option<BuildInfo>,
+    pub size_bytes: u64,
+}
+
+impl CachedBuild {
+    /// When the build was last used, falling back to the dir's modification time
+    pub fn last_used(&self) -> Option<SystemTime> {
+        match self.info {
+            Some(ref info) => Some(info.last_used),
+            None => fs::metadata(&self.dir).and_then(|m| m.modified()).ok(),
+        }
+    }
+}
+
+/// Why a build dir is selected for removal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalReason {
+    /// Older than the N most recently used builds
+    NotRecent { keep: usize },
+    /// Its inputs no longer correspond to any context of the configuration
+    NoMatchingContext,
+}
+
+impl Display for RemovalReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemovalReason::NotRecent { keep } => {
+                write!(f, "not among the {} most recently used", keep)
+            }
+            RemovalReason::NoMatchingContext => write!(f, "no longer matches sel4.toml"),
+        }
+    }
+}
+
+/// List the build dirs in `cache_dir`, most recently used first
+pub fn list_builds(cache_dir: &Path) -> io::Result<Vec<CachedBuild>> {
+    let mut builds = Vec::new();
+    if !cache_dir.exists() {
+        return Ok(builds);
+    }
+    for entry in fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let dir = entry.path();
+        builds.push(CachedBuild {
+            info: BuildInfo::read(&dir),
+            size_bytes: dir_size(&dir),
+            dir,
+        });
+    }
+    builds.sort_by_key(|b| Reverse(b.last_used()));
+    Ok(builds)
+}
+
+/// Total size of the files within `path`, not following symlinks
+pub fn dir_size(path: &Path) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|e| dir_size(&e.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Whether the recorded inputs of a build still correspond to the context
+/// they were built for in the given configuration
+pub fn matches_config(info: &BuildInfo, config: &full::Full, base_dir: Option<&Path>) -> bool {
+    let inputs = &info.inputs;
+    let context = |key: &str| inputs.context.get(key).map(String::as_str);
+    let (platform, arch, sel4_arch, is_debug, build_mode) = match (
+        context("platform"),
+        context("arch").and_then(|s| Arch::from_str(s).ok()),
+        context("sel4_arch").and_then(|s| SeL4Arch::from_str(s).ok()),
+        context("profile"),
+        SeL4BuildMode::from_str(&inputs.build_mode).ok(),
+    ) {
+        (Some(p), Some(a), Some(s), Some(profile), Some(m)) => (p, a, s, profile == "debug", m),
+        _ => return false,
+    };
+    let contextualized = match Contextualized::from_full(
+        config,
+        arch,
+        sel4_arch,
+        is_debug,
+        Platform(platform.to_string()),
+        base_dir,
+    ) {
+        Ok(c) => c,
+        Err(_) => return false,
+    };
+
+    if input_context(&contextualized, build_mode) != inputs.context
+        || sha256_hex(cmake_lists_template(build_mode).as_bytes()) != inputs.cmake_lists_sha256
+        || cmake_options(
+            &contextualized,
+            Path::new(KERNEL_PATH_PLACEHOLDER),
+            build_mode,
+        ) != inputs.cmake_options
+    {
+        return false;
+    }
+
+    contextualized
+        .sel4_sources
+        .named()
+        .iter()
+        .all(|(name, source)| {
+            match (
+                configured_source_identity(source, contextualized.context.base_dir.as_deref()),
+                inputs.sources.get(*name),
+            ) {
+                (Ok(expected), Some(recorded)) => {
+                    expected.iter().all(|(k, v)| recorded.get(k) == Some(v))
+                }
+                _ => false,
+            }
+        })
+}
+
+/// Decide which builds to remove. `builds` must be ordered most recently used first,
+/// as from `list_builds`.
+///
+/// When `keep_recent` is supplied, builds beyond that many are removed. When `config`
+/// is supplied, builds that don't correspond to it are removed.
+pub fn plan_removals(
+    builds: &[CachedBuild],
+    keep_recent: Option<usize>,
+    config: Option<(&full::Full, Option<&Path>)>,
+) -> Vec<Option<RemovalReason>> {
+    builds
+        .iter()
+        .enumerate()
+        .map(|(i, build)| {
+            if let Some((config, base_dir)) = config {
+                let matches = match build.info {
+                    Some(ref info) => matches_config(info, config, base_dir),
+                    None => false,
+                };
+                if !matches {
+                    return Some(RemovalReason::NoMatchingContext);
+                }
+            }
+            match keep_recent {
+                Some(keep) if i >= keep => Some(RemovalReason::NotRecent { keep }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_info::BuildInputs;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::tempdir;
+
+    const CONFIG: &str = r#"[sel4]
+kernel = { git = "https://github.com/seL4/seL4", rev = "4d0f02c029560cae0e8d93727eb17d58bcecc2ac" }
+tools = { git = "https://github.com/seL4/seL4_tools", rev = "f3b4bef672b92858c139787bc6fd5124e6022d26" }
+util_libs = { path = "../util_libs" }
+
+[sel4.config.sabre]
+KernelSel4Arch = 'aarch32'
+KernelARMPlatform = 'imx6'
+
+[build.sabre]
+cross_compiler_prefix = "arm-linux-gnueabihf-"
+"#;
+
+    fn recorded_build(config: &full::Full, age_secs: u64) -> CachedBuild {
+        let c = Contextualized::from_full(
+            config,
+            Arch::Arm,
+            SeL4Arch::Aarch32,
+            true,
+            Platform("sabre".to_string()),
+            Some(Path::new("/project")),
+        )
+        .unwrap();
+        let mode = SeL4BuildMode::Lib;
+        let mut inputs = BuildInputs {
+            build_mode: mode.to_string(),
+            context: input_context(&c, mode),
+            cmake_options: cmake_options(&c, Path::new(KERNEL_PATH_PLACEHOLDER), mode),
+            cmake_lists_sha256: sha256_hex(cmake_lists_template(mode).as_bytes()),
+            ..Default::default()
+        };
+        for (name, source) in [
+            ("kernel", &c.sel4_sources.kernel),
+            ("tools", &c.sel4_sources.tools),
+            ("util_libs", &c.sel4_sources.util_libs),
+        ]
+        .iter()
+        {
+            let mut identity =
+                configured_source_identity(source, c.context.base_dir.as_deref()).unwrap();
+            identity.insert("tree".to_string(), "abc123".to_string());
+            inputs.sources.insert(name.to_string(), identity);
+        }
+        CachedBuild {
+            dir: PathBuf::from(inputs.dir_name()),
+            info: Some(BuildInfo {
+                digest: inputs.digest(),
+                last_used: UNIX_EPOCH + Duration::from_secs(1_000_000 - age_secs),
+                inputs,
+            }),
+            size_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn recorded_build_matches_its_config() {
+        let config: full::Full = CONFIG.parse().unwrap();
+        let build = recorded_build(&config, 0);
+        assert!(matches_config(
+            build.info.as_ref().unwrap(),
+            &config,
+            Some(Path::new("/project"))
+        ));
+    }
+
+    #[test]
+    fn changed_config_no_longer_matches() {
+        let config: full::Full = CONFIG.parse().unwrap();
+        let build = recorded_build(&config, 0);
+        let changed: full::Full = CONFIG
+            .replace("KernelARMPlatform = 'imx6'", "KernelARMPlatform = 'imx7'")
+            .parse()
+            .unwrap();
+        assert!(!matches_config(
+            build.info.as_ref().unwrap(),
+            &changed,
+            Some(Path::new("/project"))
+        ));
+        let moved_source: full::Full = CONFIG
+            .replace("../util_libs", "../other_util_libs")
+            .parse()
+            .unwrap();
+        assert!(!matches_config(
+            build.info.as_ref().unwrap(),
+            &moved_source,
+            Some(Path::new("/project"))
+        ));
+    }
+
+    #[test]
+    fn plan_keeps_most_recent_and_matching() {
+        let config: full::Full = CONFIG.parse().unwrap();
+        let changed: full::Full = CONFIG
+            .replace("arm-linux-gnueabihf-", "arm-none-eabi-")
+            .parse()
+            .unwrap();
+        let builds = vec![
+            recorded_build(&config, 10),
+            recorded_build(&changed, 20),
+            recorded_build(&config, 30),
+            CachedBuild {
+                dir: PathBuf::from("no-info"),
+                info: None,
+                size_bytes: 0,
+            },
+        ];
+        assert_eq!(
+            vec![
+                None,
+                None,
+                Some(RemovalReason::NotRecent { keep: 2 }),
+                Some(RemovalReason::NotRecent { keep: 2 }),
+            ],
+            plan_removals(&builds, Some(2), None)
+        );
+        assert_eq!(
+            vec![
+                None,
+                Some(RemovalReason::NoMatchingContext),
+                None,
+                Some(RemovalReason::NoMatchingContext),
+            ],
+            plan_removals(&builds, None, Some((&config, Some(Path::new("/project")))))
+        );
+        assert_eq!(
+            vec![None, None, None, None],
+            plan_removals(&builds, None, None)
+        );
+    }
+
+    #[test]
+    fn lock_excludes_other_holders_until_dropped() {
+        let dir = tempdir().expect("Could not make a temp dir");
+        let path = lock_path(&dir.path().join("sel4-build").join("0123456789abcdef"));
+        assert_eq!(
+            dir.path().join("sel4-build").join("0123456789abcdef.lock"),
+            path
+        );
+        let held = CacheLock::acquire(&path).unwrap();
+        assert!(CacheLock::try_acquire(&path).unwrap().is_none());
+        drop(held);
+        assert!(CacheLock::try_acquire(&path).unwrap().is_some());
+    }
+
+    #[test]
+    fn removed_builds_take_their_lock_files() {
+        let cache = tempdir().expect("Could not make a temp dir");
+        let build = cache.path().join("0123456789abcdef");
+        fs::create_dir_all(build.join("kernel")).unwrap();
+        let lock = lock_path(&build);
+
+        let held = CacheLock::acquire(&lock).unwrap();
+        assert!(!remove_build(&build).unwrap());
+        assert!(build.exists());
+        drop(held);
+
+        assert!(remove_build(&build).unwrap());
+        assert!(!build.exists());
+        assert!(!lock.exists());
+        assert!(fs::read_dir(cache.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn target_dir_is_found_from_out_dir() {
+        let dir = tempdir().expect("Could not make a temp dir");
+        let out_dir = dir
+            .path()
+            .join("target")
+            .join("x86_64-unknown-linux-gnu")
+            .join("debug")
+            .join("build")
+            .join("selfe-sys-0123456789abcdef")
+            .join("out");
+        assert_eq!(
+            dir.path().join("target").join("x86_64-unknown-linux-gnu"),
+            target_dir_for_out_dir(&out_dir)
+        );
+        fs::create_dir_all(&out_dir).unwrap();
+        fs::write(dir.path().join("target").join("CACHEDIR.TAG"), "").unwrap();
+        assert_eq!(dir.path().join("target"), target_dir_for_out_dir(&out_dir));
+    }
+}
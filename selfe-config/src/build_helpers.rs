@@ -1,6 +1,11 @@
 //! Functions that can be called from build.rs, for when libraries need access
 //! to the sel4 configuration
 
+use crate::build_cache;
+use crate::compilation::{
+    build_sel4_with_output, built_libraries, resolve_sel4_sources, BuiltLibrary,
+    ResolvedSeL4Source, SeL4BuildMode, SeL4BuildOutcome, REFRESH_SOURCES_ENV_VAR,
+};
 use crate::model::{self, Arch, Platform, RustArch, SeL4Arch};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
@@ -15,6 +20,8 @@ pub struct BuildEnv {
     pub sel4_override_arch: Option<String>,
     pub sel4_override_sel4_arch: Option<String>,
     pub sel4_platform: Option<String>,
+    /// Where seL4 sources and builds are shared between crates, see `build_cache`
+    pub sel4_cache_dir: PathBuf,
 }
 
 pub enum BuildProfile {
@@ -24,10 +31,7 @@ pub enum BuildProfile {
 
 impl BuildProfile {
     pub fn is_debug(&self) -> bool {
-        match self {
-            BuildProfile::Debug => true,
-            _ => false,
-        }
+        matches!(self, BuildProfile::Debug)
     }
 }
 
@@ -43,6 +47,8 @@ impl BuildEnv {
             "SEL4_PLATFORM",
             "SEL4_OVERRIDE_SEL4_ARCH",
             "SEL4_OVERRIDE_ARCH",
+            build_cache::CACHE_DIR_ENV_VAR,
+            REFRESH_SOURCES_ENV_VAR,
         ]
         .iter()
         {
@@ -58,13 +64,16 @@ impl BuildEnv {
         }
         let raw_profile = get_env("PROFILE");
         let cargo_cfg_target_arch = get_env("CARGO_CFG_TARGET_ARCH");
+        let out_dir = PathBuf::from(get_env("OUT_DIR"));
+        let sel4_cache_dir =
+            build_cache::shared_cache_dir(&build_cache::target_dir_for_out_dir(&out_dir));
 
         BuildEnv {
             cargo_cfg_target_arch,
             cargo_cfg_target_pointer_width: get_env("CARGO_CFG_TARGET_POINTER_WIDTH")
                 .parse()
                 .expect("Could not parse CARGO_CFG_TARGET_POINTER_WIDTH as an unsigned integer"),
-            out_dir: PathBuf::from(get_env("OUT_DIR")),
+            out_dir,
             profile: match raw_profile.as_str() {
                 "debug" => BuildProfile::Debug,
                 "release" => BuildProfile::Release,
@@ -74,6 +83,7 @@ impl BuildEnv {
             sel4_override_arch: env::var("SEL4_OVERRIDE_ARCH").ok(),
             sel4_override_sel4_arch: env::var("SEL4_OVERRIDE_SEL4_ARCH").ok(),
             sel4_platform: env::var("SEL4_PLATFORM").ok(),
+            sel4_cache_dir,
         }
     }
 }
@@ -93,7 +103,7 @@ pub fn load_config_from_env_or_default() -> model::contextualized::Contextualize
     let (full_config, config_dir) = sel4_config_path
         .map(|config_file_path| {
             let config_file_path =
-                fs::canonicalize(&Path::new(&config_file_path)).unwrap_or_else(|_| {
+                fs::canonicalize(Path::new(&config_file_path)).unwrap_or_else(|_| {
                     panic!(
                         "Config file could not be canonicalized: {}",
                         config_file_path.display()
@@ -146,7 +156,7 @@ pub fn load_config_from_env_or_default() -> model::contextualized::Contextualize
         auto_val
     }));
 
-    model::contextualized::Contextualized::from_full(
+    let config = model::contextualized::Contextualized::from_full(
         &full_config,
         arch,
         sel4_arch,
@@ -154,7 +164,58 @@ pub fn load_config_from_env_or_default() -> model::contextualized::Contextualize
         platform,
         config_dir.as_deref(),
     )
-    .expect("Error resolving config file")
+    .expect("Error resolving config file");
+
+    for (_, source) in config.sel4_sources.named() {
+        for patch in source.patches() {
+            println!("cargo:rerun-if-changed={}", patch.display());
+        }
+    }
+    config
+}
+
+/// What `build_libsel4` built, and from where
+pub struct LibSel4Build {
+    pub sources: ResolvedSeL4Source,
+    /// The cmake build dir, holding libsel4.a within its `libsel4` dir
+    pub build_dir: PathBuf,
+    /// The additional `libraries` configured in sel4.toml
+    pub libraries: Vec<BuiltLibrary>,
+}
+
+/// Build libsel4, along with any additional configured `libraries`, in the shared
+/// seL4 cache. Crates with the same configuration reuse the same build.
+///
+/// This should be run from a build.rs. On failure the error is reported and the
+/// process exits, rather than panicking, so cargo shows just the error itself.
+pub fn build_libsel4(config: &model::contextualized::Contextualized) -> LibSel4Build {
+    fn fail(e: impl std::fmt::Display) -> ! {
+        eprintln!("error: failed to build libsel4: {}", e);
+        std::process::exit(1);
+    }
+    let BuildEnv { sel4_cache_dir, .. } = BuildEnv::from_env_vars();
+    let sources = resolve_sel4_sources(&config.sel4_sources, &sel4_cache_dir.join("source"), false)
+        .unwrap_or_else(|e| fail(e));
+    let outcome = build_sel4_with_output(
+        &sel4_cache_dir.join("build"),
+        &sources,
+        config,
+        SeL4BuildMode::Lib,
+        &mut |_, line| println!("{}", line),
+    )
+    .unwrap_or_else(|e| fail(e));
+    let build_dir = match outcome {
+        SeL4BuildOutcome::StaticLib { build_dir } => build_dir,
+        SeL4BuildOutcome::Kernel { .. } => {
+            panic!("build_sel4 built us something other than a static library")
+        }
+    };
+    let libraries = built_libraries(&build_dir, config).unwrap_or_else(|e| fail(e));
+    LibSel4Build {
+        sources,
+        build_dir,
+        libraries,
+    }
 }
 
 impl model::contextualized::Contextualized {
@@ -0,0 +1,270 @@
+//! Stable, content-based identification of seL4 build directories
+//!
+//! Each seL4 build directory is named after a SHA-256 digest of everything
+//! that feeds into that build: the effective cmake options, the CMakeLists.txt
+//! template, the content of the seL4 sources, the toolchain in use and the
+//! environment variables listed in `HASHED_ENV_VARS`.
+//!
+//! The digest is taken over the compact JSON serialization of `BuildInputs::to_json`.
+//! All maps in that serialization are sorted by key, and paths that only reflect
+//! where sources happen to be checked out are replaced by placeholders or
+//! recorded relative to the dir of sel4.toml, so the digest is the same across
+//! Rust versions, machines, checkouts and output directories.
+//!
+//! The same inputs are recorded in a `build-info.json` file within each build dir.
+
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the file, within each build dir, recording the inputs of that build
+pub const BUILD_INFO_FILE: &str = "build-info.json";
+
+/// Environment variables which influence the seL4 build, and so its build dir
+pub const HASHED_ENV_VARS: &[&str] = &[
+    "AR",
+    "AS",
+    "ASFLAGS",
+    "CC",
+    "CFLAGS",
+    "CPPFLAGS",
+    "CROSS_COMPILE",
+    "CXX",
+    "CXXFLAGS",
+    "LDFLAGS",
+    "PYTHONPATH",
+];
+
+/// Stands in for the kernel source dir within recorded cmake options
+pub const KERNEL_PATH_PLACEHOLDER: &str = "${KERNEL_PATH}";
+
+/// The number of hex digits of the digest used to name a build dir
+const DIR_NAME_LEN: usize = 16;
+
+/// Everything that determines the outcome of a single seL4 build
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BuildInputs {
+    /// "kernel" or "lib"
+    pub build_mode: String,
+    /// platform, arch, sel4_arch and profile
+    pub context: BTreeMap<String, String>,
+    /// The options passed to cmake, with source locations replaced by placeholders
+    pub cmake_options: BTreeMap<String, String>,
+    /// SHA-256 of the CMakeLists.txt template
+    pub cmake_lists_sha256: String,
+    /// Identity of each source repository, keyed by repository name
+    pub sources: BTreeMap<String, BTreeMap<String, String>>,
+    /// Identity of the compiler and build tools
+    pub toolchain: BTreeMap<String, String>,
+    /// The values of those `HASHED_ENV_VARS` that are set
+    pub env: BTreeMap<String, String>,
+}
+
+impl BuildInputs {
+    /// Capture the current values of `HASHED_ENV_VARS`
+    pub fn env_from_process() -> BTreeMap<String, String> {
+        HASHED_ENV_VARS
+            .iter()
+            .filter_map(|k| std::env::var(k).ok().map(|v| (k.to_string(), v)))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        fn nested(m: &BTreeMap<String, BTreeMap<String, String>>) -> Value {
+            Value::Object(m.iter().map(|(k, v)| (k.clone(), json!(v))).collect())
+        }
+        json!({
+            "build_mode": self.build_mode,
+            "context": self.context,
+            "cmake_options": self.cmake_options,
+            "cmake_lists_sha256": self.cmake_lists_sha256,
+            "sources": nested(&self.sources),
+            "toolchain": self.toolchain,
+            "env": self.env,
+        })
+    }
+
+    pub fn from_json(v: &Value) -> Option<BuildInputs> {
+        fn flat(v: &Value) -> Option<BTreeMap<String, String>> {
+            v.as_object()?
+                .iter()
+                .map(|(k, v)| v.as_str().map(|s| (k.clone(), s.to_string())))
+                .collect()
+        }
+        Some(BuildInputs {
+            build_mode: v.get("build_mode")?.as_str()?.to_string(),
+            context: flat(v.get("context")?)?,
+            cmake_options: flat(v.get("cmake_options")?)?,
+            cmake_lists_sha256: v.get("cmake_lists_sha256")?.as_str()?.to_string(),
+            sources: v
+                .get("sources")?
+                .as_object()?
+                .iter()
+                .map(|(k, v)| flat(v).map(|m| (k.clone(), m)))
+                .collect::<Option<_>>()?,
+            toolchain: flat(v.get("toolchain")?)?,
+            env: flat(v.get("env")?)?,
+        })
+    }
+
+    /// The hex-encoded SHA-256 digest of the canonical serialization of these inputs
+    pub fn digest(&self) -> String {
+        sha256_hex(self.to_json().to_string().as_bytes())
+    }
+
+    /// The name of the build dir for these inputs, a prefix of the digest
+    pub fn dir_name(&self) -> String {
+        self.digest()[..DIR_NAME_LEN].to_string()
+    }
+
+    /// Write `build-info.json` into `build_dir`, marking it as used now
+    pub fn write_build_info(&self, build_dir: &Path) -> io::Result<()> {
+        let last_used = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut top = Map::new();
+        top.insert("digest".to_string(), Value::String(self.digest()));
+        top.insert("last_used".to_string(), json!(last_used));
+        top.insert("inputs".to_string(), self.to_json());
+        fs::write(
+            build_dir.join(BUILD_INFO_FILE),
+            serde_json::to_string_pretty(&Value::Object(top))?,
+        )
+    }
+}
+
+/// The content of a build dir's `build-info.json`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildInfo {
+    pub digest: String,
+    /// When `build_sel4` last used the build dir
+    pub last_used: SystemTime,
+    pub inputs: BuildInputs,
+}
+
+impl BuildInfo {
+    /// Read a build dir's `build-info.json`, if present and valid
+    pub fn read(build_dir: &Path) -> Option<BuildInfo> {
+        let content = fs::read_to_string(build_dir.join(BUILD_INFO_FILE)).ok()?;
+        let top: Value = serde_json::from_str(&content).ok()?;
+        Some(BuildInfo {
+            digest: top.get("digest")?.as_str()?.to_string(),
+            last_used: UNIX_EPOCH + Duration::from_secs(top.get("last_used")?.as_u64()?),
+            inputs: BuildInputs::from_json(top.get("inputs")?)?,
+        })
+    }
+}
+
+/// `path` as recorded in build info: relative to `base_dir`, the dir of
+/// sel4.toml, going up with `..` when it lies outside, so sources laid out
+/// the same way around sel4.toml are recorded the same wherever they are.
+/// Paths are kept as they are when either is relative or there is no `base_dir`.
+pub fn relative_location(path: &Path, base_dir: Option<&Path>) -> String {
+    let base_dir = match base_dir {
+        Some(base_dir) if base_dir.is_absolute() && path.is_absolute() => base_dir,
+        _ => return path.display().to_string(),
+    };
+    let path: Vec<Component> = path.components().collect();
+    let base: Vec<Component> = base_dir.components().collect();
+    let shared = path
+        .iter()
+        .zip(base.iter())
+        .take_while(|(p, b)| p == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in shared..base.len() {
+        relative.push("..");
+    }
+    for component in path[shared..].iter() {
+        relative.push(component.as_os_str());
+    }
+    if relative.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        relative.display().to_string()
+    }
+}
+
+/// Hex-encoded SHA-256 of the given bytes
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn example() -> BuildInputs {
+        let mut inputs = BuildInputs {
+            build_mode: "lib".to_string(),
+            cmake_lists_sha256: sha256_hex(b"cmake_minimum_required(VERSION 3.7.2)"),
+            ..Default::default()
+        };
+        inputs
+            .context
+            .insert("platform".to_string(), "sabre".to_string());
+        inputs
+            .cmake_options
+            .insert("KernelSel4Arch".to_string(), "aarch32".to_string());
+        inputs.cmake_options.insert(
+            "KERNEL_PATH".to_string(),
+            KERNEL_PATH_PLACEHOLDER.to_string(),
+        );
+        let mut kernel = BTreeMap::new();
+        kernel.insert("tree".to_string(), "abc123".to_string());
+        inputs.sources.insert("kernel".to_string(), kernel);
+        inputs
+    }
+
+    #[test]
+    fn sha256_known_value() {
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            sha256_hex(b"abc")
+        );
+    }
+
+    #[test]
+    fn digest_is_independent_of_insertion_order() {
+        let a = example();
+        let mut b = BuildInputs {
+            build_mode: a.build_mode.clone(),
+            cmake_lists_sha256: a.cmake_lists_sha256.clone(),
+            context: a.context.clone(),
+            sources: a.sources.clone(),
+            ..Default::default()
+        };
+        for (k, v) in a.cmake_options.iter().rev() {
+            b.cmake_options.insert(k.clone(), v.clone());
+        }
+        assert_eq!(a.digest(), b.digest());
+        assert_eq!(DIR_NAME_LEN, a.dir_name().len());
+    }
+
+    #[test]
+    fn digest_changes_with_env() {
+        let a = example();
+        let mut b = example();
+        b.env.insert("CFLAGS".to_string(), "-O3".to_string());
+        assert_ne!(a.digest(), b.digest());
+    }
+
+    #[test]
+    fn build_info_round_trip() {
+        let dir = tempdir().expect("Could not make a temp dir");
+        let inputs = example();
+        inputs.write_build_info(dir.path()).unwrap();
+        let info = BuildInfo::read(dir.path()).expect("Could not read build info");
+        assert_eq!(inputs, info.inputs);
+        assert_eq!(inputs.digest(), info.digest);
+    }
+}
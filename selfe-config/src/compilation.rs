@@ -1,23 +1,44 @@
-use crate::model::{self, Arch};
-use std::collections::hash_map::DefaultHasher;
+use crate::artifacts::{find_artifact, kernel_artifacts, Artifact, ArtifactKind};
+use crate::build_cache::{lock_path, CacheLock};
+use crate::build_info::{relative_location, sha256_hex, BuildInputs, KERNEL_PATH_PLACEHOLDER};
+use crate::model::{self, Arch, Compiler, Generator};
 use std::collections::BTreeMap;
-use std::fs;
-use std::hash::{Hash, Hasher};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::iter;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 const CMAKELISTS_KERNEL: &str = include_str!("CMakeLists_kernel.txt");
 const CMAKELISTS_LIB: &str = include_str!("CMakeLists_lib.txt");
 
+/// When set, the remote of each branch source is asked for the branch's head on
+/// every build, rather than at most once per `BRANCH_CHECK_INTERVAL`
+pub const REFRESH_SOURCES_ENV_VAR: &str = "SEL4_REFRESH_SOURCES";
+
+/// How long the remote head of a branch source is relied upon once seen
+const BRANCH_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// File within the .git dir of a branch checkout recording the branch and the
+/// remote head last seen for it
+const BRANCH_HEAD_FILE: &str = "selfe-branch-head";
+
 fn clone_at_rev(repo: &str, rev: &str, dir: &Path) -> Result<(), String> {
     let mut git_clone_command = Command::new("git");
     git_clone_command
         .arg("clone")
         .arg(repo)
         .arg(dir)
-        .stdout(Stdio::inherit())
+        .stdout(Stdio::null())
         .stderr(Stdio::inherit());
-    println!("Running git: {:?}", &git_clone_command);
+    eprintln!("Running git: {:?}", &git_clone_command);
     let clone_output = git_clone_command
         .output()
         .map_err(|e| format!("failed to run git: {}", e))?;
@@ -30,9 +51,9 @@ fn clone_at_rev(repo: &str, rev: &str, dir: &Path) -> Result<(), String> {
         .arg("--hard")
         .arg(rev)
         .current_dir(dir)
-        .stdout(Stdio::inherit())
+        .stdout(Stdio::null())
         .stderr(Stdio::inherit());
-    println!("Running git: {:?}", &git_reset_command);
+    eprintln!("Running git: {:?}", &git_reset_command);
     let reset_output = git_reset_command
         .output()
         .map_err(|e| format!("failed to run git: {}", e))?;
@@ -52,9 +73,9 @@ fn clone_at_branch_or_tag(repo: &str, branch_or_tag: &str, dir: &Path) -> Result
         .arg(branch_or_tag)
         .arg(repo)
         .arg(dir)
-        .stdout(Stdio::inherit())
+        .stdout(Stdio::null())
         .stderr(Stdio::inherit());
-    println!("Running git: {:?}", &git_clone_command);
+    eprintln!("Running git: {:?}", &git_clone_command);
     let output = git_clone_command
         .output()
         .map_err(|e| format!("failed to run git: {}", e))?;
@@ -65,31 +86,322 @@ fn clone_at_branch_or_tag(repo: &str, branch_or_tag: &str, dir: &Path) -> Result
     }
 }
 
-fn is_dir_absent_or_empty(dir_path: &Path) -> bool {
+fn is_dir_absent_or_empty(dir_path: &Path) -> Result<bool, String> {
     if dir_path.exists() {
         if !dir_path.is_dir() {
-            panic!(
-                "Found pre-existing file at {} where either nothing or an empty dir was expected",
+            return Err(format!(
+                "Found pre-existing file at {} where either nothing or a directory was expected",
                 dir_path.display()
-            );
+            ));
         }
-        std::fs::read_dir(dir_path)
-            .map_err(|e| format!("Could not read directory {} : {:?}", dir_path.display(), e))
-            .unwrap()
+        Ok(fs::read_dir(dir_path)
+            .map_err(|e| format!("Could not read directory {} : {:?}", dir_path.display(), e))?
             .count()
-            == 0
+            == 0)
+    } else {
+        Ok(true)
+    }
+}
+
+/// Run a git command in `dir` and hand back its trimmed stdout
+fn git_output(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| format!("failed to run git: {}", e))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
     } else {
-        true
+        Err(format!(
+            "git {} did not report success in {}",
+            args.join(" "),
+            dir.display()
+        ))
+    }
+}
+
+fn run_git(dir: &Path, args: &[&str]) -> Result<(), String> {
+    let mut git_command = Command::new("git");
+    git_command
+        .args(args)
+        .current_dir(dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit());
+    eprintln!("Running git: {:?}", &git_command);
+    let output = git_command
+        .output()
+        .map_err(|e| format!("failed to run git: {}", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("git {} did not report success", args.join(" ")))
+    }
+}
+
+/// The condition of a previously-populated git source directory
+#[derive(Debug, PartialEq)]
+enum CheckoutState {
+    /// At the expected target, with no local modifications
+    Fresh,
+    /// A usable repository that is at the wrong commit or has local modifications
+    Stale(String),
+    /// Not a usable clone of the expected repository, e.g. an interrupted clone
+    Corrupt(String),
+}
+
+fn check_checkout(
+    url: &str,
+    target: &model::GitTarget,
+    patches: &PatchSet,
+    dir: &Path,
+) -> CheckoutState {
+    use model::GitTarget;
+    if !dir.join(".git").exists() {
+        return CheckoutState::Corrupt("no .git directory present".to_string());
+    }
+    if git_output(dir, &["rev-parse", "--verify", "HEAD^{commit}"]).is_err() {
+        return CheckoutState::Corrupt("HEAD does not resolve to a commit".to_string());
+    }
+    match git_output(dir, &["config", "--get", "remote.origin.url"]) {
+        Ok(ref origin) if origin == url => (),
+        Ok(origin) => {
+            return CheckoutState::Corrupt(format!(
+                "origin is {} rather than the expected {}",
+                origin, url
+            ))
+        }
+        Err(_) => return CheckoutState::Corrupt("no origin remote configured".to_string()),
+    }
+    match git_output(dir, &["status", "--porcelain", "--untracked-files=no"]) {
+        Ok(ref status) if status.is_empty() => (),
+        Ok(_) => return CheckoutState::Stale("tracked files have been modified".to_string()),
+        Err(e) => return CheckoutState::Corrupt(e),
+    }
+    if patches.applied_digest(dir) != patches.digest {
+        return CheckoutState::Stale("the configured patches are not applied".to_string());
+    }
+    // Each applied patch is a commit on top of the target
+    let base = format!("HEAD~{}^{{commit}}", patches.paths.len());
+    let head = match git_output(dir, &["rev-parse", "--verify", &base]) {
+        Ok(head) => head,
+        Err(_) => return CheckoutState::Stale("patched history is incomplete".to_string()),
+    };
+
+    let expected = match target {
+        GitTarget::Rev(rev) => git_output(
+            dir,
+            &["rev-parse", "--verify", &format!("{}^{{commit}}", rev)],
+        ),
+        GitTarget::Tag(tag) => git_output(
+            dir,
+            &[
+                "rev-parse",
+                "--verify",
+                &format!("refs/tags/{}^{{commit}}", tag),
+            ],
+        ),
+        GitTarget::Branch(branch) => match remote_branch_head(url, branch, dir) {
+            Some(head) => head,
+            None => {
+                eprintln!(
+                    "Could not reach {} to check branch {}, using the existing checkout in {}",
+                    url,
+                    branch,
+                    dir.display()
+                );
+                return CheckoutState::Fresh;
+            }
+        },
+    };
+    match expected {
+        Ok(ref expected) if expected == &head => CheckoutState::Fresh,
+        Ok(expected) => CheckoutState::Stale(format!(
+            "HEAD is {} but {} {} is {}",
+            head,
+            target.kind(),
+            target.value(),
+            expected
+        )),
+        Err(_) => CheckoutState::Stale(format!(
+            "{} {} is not present locally",
+            target.kind(),
+            target.value()
+        )),
     }
 }
 
+/// Bring an existing clone to the expected target, discarding local modifications
+fn refresh_checkout(target: &model::GitTarget, dir: &Path) -> Result<(), String> {
+    use model::GitTarget;
+    match target {
+        GitTarget::Rev(rev) => {
+            if git_output(
+                dir,
+                &["rev-parse", "--verify", &format!("{}^{{commit}}", rev)],
+            )
+            .is_err()
+            {
+                run_git(dir, &["fetch", "origin"])?;
+            }
+            run_git(dir, &["reset", "--hard", rev])
+        }
+        GitTarget::Branch(branch) => {
+            run_git(dir, &["fetch", "--depth=1", "origin", branch])?;
+            run_git(dir, &["reset", "--hard", "FETCH_HEAD"])
+        }
+        GitTarget::Tag(tag) => {
+            let refspec = format!("+refs/tags/{0}:refs/tags/{0}", tag);
+            run_git(dir, &["fetch", "--depth=1", "origin", &refspec])?;
+            run_git(dir, &["reset", "--hard", &format!("refs/tags/{}", tag)])
+        }
+    }
+}
+
+/// Name of the file, within a checkout's .git dir, recording the digest of the applied patches
+const APPLIED_PATCHES_FILE: &str = "selfe-applied-patches";
+
+/// The ordered patch files configured for a git source, along with a digest of their content
+struct PatchSet {
+    paths: Vec<PathBuf>,
+    digest: Option<String>,
+}
+
+impl PatchSet {
+    fn load(paths: &[PathBuf]) -> Result<PatchSet, String> {
+        if paths.is_empty() {
+            return Ok(PatchSet {
+                paths: vec![],
+                digest: None,
+            });
+        }
+        let mut patch_digests = String::new();
+        let mut canonical_paths = Vec::with_capacity(paths.len());
+        for p in paths {
+            let content = fs::read(p)
+                .map_err(|e| format!("Could not read patch file {} : {}", p.display(), e))?;
+            patch_digests.push_str(&sha256_hex(&content));
+            patch_digests.push('\n');
+            canonical_paths.push(fs::canonicalize(p).map_err(|e| {
+                format!("Could not canonicalize patch file {} : {}", p.display(), e)
+            })?);
+        }
+        Ok(PatchSet {
+            paths: canonical_paths,
+            digest: Some(sha256_hex(patch_digests.as_bytes())[..16].to_string()),
+        })
+    }
+
+    fn applied_digest(&self, dir: &Path) -> Option<String> {
+        fs::read_to_string(dir.join(".git").join(APPLIED_PATCHES_FILE))
+            .ok()
+            .map(|s| s.trim().to_string())
+    }
+
+    /// Apply each patch as a commit on top of the current (pristine) checkout
+    fn apply(&self, dir: &Path) -> Result<(), String> {
+        let marker = dir.join(".git").join(APPLIED_PATCHES_FILE);
+        if marker.exists() {
+            fs::remove_file(&marker)
+                .map_err(|e| format!("Could not remove {} : {}", marker.display(), e))?;
+        }
+        let digest = match self.digest {
+            Some(ref digest) => digest,
+            None => return Ok(()),
+        };
+        for p in self.paths.iter() {
+            let patch_path = p
+                .to_str()
+                .ok_or_else(|| format!("Patch file path {} is not valid unicode", p.display()))?;
+            run_git(dir, &["apply", "--index", patch_path])
+                .map_err(|e| format!("Could not apply patch {} : {}", p.display(), e))?;
+            let message = format!("selfe patch: {}", p.display());
+            run_git(
+                dir,
+                &[
+                    "-c",
+                    "user.name=selfe",
+                    "-c",
+                    "user.email=selfe@localhost",
+                    "commit",
+                    "--quiet",
+                    "--no-verify",
+                    "--no-gpg-sign",
+                    "-m",
+                    &message,
+                ],
+            )?;
+        }
+        fs::write(&marker, digest)
+            .map_err(|e| format!("Could not write {} : {}", marker.display(), e))
+    }
+}
+
+/// The commit `branch` is at on the remote `url`: as recorded in the checkout
+/// `dir` when seen within `BRANCH_CHECK_INTERVAL` and `REFRESH_SOURCES_ENV_VAR`
+/// is unset, or else as listed by the remote. `None` when the remote can't be reached.
+fn remote_branch_head(url: &str, branch: &str, dir: &Path) -> Option<Result<String, String>> {
+    let record = dir.join(".git").join(BRANCH_HEAD_FILE);
+    let age = fs::metadata(&record)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok());
+    if env::var_os(REFRESH_SOURCES_ENV_VAR).is_none()
+        && matches!(age, Some(age) if age < BRANCH_CHECK_INTERVAL)
+    {
+        let recorded = fs::read_to_string(&record).unwrap_or_default();
+        let mut fields = recorded.split_whitespace();
+        if let (Some(recorded_branch), Some(head)) = (fields.next(), fields.next()) {
+            if recorded_branch == branch {
+                return Some(Ok(head.to_string()));
+            }
+        }
+    }
+
+    let listing = git_output(dir, &["ls-remote", url, &format!("refs/heads/{}", branch)]).ok()?;
+    let head = listing
+        .split_whitespace()
+        .next()
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| format!("branch {} not found at {}", branch, url));
+    if let Ok(ref head) = head {
+        // Only an optimization, so failing to record it is harmless
+        let _ = fs::write(&record, format!("{} {}\n", branch, head));
+    }
+    Some(head)
+}
+
+fn clone_target(url: &str, target: &model::GitTarget, dir: &Path) -> Result<(), String> {
+    use model::GitTarget;
+    match target {
+        GitTarget::Branch(v) | GitTarget::Tag(v) => clone_at_branch_or_tag(url, v, dir),
+        GitTarget::Rev(rev) => clone_at_rev(url, rev, dir),
+    }
+}
+
+fn reset_dir(dir: &Path) -> Result<(), String> {
+    fs::remove_dir_all(dir)
+        .map_err(|e| format!("Could not remove directory {} : {}", dir.display(), e))?;
+    fs::create_dir_all(dir)
+        .map_err(|e| format!("Could not create directory {} : {}", dir.display(), e))
+}
+
 pub struct ResolvedSeL4Source {
     pub kernel_dir: PathBuf,
     pub tools_dir: PathBuf,
     pub util_libs_dir: PathBuf,
+    /// Present when a `sel4_libs` source is configured
+    pub sel4_libs_dir: Option<PathBuf>,
 }
 
 /// dest_dir: Where downloaded source will be placed, if necessary
+///
+/// Previously downloaded git sources are reused when they are a clean checkout
+/// of the expected rev, branch or tag. Stale checkouts are fetched and reset,
+/// and checkouts that are not usable git clones are removed and cloned afresh.
+/// Any patches configured for a git source are applied, in order, on top of
+/// a pristine checkout of its target.
 pub fn resolve_sel4_sources(
     source: &model::SeL4Sources,
     dest_dir: &Path,
@@ -101,42 +413,112 @@ pub fn resolve_sel4_sources(
         dest_dir: &Path,
         is_verbose: bool,
     ) -> Result<PathBuf, String> {
-        use model::{GitTarget, RepoSource};
+        use model::RepoSource;
         match source {
             RepoSource::LocalPath(p) => Ok(p.clone()),
-            RepoSource::RemoteGit { url, target } => {
+            RepoSource::RemoteGit {
+                url,
+                target,
+                patches,
+            } => {
+                let patches = PatchSet::load(patches)?;
                 let target_kind = target.kind();
-                let name_suffix = format!("{}-{}-{}", name_hint, target_kind, target.value());
+                let name_suffix = match patches.digest {
+                    Some(ref digest) => format!(
+                        "{}-{}-{}-patched-{}",
+                        name_hint,
+                        target_kind,
+                        target.value(),
+                        digest
+                    ),
+                    None => format!("{}-{}-{}", name_hint, target_kind, target.value()),
+                };
                 let dir = dest_dir.join(name_suffix);
-                let dir_needs_content = is_dir_absent_or_empty(&dir);
+                let lock = lock_path(&dir);
+                let _lock = CacheLock::acquire(&lock).map_err(|e| {
+                    format!(
+                        "Failed to lock {} dir {} : {}",
+                        name_hint,
+                        lock.display(),
+                        e
+                    )
+                })?;
+                let dir_needs_content = is_dir_absent_or_empty(&dir)?;
                 if is_verbose {
-                    println!(
+                    eprintln!(
                         "Git based source directory {:?} {} need fresh content",
                         dir,
                         if dir_needs_content { "DID" } else { " did not" }
                     );
                 }
-                fs::create_dir_all(&dir).expect("Failed to create dir");
-                let dir = fs::canonicalize(&dir).unwrap_or_else(|_| {
-                    panic!(
-                        "Failed to canonicalize {} dir: {}",
+                fs::create_dir_all(&dir).map_err(|e| {
+                    format!(
+                        "Failed to create {} dir {} : {}",
                         name_hint,
-                        &dir.display()
+                        dir.display(),
+                        e
                     )
-                });
+                })?;
+                let dir = fs::canonicalize(&dir).map_err(|e| {
+                    format!(
+                        "Failed to canonicalize {} dir {} : {}",
+                        name_hint,
+                        dir.display(),
+                        e
+                    )
+                })?;
 
                 if dir_needs_content {
-                    match target {
-                        GitTarget::Branch(v) | GitTarget::Tag(v) => {
-                            //"git://github.com/seL4/seL4_tools.git",
-                            clone_at_branch_or_tag(url, v, &dir)?;
-                        }
-                        GitTarget::Rev(rev) => {
-                            clone_at_rev(url, rev, &dir)?;
+                    clone_target(url, target, &dir)?;
+                    patches.apply(&dir)?;
+                    return Ok(dir);
+                }
+
+                match check_checkout(url, target, &patches, &dir) {
+                    CheckoutState::Fresh => return Ok(dir),
+                    CheckoutState::Stale(reason) => {
+                        eprintln!(
+                            "Existing {} source in {} is stale ({}), refreshing it",
+                            name_hint,
+                            dir.display(),
+                            reason
+                        );
+                        if let Err(e) =
+                            refresh_checkout(target, &dir).and_then(|_| patches.apply(&dir))
+                        {
+                            eprintln!(
+                                "Could not refresh {} source ({}), cloning it again",
+                                name_hint, e
+                            );
+                            reset_dir(&dir)?;
+                            clone_target(url, target, &dir)?;
+                            patches.apply(&dir)?;
                         }
-                    };
+                    }
+                    CheckoutState::Corrupt(reason) => {
+                        eprintln!(
+                            "Existing {} source in {} is unusable ({}), cloning it again",
+                            name_hint,
+                            dir.display(),
+                            reason
+                        );
+                        reset_dir(&dir)?;
+                        clone_target(url, target, &dir)?;
+                        patches.apply(&dir)?;
+                    }
+                }
+
+                match check_checkout(url, target, &patches, &dir) {
+                    CheckoutState::Fresh => Ok(dir),
+                    CheckoutState::Stale(reason) | CheckoutState::Corrupt(reason) => Err(format!(
+                        "{} source in {} could not be brought to {} {}: {}",
+                        name_hint,
+                        dir.display(),
+                        target_kind,
+                        target.value(),
+                        reason
+                    )),
                 }
-                Ok(dir)
             }
         }
     }
@@ -144,6 +526,15 @@ pub fn resolve_sel4_sources(
         kernel_dir: resolve_repo_source(&source.kernel, "kernel", dest_dir, is_verbose)?,
         tools_dir: resolve_repo_source(&source.tools, "seL4_tools", dest_dir, is_verbose)?,
         util_libs_dir: resolve_repo_source(&source.util_libs, "util_libs", dest_dir, is_verbose)?,
+        sel4_libs_dir: match source.sel4_libs {
+            Some(ref sel4_libs) => Some(resolve_repo_source(
+                sel4_libs,
+                "seL4_libs",
+                dest_dir,
+                is_verbose,
+            )?),
+            None => None,
+        },
     })
 }
 
@@ -153,52 +544,360 @@ pub enum SeL4BuildMode {
     Lib,
 }
 
+impl Display for SeL4BuildMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            SeL4BuildMode::Kernel => "kernel",
+            SeL4BuildMode::Lib => "lib",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for SeL4BuildMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kernel" => Ok(SeL4BuildMode::Kernel),
+            "lib" => Ok(SeL4BuildMode::Lib),
+            _ => Err("Unrecognized build mode".to_string()),
+        }
+    }
+}
+
 pub enum SeL4BuildOutcome {
     StaticLib {
         build_dir: PathBuf,
     },
     Kernel {
         build_dir: PathBuf,
+        /// The image to boot: the kernel image if there is a separate one,
+        /// otherwise the image bundling the kernel and root task
         kernel_path: PathBuf,
+        /// The root task image, when loaded separately from the kernel
         root_image_path: Option<PathBuf>,
+        /// Everything the build produced, as found in the build dir
+        artifacts: Vec<Artifact>,
     },
 }
 
-/// Return the cmake build dir
-pub fn build_sel4(
-    out_dir: &Path,
-    kernel_dir: &Path,
-    tools_dir: &Path,
-    util_libs_dir: &Path,
+/// The things that can go wrong when attempting to build seL4
+#[derive(Debug)]
+pub enum BuildError {
+    /// A required external tool could not be run at all
+    ToolMissing { tool: &'static str, error: String },
+    /// CMake ran but did not successfully configure the build
+    ConfigureFailed {
+        build_dir: PathBuf,
+        log_path: PathBuf,
+        exit_code: Option<i32>,
+        /// The first lines of output that look like errors
+        error_lines: Vec<String>,
+    },
+    /// The configured build ran but did not successfully compile
+    CompileFailed {
+        build_dir: PathBuf,
+        log_path: PathBuf,
+        exit_code: Option<i32>,
+        /// The first lines of output that look like errors
+        error_lines: Vec<String>,
+    },
+    /// The configuration can't be used to drive a build
+    InvalidConfig(String),
+    /// The configured target is not one this crate knows how to build
+    UnsupportedTarget(String),
+    /// Problems preparing the build directory and its contents
+    Io { path: PathBuf, error: String },
+    /// The build succeeded, but an expected output could not be found
+    MissingArtifact {
+        build_dir: PathBuf,
+        artifact: &'static str,
+    },
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        fn write_error_lines(
+            f: &mut std::fmt::Formatter,
+            error_lines: &[String],
+        ) -> Result<(), std::fmt::Error> {
+            for line in error_lines {
+                f.write_fmt(format_args!("\n    {}", line))?;
+            }
+            Ok(())
+        }
+        fn exit_status(exit_code: &Option<i32>) -> String {
+            match exit_code {
+                Some(c) => format!("exit code {}", c),
+                None => "termination by signal".to_string(),
+            }
+        }
+        match self {
+            BuildError::ToolMissing { tool, error } => f.write_fmt(format_args!(
+                "Could not run {}, is it installed and on the PATH? {}",
+                tool, error
+            )),
+            BuildError::ConfigureFailed {
+                build_dir,
+                log_path,
+                exit_code,
+                error_lines,
+            } => {
+                f.write_fmt(format_args!(
+                    "CMake configuration of {} failed with {}. See {} for details.",
+                    build_dir.display(),
+                    exit_status(exit_code),
+                    log_path.display()
+                ))?;
+                write_error_lines(f, error_lines)
+            }
+            BuildError::CompileFailed {
+                build_dir,
+                log_path,
+                exit_code,
+                error_lines,
+            } => {
+                f.write_fmt(format_args!(
+                    "Compilation in {} failed with {}. See {} for details.",
+                    build_dir.display(),
+                    exit_status(exit_code),
+                    log_path.display()
+                ))?;
+                write_error_lines(f, error_lines)
+            }
+            BuildError::InvalidConfig(s) => f.write_fmt(format_args!(
+                "Invalid configuration for an seL4 build: {}",
+                s
+            )),
+            BuildError::UnsupportedTarget(s) => {
+                f.write_fmt(format_args!("Unsupported target: {}", s))
+            }
+            BuildError::Io { path, error } => {
+                f.write_fmt(format_args!("I/O error for {}: {}", path.display(), error))
+            }
+            BuildError::MissingArtifact {
+                build_dir,
+                artifact,
+            } => f.write_fmt(format_args!(
+                "The build in {} did not produce {}",
+                build_dir.display(),
+                artifact
+            )),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Where the lib CMakeLists.txt records the libraries it built, within the build dir
+const BUILT_LIBRARIES_DIR: &str = "selfe-libraries";
+
+/// An additional seL4 library built alongside libsel4
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuiltLibrary {
+    /// The CMake target name, as configured in `libraries`
+    pub name: String,
+    /// The static library archive
+    pub archive: PathBuf,
+    /// The library's own public include dirs, not including those of its dependencies
+    pub include_dirs: Vec<PathBuf>,
+}
+
+impl BuiltLibrary {
+    /// Tell cargo to link this library statically. Should be run from a build.rs
+    pub fn print_cargo_link_directives(&self) {
+        if let Some(dir) = self.archive.parent() {
+            println!("cargo:rustc-link-search=native={}", dir.display());
+        }
+        let link_name = self
+            .archive
+            .file_stem()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default();
+        println!(
+            "cargo:rustc-link-lib=static={}",
+            link_name.strip_prefix("lib").unwrap_or(&link_name)
+        );
+    }
+}
+
+/// The additional libraries built by a lib build in `build_dir`, in the
+/// order they were configured
+pub fn built_libraries(
+    build_dir: &Path,
     config: &model::contextualized::Contextualized,
-    build_mode: SeL4BuildMode,
-) -> SeL4BuildOutcome {
-    if let Some(ref build_dir) = config.build_dir {
-	match build_mode {
-	    SeL4BuildMode::Lib => {
-		return SeL4BuildOutcome::StaticLib {
-		    build_dir: build_dir.to_path_buf()
-		}
-	    },
-	    SeL4BuildMode::Kernel => {
-		panic!("Kernel build not supported when build_dir is provided");
-	    }
-	}
-    }
-
-    let cmake_lists_content = match build_mode {
+) -> Result<Vec<BuiltLibrary>, BuildError> {
+    config
+        .libraries
+        .iter()
+        .map(|name| {
+            let record = build_dir
+                .join(BUILT_LIBRARIES_DIR)
+                .join(format!("{}.txt", name));
+            let content = fs::read_to_string(&record).map_err(io_error(&record))?;
+            let mut lines = content.lines().filter(|l| !l.trim().is_empty());
+            let archive = lines
+                .next()
+                .map(PathBuf::from)
+                .ok_or_else(|| BuildError::Io {
+                    path: record.clone(),
+                    error: "no library archive recorded".to_string(),
+                })?;
+            Ok(BuiltLibrary {
+                name: name.clone(),
+                archive,
+                include_dirs: lines.map(PathBuf::from).collect(),
+            })
+        })
+        .collect()
+}
+
+/// A step of `build_sel4` that runs an external tool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStep {
+    /// Running cmake to configure the build dir
+    Configure,
+    /// Running the build tool via `cmake --build`
+    Build,
+}
+
+impl BuildStep {
+    /// The file, within the build dir, that the output of this step is logged to
+    pub fn log_file_name(self) -> &'static str {
+        match self {
+            BuildStep::Configure => "configure.log",
+            BuildStep::Build => "build.log",
+        }
+    }
+}
+
+/// The most error lines kept for a `BuildError`
+const MAX_ERROR_LINES: usize = 10;
+
+/// Whether a line of cmake, compiler or build tool output reports an error
+fn is_error_line(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("CMake Error")
+        || line.starts_with("FAILED:")
+        || line.starts_with("ninja: error")
+        || line.starts_with("make: ***")
+        || (line.starts_with("make[") && line.contains("***"))
+        || line.contains(": error:")
+        || line.contains(": fatal error:")
+        || line.contains("undefined reference to")
+}
+
+/// Run `cmd` to completion, logging its stdout and stderr, line by line as they
+/// arrive, to `log_path` and to `on_output`. Returns the exit status and the first
+/// lines that look like errors.
+fn run_logged(
+    cmd: &mut Command,
+    tool: &'static str,
+    step: BuildStep,
+    log_path: &Path,
+    on_output: &mut dyn FnMut(BuildStep, &str),
+) -> Result<(ExitStatus, Vec<String>), BuildError> {
+    let mut log = File::create(log_path).map_err(io_error(log_path))?;
+    writeln!(log, "Running: {:?}", cmd).map_err(io_error(log_path))?;
+
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| BuildError::ToolMissing {
+            tool,
+            error: e.to_string(),
+        })?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut streams: Vec<Box<dyn Read + Send>> = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        streams.push(Box::new(stdout));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        streams.push(Box::new(stderr));
+    }
+    let readers: Vec<_> = streams
+        .into_iter()
+        .map(|stream| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream);
+                let mut buf = Vec::new();
+                while let Ok(n) = reader.read_until(b'\n', &mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                    let line = String::from_utf8_lossy(&buf).trim_end().to_string();
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                    buf.clear();
+                }
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let mut error_lines = Vec::new();
+    for line in rx {
+        writeln!(log, "{}", line).map_err(io_error(log_path))?;
+        if error_lines.len() < MAX_ERROR_LINES && is_error_line(&line) {
+            error_lines.push(line.clone());
+        }
+        on_output(step, &line);
+    }
+    for reader in readers {
+        let _ = reader.join();
+    }
+    let status = child.wait().map_err(|e| BuildError::ToolMissing {
+        tool,
+        error: e.to_string(),
+    })?;
+    Ok((status, error_lines))
+}
+
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> BuildError + '_ {
+    move |e| BuildError::Io {
+        path: path.to_path_buf(),
+        error: e.to_string(),
+    }
+}
+
+/// The CMakeLists.txt content used for the given kind of build
+pub fn cmake_lists_template(build_mode: SeL4BuildMode) -> &'static str {
+    match build_mode {
         SeL4BuildMode::Kernel => CMAKELISTS_KERNEL,
         SeL4BuildMode::Lib => CMAKELISTS_LIB,
-    };
+    }
+}
 
+/// The options `build_sel4` passes to cmake for the given configuration
+pub fn cmake_options(
+    config: &model::contextualized::Contextualized,
+    kernel_dir: &Path,
+    build_mode: SeL4BuildMode,
+) -> BTreeMap<String, String> {
     let mut cmake_opts = BTreeMap::new();
-    if let Some(prefix) = &config.build.cross_compiler_prefix {
-        cmake_opts.insert("CROSS_COMPILER_PREFIX".to_string(), prefix.to_owned());
-    }
+    let toolchain_file = match config.build.compiler {
+        Compiler::Gcc => {
+            if let Some(prefix) = &config.build.cross_compiler_prefix {
+                cmake_opts.insert("CROSS_COMPILER_PREFIX".to_string(), prefix.to_owned());
+            }
+            "gcc.cmake"
+        }
+        Compiler::Clang => {
+            if let Some(triple) = &config.build.triple {
+                cmake_opts.insert("TRIPLE".to_string(), triple.to_owned());
+            }
+            "llvm.cmake"
+        }
+    };
 
     cmake_opts.insert(
         "CMAKE_TOOLCHAIN_FILE".to_string(),
-        kernel_dir.join("gcc.cmake").display().to_string(),
+        kernel_dir.join(toolchain_file).display().to_string(),
     );
     cmake_opts.insert("KERNEL_PATH".to_string(), kernel_dir.display().to_string());
 
@@ -207,6 +906,9 @@ pub fn build_sel4(
             "LibSel4FunctionAttributes".to_string(),
             "public".to_string(),
         );
+        if !config.libraries.is_empty() {
+            cmake_opts.insert("SELFE_LIBRARIES".to_string(), config.libraries.join(";"));
+        }
     }
 
     for (k, v) in config.sel4_config.iter() {
@@ -218,116 +920,492 @@ pub fn build_sel4(
 
         cmake_opts.insert(k.to_owned(), v_str);
     }
+    cmake_opts
+}
 
-    // create the build directory by hashing both input config and the actual
-    // cmake options
-    let mut hash_state = DefaultHasher::new();
-    config.hash(&mut hash_state);
-    cmake_opts.hash(&mut hash_state);
-    cmake_lists_content.hash(&mut hash_state);
-    // TODO hash relevant environment variables as well. Or tightly manage the target env.
-    let config_hash = hash_state.finish();
+/// The PATH for build tools: that of this process, preceded by the configured
+/// `toolchain_dir`, or its `bin` subdir when it has one
+pub fn toolchain_path(config: &model::contextualized::Contextualized) -> Option<OsString> {
+    let toolchain_dir = config.build.toolchain_dir.as_ref()?;
+    let bin_dir = toolchain_dir.join("bin");
+    let dir = if bin_dir.is_dir() {
+        bin_dir
+    } else {
+        toolchain_dir.clone()
+    };
+    let existing = env::var_os("PATH").unwrap_or_default();
+    env::join_paths(iter::once(dir).chain(env::split_paths(&existing))).ok()
+}
 
-    let build_dir = out_dir
-        .join("sel4-build")
-        .join(format!("{:x}", config_hash));
-    if build_dir.exists() && !build_dir.is_dir() {
-        panic!(
-            "{} already exists, and is not a directory",
-            build_dir.to_str().unwrap()
+/// First line of the output of `program --version`, if it can be run
+/// with the given PATH, or this process's PATH when that is `None`
+pub(crate) fn tool_version(program: &str, path: Option<&OsStr>) -> Option<String> {
+    let mut cmd = Command::new(program);
+    if let Some(path) = path {
+        cmd.env("PATH", path);
+    }
+    let output = cmd.arg("--version").stderr(Stdio::null()).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .map(|l| l.trim().to_string())
+}
+
+/// What the configuration says about a source repository, as recorded in build info,
+/// with any local path relative to `base_dir`, see `build_info::relative_location`
+pub(crate) fn configured_source_identity(
+    source: &model::RepoSource,
+    base_dir: Option<&Path>,
+) -> Result<BTreeMap<String, String>, String> {
+    let mut identity = BTreeMap::new();
+    match source {
+        model::RepoSource::LocalPath(p) => {
+            identity.insert("path".to_string(), relative_location(p, base_dir));
+        }
+        model::RepoSource::RemoteGit {
+            url,
+            target,
+            patches,
+        } => {
+            identity.insert("git".to_string(), url.clone());
+            identity.insert(target.kind().to_string(), target.value().to_string());
+            if let Some(digest) = PatchSet::load(patches)?.digest {
+                identity.insert("patches".to_string(), digest);
+            }
+        }
+    }
+    Ok(identity)
+}
+
+/// What distinguishes the content of a source repository, without reference to
+/// where it happens to be checked out
+fn source_identity(
+    source: &model::RepoSource,
+    dir: &Path,
+    base_dir: Option<&Path>,
+) -> Result<BTreeMap<String, String>, String> {
+    let mut identity = configured_source_identity(source, base_dir)?;
+    // The tree hash reflects the checked-out content, including any applied patches,
+    // but not commit metadata such as when those patches were committed
+    if let Ok(tree) = git_output(dir, &["rev-parse", "HEAD^{tree}"]) {
+        identity.insert("tree".to_string(), tree);
+        if let Ok(status) = git_output(dir, &["status", "--porcelain", "--untracked-files=no"]) {
+            identity.insert("dirty".to_string(), (!status.is_empty()).to_string());
+        }
+    }
+    Ok(identity)
+}
+
+/// The context of a build, as recorded in build info
+pub(crate) fn input_context(
+    config: &model::contextualized::Contextualized,
+    build_mode: SeL4BuildMode,
+) -> BTreeMap<String, String> {
+    let mut context = BTreeMap::new();
+    context.insert("platform".to_string(), config.context.platform.to_string());
+    context.insert("arch".to_string(), config.context.arch.to_string());
+    context.insert(
+        "sel4_arch".to_string(),
+        config.context.sel4_arch.to_string(),
+    );
+    context.insert(
+        "profile".to_string(),
+        if config.context.is_debug {
+            "debug"
+        } else {
+            "release"
+        }
+        .to_string(),
+    );
+    if build_mode == SeL4BuildMode::Kernel {
+        if let Some(ref root_task) = config.build.root_task {
+            context.insert(
+                "root_task_image".to_string(),
+                relative_location(&root_task.image_path, config.context.base_dir.as_deref()),
+            );
+        }
+    }
+    context
+}
+
+/// The configured generator, or else Ninja when it is installed and Unix Makefiles
+/// otherwise. Fails when the build tool for the generator is not installed.
+///
+/// `path` is the PATH to search, if not this process's PATH.
+pub fn select_generator(
+    configured: Option<Generator>,
+    path: Option<&OsStr>,
+) -> Result<Generator, BuildError> {
+    let generator = match configured {
+        Some(g) => g,
+        None => [Generator::Ninja, Generator::UnixMakefiles]
+            .iter()
+            .cloned()
+            .find(|g| tool_version(g.build_tool(), path).is_some())
+            .ok_or_else(|| BuildError::ToolMissing {
+                tool: "ninja or make",
+                error: "neither could be run".to_string(),
+            })?,
+    };
+    if tool_version(generator.build_tool(), path).is_none() {
+        return Err(BuildError::ToolMissing {
+            tool: generator.build_tool(),
+            error: format!(
+                "required by the {} generator, but could not be run",
+                generator
+            ),
+        });
+    }
+    Ok(generator)
+}
+
+/// The number of parallel build jobs requested of cargo: `NUM_JOBS` within
+/// build scripts, or else `CARGO_BUILD_JOBS`
+fn build_jobs<F: Fn(&str) -> Option<String>>(var: F) -> Option<usize> {
+    ["NUM_JOBS", "CARGO_BUILD_JOBS"]
+        .iter()
+        .filter_map(|k| var(k))
+        .find_map(|v| v.trim().parse().ok())
+        .filter(|jobs| *jobs > 0)
+}
+
+/// Identity of the compiler and build tools, as recorded in build info: their
+/// versions, as found on any `toolchain_dir`, rather than where they are installed
+fn toolchain_identity(
+    config: &model::contextualized::Contextualized,
+    generator: Generator,
+    path: Option<&OsStr>,
+) -> BTreeMap<String, String> {
+    let mut toolchain = BTreeMap::new();
+    toolchain.insert("generator".to_string(), generator.to_string());
+    let compiler = match config.build.compiler {
+        Compiler::Gcc => format!(
+            "{}gcc",
+            config
+                .build
+                .cross_compiler_prefix
+                .clone()
+                .unwrap_or_default()
+        ),
+        Compiler::Clang => "clang".to_string(),
+    };
+    for (key, program) in [
+        ("compiler", compiler.as_str()),
+        ("cmake", "cmake"),
+        (generator.build_tool(), generator.build_tool()),
+    ]
+    .iter()
+    {
+        toolchain.insert(
+            key.to_string(),
+            tool_version(program, path).unwrap_or_else(|| format!("{} unavailable", program)),
+        );
+    }
+    toolchain
+}
+
+fn build_inputs(
+    config: &model::contextualized::Contextualized,
+    cmake_opts: &BTreeMap<String, String>,
+    cmake_lists_content: &str,
+    build_mode: SeL4BuildMode,
+    toolchain: BTreeMap<String, String>,
+    kernel_dir: &Path,
+    sources: &[(&str, &model::RepoSource, &Path)],
+) -> Result<BuildInputs, String> {
+    let kernel_dir = kernel_dir.display().to_string();
+    let cmake_options = cmake_opts
+        .iter()
+        .map(|(k, v)| (k.clone(), v.replace(&kernel_dir, KERNEL_PATH_PLACEHOLDER)))
+        .collect();
+
+    let mut source_identities = BTreeMap::new();
+    for (name, source, dir) in sources.iter() {
+        source_identities.insert(
+            name.to_string(),
+            source_identity(source, dir, config.context.base_dir.as_deref())?,
         );
     }
 
-    println!("Using build_dir={}", build_dir.display());
-    fs::create_dir_all(&build_dir).expect("Failed to create build dir");
-    fs::write(build_dir.join("CMakeLists.txt"), cmake_lists_content).unwrap();
+    Ok(BuildInputs {
+        build_mode: build_mode.to_string(),
+        context: input_context(config, build_mode),
+        cmake_options,
+        cmake_lists_sha256: sha256_hex(cmake_lists_content.as_bytes()),
+        sources: source_identities,
+        toolchain,
+        env: BuildInputs::env_from_process(),
+    })
+}
+
+/// Return the cmake build dir
+///
+/// The build dir is `out_dir/sel4-build/<digest>`, where the digest covers all
+/// of the inputs to the build, as described in the `build_info` module.
+/// The build dir is locked for the duration of the build, so `out_dir` may be
+/// shared between concurrent callers, as with the `build_cache` module's shared cache.
+///
+/// The output of cmake and the build tool is logged to files in the build dir,
+/// as named by `BuildStep::log_file_name`, and echoed to stdout.
+///
+/// Lib builds also build the configured `libraries`, see `built_libraries`,
+/// though only those from util_libs, since no seL4_libs dir is supplied here.
+pub fn build_sel4(
+    out_dir: &Path,
+    kernel_dir: &Path,
+    tools_dir: &Path,
+    util_libs_dir: &Path,
+    config: &model::contextualized::Contextualized,
+    build_mode: SeL4BuildMode,
+) -> Result<SeL4BuildOutcome, BuildError> {
+    let sources = ResolvedSeL4Source {
+        kernel_dir: kernel_dir.to_path_buf(),
+        tools_dir: tools_dir.to_path_buf(),
+        util_libs_dir: util_libs_dir.to_path_buf(),
+        sel4_libs_dir: None,
+    };
+    build_sel4_with_output(out_dir, &sources, config, build_mode, &mut |_, line| {
+        println!("{}", line)
+    })
+}
+
+/// As `build_sel4`, but taking all of the resolved sources, and with each line
+/// of cmake and build tool output passed to `on_output` rather than echoed
+pub fn build_sel4_with_output(
+    out_dir: &Path,
+    sources: &ResolvedSeL4Source,
+    config: &model::contextualized::Contextualized,
+    build_mode: SeL4BuildMode,
+    on_output: &mut dyn FnMut(BuildStep, &str),
+) -> Result<SeL4BuildOutcome, BuildError> {
+    let kernel_dir = sources.kernel_dir.as_path();
+    let tools_dir = sources.tools_dir.as_path();
+    let util_libs_dir = sources.util_libs_dir.as_path();
+    if let Some(ref build_dir) = config.build_dir {
+        match build_mode {
+            SeL4BuildMode::Lib => {
+                return Ok(SeL4BuildOutcome::StaticLib {
+                    build_dir: build_dir.to_path_buf(),
+                })
+            }
+            SeL4BuildMode::Kernel => {
+                return Err(BuildError::InvalidConfig(
+                    "Kernel build not supported when build_dir is provided".to_string(),
+                ));
+            }
+        }
+    }
+
+    let cmake_lists_content = cmake_lists_template(build_mode);
+    let cmake_opts = cmake_options(config, kernel_dir, build_mode);
+
+    if !cmake_opts.contains_key("KernelSel4Arch") {
+        return Err(BuildError::InvalidConfig(
+            "KernelSel4Arch missing but required as a sel4 config option".to_string(),
+        ));
+    }
+    // TODO - should we enforce that this value matches the resolved config platform name?
+    if cmake_opts.contains_key("KernelPlatform") {
+        return Err(BuildError::InvalidConfig("Explicitly supplying a KernelPlatform property interferes with the inner workings of the seL4 cmake build".to_string()));
+    }
+    if !cmake_opts.contains_key("KernelX86Platform")
+        && !cmake_opts.contains_key("KernelARMPlatform")
+    {
+        return Err(BuildError::InvalidConfig(
+            "KernelARMPlatform or KernelX86Platform missing but required as a sel4 config option"
+                .to_string(),
+        ));
+    }
+    let root_task = match build_mode {
+        SeL4BuildMode::Kernel => {
+            match config.context.arch {
+                Arch::X86 | Arch::Arm => (),
+                arch => {
+                    return Err(BuildError::UnsupportedTarget(format!(
+                        "kernel builds are not supported for the {} arch",
+                        arch
+                    )))
+                }
+            }
+            Some(config.build.root_task.as_ref().ok_or_else(|| {
+                BuildError::InvalidConfig(
+                    "A build profile's `root_task_image` is required for a kernel build"
+                        .to_string(),
+                )
+            })?)
+        }
+        SeL4BuildMode::Lib => None,
+    };
+
+    if config.build.compiler == Compiler::Clang && !cmake_opts.contains_key("TRIPLE") {
+        return Err(BuildError::InvalidConfig(format!(
+            "clang builds need a `triple` or `cross_compiler_prefix` in [build.{}]",
+            config.context.platform
+        )));
+    }
+
+    let path = toolchain_path(config);
+    let path = path.as_deref();
+    let generator = select_generator(config.build.generator, path)?;
+
+    // name the build directory after a digest of everything that goes into it
+    let inputs = build_inputs(
+        config,
+        &cmake_opts,
+        cmake_lists_content,
+        build_mode,
+        toolchain_identity(config, generator, path),
+        kernel_dir,
+        &[
+            ("kernel", &config.sel4_sources.kernel, kernel_dir),
+            ("tools", &config.sel4_sources.tools, tools_dir),
+            ("util_libs", &config.sel4_sources.util_libs, util_libs_dir),
+        ]
+        .iter()
+        .cloned()
+        .chain(
+            config
+                .sel4_sources
+                .sel4_libs
+                .as_ref()
+                .zip(sources.sel4_libs_dir.as_deref())
+                .map(|(source, dir)| ("sel4_libs", source, dir)),
+        )
+        .collect::<Vec<_>>(),
+    )
+    .map_err(BuildError::InvalidConfig)?;
+
+    let build_dir = out_dir.join("sel4-build").join(inputs.dir_name());
+    if build_dir.exists() && !build_dir.is_dir() {
+        return Err(BuildError::Io {
+            path: build_dir,
+            error: "already exists, and is not a directory".to_string(),
+        });
+    }
+
+    // Held until the build finishes, so that concurrent builds of the same
+    // inputs run one after another and all but the first find nothing to do
+    let lock = lock_path(&build_dir);
+    let _lock = CacheLock::acquire(&lock).map_err(io_error(&lock))?;
+
+    eprintln!("Using build_dir={}", build_dir.display());
+    fs::create_dir_all(&build_dir).map_err(io_error(&build_dir))?;
+    let cmake_lists_path = build_dir.join("CMakeLists.txt");
+    fs::write(&cmake_lists_path, cmake_lists_content).map_err(io_error(&cmake_lists_path))?;
+    inputs
+        .write_build_info(&build_dir)
+        .map_err(io_error(&build_dir))?;
+
+    // Environment for both the configure and build steps, as the
+    // build step re-runs cmake when its inputs change
+    let mut tool_env: Vec<(&str, OsString)> = vec![("SEL4_TOOLS_DIR", tools_dir.into())];
+    if let Some(path) = path {
+        tool_env.push(("PATH", path.to_os_string()));
+    }
+    if let Some(root_task) = root_task {
+        let rti = PathBuf::from(&root_task.image_path);
+        eprintln!("ROOT_TASK_PATH={}", rti.display());
+        tool_env.push(("ROOT_TASK_PATH", rti.into()));
+    }
+    if root_task.is_some() || !config.libraries.is_empty() {
+        tool_env.push(("UTIL_LIBS_SOURCE_PATH", util_libs_dir.into()));
+        tool_env.push(("UTIL_LIBS_BIN_PATH", build_dir.join("util_libs").into()));
+    }
+    if build_mode == SeL4BuildMode::Lib && !config.libraries.is_empty() {
+        if let Some(ref sel4_libs_dir) = sources.sel4_libs_dir {
+            tool_env.push(("SEL4_LIBS_SOURCE_PATH", sel4_libs_dir.into()));
+            tool_env.push(("SEL4_LIBS_BIN_PATH", build_dir.join("sel4_libs").into()));
+        }
+    }
 
     // Run CMake
     let mut cmake = Command::new("cmake");
     cmake
         .args(cmake_opts.iter().map(|(k, v)| format!("-D{}={}", k, v)))
         .arg("-G")
-        .arg("Ninja")
+        .arg(generator.to_string())
         .arg(".")
         .current_dir(&build_dir)
-        .env("SEL4_TOOLS_DIR", tools_dir);
+        .envs(tool_env.iter().cloned());
 
-    if build_mode == SeL4BuildMode::Kernel {
-        let rti = PathBuf::from(
-            &config
-                .build
-                .root_task
-                .as_ref()
-                .expect("A build profile's  `root_task_image` is required for a kernel build")
-                .image_path,
-        );
+    eprintln!("Running cmake: {:?}", &cmake);
 
-        let util_libs_bin_dir = build_dir.join("util_libs");
-        fs::create_dir_all(&build_dir).expect("Failed to create util libs build dir");
-        println!("ROOT_TASK_PATH={}", rti.display());
-        cmake
-            .env("ROOT_TASK_PATH", rti)
-            .env("UTIL_LIBS_SOURCE_PATH", util_libs_dir)
-            .env("UTIL_LIBS_BIN_PATH", &util_libs_bin_dir);
+    let log_path = build_dir.join(BuildStep::Configure.log_file_name());
+    let (status, error_lines) = run_logged(
+        &mut cmake,
+        "cmake",
+        BuildStep::Configure,
+        &log_path,
+        on_output,
+    )?;
+    if !status.success() {
+        return Err(BuildError::ConfigureFailed {
+            log_path,
+            build_dir,
+            exit_code: status.code(),
+            error_lines,
+        });
     }
 
-    cmake.stdout(Stdio::inherit()).stderr(Stdio::inherit());
-    println!("Running cmake: {:?}", &cmake);
-
-    let output = cmake.output().expect("failed to run cmake");
-    assert!(output.status.success());
-
-    // Run ninja
-    let mut ninja = Command::new("ninja");
-    ninja
+    // Build via cmake, so the build tool of whichever generator is in use gets driven
+    let mut build = Command::new("cmake");
+    build
+        .arg("--build")
+        .arg(".")
+        .arg("--target")
         .arg(match build_mode {
             SeL4BuildMode::Kernel => "all",
-            SeL4BuildMode::Lib => "libsel4.a",
-        })
-        .current_dir(&build_dir)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit());
-    println!("Running ninja: {:?}", &ninja);
-
-    let output = ninja.output().expect("failed to run ninja");
-    assert!(output.status.success());
+            SeL4BuildMode::Lib => "selfe_lib",
+        });
+    if let Some(jobs) = build_jobs(|k| env::var(k).ok()) {
+        build.arg("--").arg(format!("-j{}", jobs));
+    }
+    build.current_dir(&build_dir).envs(tool_env);
+    eprintln!("Running cmake --build: {:?}", &build);
 
-    let sel4_arch = cmake_opts
-        .get("KernelSel4Arch")
-        .expect("KernelSel4Arch missing but required as a sel4 config option");
-    // TODO - should we enforce that this value matches the resolved config platform name?
-    if cmake_opts.get("KernelPlatform").is_some() {
-        panic!("Explicitly supplying a KernelPlatform property interferes with the inner workings of the seL4 cmake build")
+    let log_path = build_dir.join(BuildStep::Build.log_file_name());
+    let (status, error_lines) =
+        run_logged(&mut build, "cmake", BuildStep::Build, &log_path, on_output)?;
+    if !status.success() {
+        return Err(BuildError::CompileFailed {
+            log_path,
+            build_dir,
+            exit_code: status.code(),
+            error_lines,
+        });
     }
-    let kernel_platform = cmake_opts.get("KernelX86Platform").unwrap_or_else(|| {
-        cmake_opts.get("KernelARMPlatform").expect(
-            "KernelARMPlatform or KernelX86Platform missing but required as a sel4 config option",
-        )
-    });
-    match build_mode {
-        SeL4BuildMode::Kernel => match config.context.arch {
-            Arch::X86 => SeL4BuildOutcome::Kernel {
-                build_dir: build_dir.clone(),
-                kernel_path: build_dir
-                    .join("images")
-                    .join(format!("kernel-{}-{}", sel4_arch, kernel_platform)),
-                root_image_path: Some(
-                    build_dir
-                        .join("images")
-                        .join(format!("root_task-image-{}-{}", sel4_arch, kernel_platform)),
-                ),
-            },
-            Arch::Arm => SeL4BuildOutcome::Kernel {
-                build_dir: build_dir.clone(),
-                kernel_path: build_dir
-                    .join("images")
-                    .join(format!("root_task-image-arm-{}", kernel_platform)),
-                root_image_path: None,
-            },
-            _ => panic!("Unsupported target"),
-        },
+
+    Ok(match build_mode {
+        SeL4BuildMode::Kernel => {
+            let artifacts = kernel_artifacts(&build_dir).map_err(io_error(&build_dir))?;
+            let image = |kind| find_artifact(&artifacts, kind).map(|a| a.path.clone());
+            // A separate kernel image is loaded alongside the root task image, as on x86,
+            // or else the root task image bundles the kernel, as with the ARM elfloader
+            let (kernel_path, root_image_path) = match (
+                image(ArtifactKind::KernelImage),
+                image(ArtifactKind::RootImage),
+            ) {
+                (Some(kernel), root) => (kernel, root),
+                (None, Some(root)) => (root, None),
+                (None, None) => {
+                    return Err(BuildError::MissingArtifact {
+                        build_dir,
+                        artifact: "a bootable image in images/",
+                    })
+                }
+            };
+            SeL4BuildOutcome::Kernel {
+                build_dir,
+                kernel_path,
+                root_image_path,
+                artifacts,
+            }
+        }
         SeL4BuildMode::Lib => SeL4BuildOutcome::StaticLib { build_dir },
-    }
+    })
 }
 
 #[cfg(test)]
@@ -336,16 +1414,32 @@ mod tests {
     use std::fs::File;
     use std::io::Write;
     use tempfile::tempdir;
+    #[test]
+    fn build_jobs_prefers_num_jobs() {
+        let vars = |num_jobs: Option<&'static str>, cargo_build_jobs: Option<&'static str>| {
+            move |k: &str| match k {
+                "NUM_JOBS" => num_jobs.map(str::to_string),
+                "CARGO_BUILD_JOBS" => cargo_build_jobs.map(str::to_string),
+                _ => None,
+            }
+        };
+        assert_eq!(Some(4), build_jobs(vars(Some("4"), Some("8"))));
+        assert_eq!(Some(8), build_jobs(vars(None, Some("8"))));
+        assert_eq!(Some(8), build_jobs(vars(Some("lots"), Some("8"))));
+        assert_eq!(None, build_jobs(vars(Some("0"), None)));
+        assert_eq!(None, build_jobs(vars(None, None)));
+    }
+
     #[test]
     fn is_dir_absent_or_empty_when_absent() {
-        assert!(is_dir_absent_or_empty(Path::new(
-            "/314159/2653789/totally_not_a_real_path"
-        )));
+        assert!(
+            is_dir_absent_or_empty(Path::new("/314159/2653789/totally_not_a_real_path")).unwrap()
+        );
     }
     #[test]
     fn is_dir_empty_negative_when_empty() {
         let t = tempdir().expect("Could not make a temp dir");
-        assert!(is_dir_absent_or_empty(t.path()));
+        assert!(is_dir_absent_or_empty(t.path()).unwrap());
     }
     #[test]
     fn is_dir_empty_negative_when_full() {
@@ -354,6 +1448,467 @@ mod tests {
         let mut file = File::create(file_path).expect("Could not create file in temp dir");
         writeln!(file, "A tiny bit of content").expect("Could not write content to dummy file");
         file.flush().expect("Could not flush to file");
-        assert!(!is_dir_absent_or_empty(t.path()));
+        assert!(!is_dir_absent_or_empty(t.path()).unwrap());
+    }
+    #[test]
+    fn is_dir_absent_or_empty_errs_when_file() {
+        let t = tempdir().expect("Could not make a temp dir");
+        let file_path = t.path().join("not-a-dir");
+        File::create(&file_path).expect("Could not create file in temp dir");
+        assert!(is_dir_absent_or_empty(&file_path).is_err());
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args([
+                "-c",
+                "user.name=selfe",
+                "-c",
+                "user.email=selfe@example.com",
+            ])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .expect("Could not run git");
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    /// Make a repository with two commits to a `note.txt` file,
+    /// handing back the url and the revs of both commits
+    fn make_upstream(dir: &Path) -> (String, String, String) {
+        git(dir, &["init", "--quiet"]);
+        fs::write(dir.join("note.txt"), "first").unwrap();
+        git(dir, &["add", "note.txt"]);
+        git(dir, &["commit", "--quiet", "-m", "first"]);
+        let first = git(dir, &["rev-parse", "HEAD"]);
+        fs::write(dir.join("note.txt"), "second").unwrap();
+        git(dir, &["commit", "--quiet", "-am", "second"]);
+        let second = git(dir, &["rev-parse", "HEAD"]);
+        (dir.display().to_string(), first, second)
+    }
+
+    fn kernel_only_sources(url: &str, rev: &str) -> model::SeL4Sources {
+        patched_kernel_only_sources(url, rev, vec![])
+    }
+
+    fn patched_kernel_only_sources(
+        url: &str,
+        rev: &str,
+        patches: Vec<PathBuf>,
+    ) -> model::SeL4Sources {
+        model::SeL4Sources {
+            sel4_libs: None,
+            kernel: model::RepoSource::RemoteGit {
+                url: url.to_string(),
+                target: model::GitTarget::Rev(rev.to_string()),
+                patches,
+            },
+            tools: model::RepoSource::LocalPath(PathBuf::from(".")),
+            util_libs: model::RepoSource::LocalPath(PathBuf::from(".")),
+        }
+    }
+
+    #[test]
+    fn stale_checkout_is_reset_to_expected_rev() {
+        let upstream = tempdir().expect("Could not make a temp dir");
+        let dest = tempdir().expect("Could not make a temp dir");
+        let (url, first, second) = make_upstream(upstream.path());
+        let sources = kernel_only_sources(&url, &first);
+
+        let resolved = resolve_sel4_sources(&sources, dest.path(), false).unwrap();
+        assert_eq!(first, git(&resolved.kernel_dir, &["rev-parse", "HEAD"]));
+
+        git(
+            &resolved.kernel_dir,
+            &["reset", "--quiet", "--hard", &second],
+        );
+        fs::write(resolved.kernel_dir.join("note.txt"), "local edit").unwrap();
+
+        let resolved = resolve_sel4_sources(&sources, dest.path(), false).unwrap();
+        assert_eq!(first, git(&resolved.kernel_dir, &["rev-parse", "HEAD"]));
+        assert_eq!(
+            "first",
+            fs::read_to_string(resolved.kernel_dir.join("note.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn interrupted_clone_is_replaced() {
+        let upstream = tempdir().expect("Could not make a temp dir");
+        let dest = tempdir().expect("Could not make a temp dir");
+        let (url, first, _second) = make_upstream(upstream.path());
+        let partial = dest.path().join(format!("kernel-rev-{}", first));
+        fs::create_dir_all(&partial).unwrap();
+        fs::write(partial.join("leftover"), "half a clone").unwrap();
+
+        let resolved =
+            resolve_sel4_sources(&kernel_only_sources(&url, &first), dest.path(), false).unwrap();
+        assert_eq!(first, git(&resolved.kernel_dir, &["rev-parse", "HEAD"]));
+        assert!(!resolved.kernel_dir.join("leftover").exists());
+    }
+
+    #[test]
+    fn file_in_place_of_checkout_is_an_error() {
+        let dest = tempdir().expect("Could not make a temp dir");
+        let rev = "4d0f02c029560cae0e8d93727eb17d58bcecc2ac";
+        File::create(dest.path().join(format!("kernel-rev-{}", rev))).unwrap();
+        let sources = kernel_only_sources("https://github.com/seL4/seL4", rev);
+        assert!(resolve_sel4_sources(&sources, dest.path(), false).is_err());
+    }
+
+    #[test]
+    fn patches_are_applied_once_on_top_of_target() {
+        let upstream = tempdir().expect("Could not make a temp dir");
+        let dest = tempdir().expect("Could not make a temp dir");
+        let (url, first, _second) = make_upstream(upstream.path());
+        let patch_path = upstream.path().join("greeting.patch");
+        fs::write(
+            &patch_path,
+            "--- a/note.txt\n+++ b/note.txt\n@@ -1 +1 @@\n-first\n\\ No newline at end of file\n+patched\n",
+        )
+        .unwrap();
+        let sources = patched_kernel_only_sources(&url, &first, vec![patch_path]);
+
+        let resolved = resolve_sel4_sources(&sources, dest.path(), false).unwrap();
+        assert_eq!(
+            "patched\n",
+            fs::read_to_string(resolved.kernel_dir.join("note.txt")).unwrap()
+        );
+        assert_eq!(first, git(&resolved.kernel_dir, &["rev-parse", "HEAD~1"]));
+        let patched_head = git(&resolved.kernel_dir, &["rev-parse", "HEAD"]);
+
+        let resolved_again = resolve_sel4_sources(&sources, dest.path(), false).unwrap();
+        assert_eq!(resolved.kernel_dir, resolved_again.kernel_dir);
+        assert_eq!(
+            patched_head,
+            git(&resolved_again.kernel_dir, &["rev-parse", "HEAD"])
+        );
+
+        let unpatched =
+            resolve_sel4_sources(&kernel_only_sources(&url, &first), dest.path(), false).unwrap();
+        assert_ne!(resolved.kernel_dir, unpatched.kernel_dir);
+    }
+
+    #[test]
+    fn branch_heads_are_rechecked_only_after_an_interval() {
+        let upstream = tempdir().expect("Could not make a temp dir");
+        let dest = tempdir().expect("Could not make a temp dir");
+        let (url, _first, second) = make_upstream(upstream.path());
+        let branch = git(upstream.path(), &["rev-parse", "--abbrev-ref", "HEAD"]);
+        let target = model::GitTarget::Branch(branch);
+        let dir = dest.path().join("kernel");
+        fs::create_dir_all(&dir).unwrap();
+        clone_target(&url, &target, &dir).unwrap();
+        let patches = PatchSet::load(&[]).unwrap();
+        assert!(matches!(
+            check_checkout(&url, &target, &patches, &dir),
+            CheckoutState::Fresh
+        ));
+
+        // A new upstream commit goes unnoticed while the recorded head is recent
+        fs::write(upstream.path().join("note.txt"), "third").unwrap();
+        git(upstream.path(), &["commit", "--quiet", "-am", "third"]);
+        assert!(matches!(
+            check_checkout(&url, &target, &patches, &dir),
+            CheckoutState::Fresh
+        ));
+        assert_eq!(second, git(&dir, &["rev-parse", "HEAD"]));
+
+        fs::remove_file(dir.join(".git").join(BRANCH_HEAD_FILE)).unwrap();
+        assert!(matches!(
+            check_checkout(&url, &target, &patches, &dir),
+            CheckoutState::Stale(_)
+        ));
+    }
+
+    fn contextualize(sel4_config: &str) -> model::contextualized::Contextualized {
+        contextualize_with_build(sel4_config, "")
+    }
+
+    fn contextualize_with_build(
+        sel4_config: &str,
+        build: &str,
+    ) -> model::contextualized::Contextualized {
+        let content = format!(
+            r#"[sel4]
+kernel = {{ path = "." }}
+tools = {{ path = "." }}
+util_libs = {{ path = "." }}
+
+[sel4.config]
+{}
+
+[build.sabre]
+{}
+"#,
+            sel4_config, build
+        );
+        model::contextualized::Contextualized::from_str(
+            &content,
+            Arch::Arm,
+            model::SeL4Arch::Aarch32,
+            true,
+            model::Platform("sabre".to_string()),
+            None,
+        )
+        .expect("Could not contextualize")
+    }
+
+    fn build_error(sel4_config: &str, build_mode: SeL4BuildMode) -> BuildError {
+        let out_dir = tempdir().expect("Could not make a temp dir");
+        let here = Path::new(".");
+        match build_sel4(
+            out_dir.path(),
+            here,
+            here,
+            here,
+            &contextualize(sel4_config),
+            build_mode,
+        ) {
+            Err(e) => e,
+            Ok(_) => panic!("Expected the build to fail"),
+        }
+    }
+
+    #[test]
+    fn build_dir_digest_is_independent_of_checkout_location() {
+        let content = r#"[sel4]
+kernel = { path = "deps/seL4" }
+tools = { path = "../seL4_tools" }
+util_libs = { path = "deps/util_libs" }
+
+[sel4.config]
+KernelSel4Arch = "aarch32"
+KernelARMPlatform = "imx6"
+
+[build.sabre]
+cross_compiler_prefix = "arm-linux-gnueabihf-"
+toolchain_dir = "../toolchain"
+
+[build.sabre.debug]
+make_root_task = "cargo build"
+root_task_image = "../target/debug/root-task"
+"#;
+        let digest = |base_dir: &Path| {
+            let config = model::contextualized::Contextualized::from_str(
+                content,
+                Arch::Arm,
+                model::SeL4Arch::Aarch32,
+                true,
+                model::Platform("sabre".to_string()),
+                Some(base_dir),
+            )
+            .expect("Could not contextualize");
+            let kernel_dir = base_dir.join("deps/seL4");
+            let cmake_opts = cmake_options(&config, &kernel_dir, SeL4BuildMode::Kernel);
+            let sources = config.sel4_sources.named();
+            let sources: Vec<(&str, &model::RepoSource, &Path)> = sources
+                .iter()
+                .map(|(name, source)| (*name, *source, base_dir))
+                .collect();
+            build_inputs(
+                &config,
+                &cmake_opts,
+                CMAKELISTS_KERNEL,
+                SeL4BuildMode::Kernel,
+                toolchain_identity(&config, Generator::Ninja, Some(OsStr::new(""))),
+                &kernel_dir,
+                &sources,
+            )
+            .expect("Could not gather build inputs")
+        };
+        let first = tempdir().unwrap();
+        let second = tempdir().unwrap();
+        let first = digest(&first.path().join("app"));
+        let second = digest(&second.path().join("checkouts/app"));
+        assert_eq!(first.digest(), second.digest());
+        assert_eq!(
+            Some(&"../seL4_tools".to_string()),
+            first.sources["tools"].get("path")
+        );
+        assert_eq!(None, first.toolchain.get("toolchain_dir"));
+        assert_eq!(
+            Some(&"../target/debug/root-task".to_string()),
+            first.context.get("root_task_image")
+        );
+    }
+
+    #[test]
+    fn build_requires_sel4_arch() {
+        match build_error("KernelARMPlatform = 'imx6'", SeL4BuildMode::Lib) {
+            BuildError::InvalidConfig(msg) => assert!(msg.contains("KernelSel4Arch")),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn build_rejects_explicit_kernel_platform() {
+        match build_error(
+            "KernelSel4Arch = 'aarch32'\nKernelPlatform = 'imx6'",
+            SeL4BuildMode::Lib,
+        ) {
+            BuildError::InvalidConfig(msg) => assert!(msg.contains("KernelPlatform")),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn kernel_build_requires_root_task() {
+        match build_error(
+            "KernelSel4Arch = 'aarch32'\nKernelARMPlatform = 'imx6'",
+            SeL4BuildMode::Kernel,
+        ) {
+            BuildError::InvalidConfig(msg) => assert!(msg.contains("root_task_image")),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn clang_builds_use_llvm_toolchain_file() {
+        let kernel_dir = Path::new("/kernel");
+        let gcc = cmake_options(
+            &contextualize_with_build("", "cross_compiler_prefix = 'arm-linux-gnueabihf-'"),
+            kernel_dir,
+            SeL4BuildMode::Lib,
+        );
+        assert_eq!(
+            Some("arm-linux-gnueabihf-"),
+            gcc.get("CROSS_COMPILER_PREFIX").map(String::as_str)
+        );
+        assert_eq!(
+            Some("/kernel/gcc.cmake"),
+            gcc.get("CMAKE_TOOLCHAIN_FILE").map(String::as_str)
+        );
+        assert!(!gcc.contains_key("TRIPLE"));
+
+        let clang = cmake_options(
+            &contextualize_with_build(
+                "",
+                "cross_compiler_prefix = 'arm-linux-gnueabihf-'\ncompiler = 'clang'",
+            ),
+            kernel_dir,
+            SeL4BuildMode::Lib,
+        );
+        assert_eq!(
+            Some("arm-linux-gnueabihf"),
+            clang.get("TRIPLE").map(String::as_str)
+        );
+        assert_eq!(
+            Some("/kernel/llvm.cmake"),
+            clang.get("CMAKE_TOOLCHAIN_FILE").map(String::as_str)
+        );
+        assert!(!clang.contains_key("CROSS_COMPILER_PREFIX"));
+    }
+
+    #[test]
+    fn clang_build_requires_triple() {
+        let out_dir = tempdir().expect("Could not make a temp dir");
+        let here = Path::new(".");
+        let config = contextualize_with_build(
+            "KernelSel4Arch = 'aarch32'\nKernelARMPlatform = 'imx6'",
+            "compiler = 'clang'",
+        );
+        match build_sel4(
+            out_dir.path(),
+            here,
+            here,
+            here,
+            &config,
+            SeL4BuildMode::Lib,
+        ) {
+            Err(BuildError::InvalidConfig(msg)) => assert!(msg.contains("triple"), "{}", msg),
+            Err(e) => panic!("Expected an InvalidConfig error, found {}", e),
+            Ok(_) => panic!("Expected the build to fail"),
+        }
+    }
+
+    #[test]
+    fn toolchain_dir_bin_is_searched_first() {
+        let dir = tempdir().expect("Could not make a temp dir");
+        fs::create_dir_all(dir.path().join("bin")).unwrap();
+        let config =
+            contextualize_with_build("", &format!("toolchain_dir = '{}'", dir.path().display()));
+        let path = toolchain_path(&config).expect("Expected a PATH");
+        assert_eq!(Some(dir.path().join("bin")), env::split_paths(&path).next());
+        assert!(toolchain_path(&contextualize("")).is_none());
+    }
+
+    #[test]
+    fn error_lines_are_recognized() {
+        assert!(is_error_line("CMake Error at CMakeLists.txt:12 (include):"));
+        assert!(is_error_line(
+            "/kernel/src/arch/arm/kernel/boot.c:42:5: error: 'foo' undeclared"
+        ));
+        assert!(is_error_line("FAILED: kernel/kernel.elf"));
+        assert!(is_error_line(
+            "make[2]: *** [kernel/CMakeFiles/kernel.elf.dir/build.make:99] Error 1"
+        ));
+        assert!(!is_error_line("[12/345] Building C object kernel.c.obj"));
+        assert!(!is_error_line("-- Configuring done"));
+    }
+
+    #[test]
+    fn run_logged_captures_output_and_errors() {
+        let dir = tempdir().expect("Could not make a temp dir");
+        let log_path = dir.path().join(BuildStep::Build.log_file_name());
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg("echo compiling; echo 'boot.c:1:2: error: oops' >&2; exit 3");
+        let mut seen = Vec::new();
+        let (status, error_lines) = run_logged(
+            &mut cmd,
+            "sh",
+            BuildStep::Build,
+            &log_path,
+            &mut |step, line| seen.push((step, line.to_string())),
+        )
+        .unwrap();
+        assert_eq!(Some(3), status.code());
+        assert_eq!(vec!["boot.c:1:2: error: oops".to_string()], error_lines);
+        assert_eq!(2, seen.len());
+        assert!(seen.iter().all(|(step, _)| *step == BuildStep::Build));
+        let log = fs::read_to_string(&log_path).unwrap();
+        assert!(log.contains("compiling"));
+        assert!(log.contains("boot.c:1:2: error: oops"));
+    }
+
+    #[test]
+    fn built_libraries_are_read_from_records() {
+        let build_dir = tempdir().expect("Could not make a temp dir");
+        let config = contextualize_with_build("", "");
+        assert!(built_libraries(build_dir.path(), &config)
+            .unwrap()
+            .is_empty());
+
+        let mut config = config;
+        config.libraries = vec!["platsupport".to_string()];
+        let options = cmake_options(&config, Path::new("/kernel"), SeL4BuildMode::Lib);
+        assert_eq!(
+            Some("platsupport"),
+            options.get("SELFE_LIBRARIES").map(String::as_str)
+        );
+        assert!(built_libraries(build_dir.path(), &config).is_err());
+
+        let records = build_dir.path().join(BUILT_LIBRARIES_DIR);
+        fs::create_dir_all(&records).unwrap();
+        fs::write(
+            records.join("platsupport.txt"),
+            "/build/util_libs/libplatsupport/libplatsupport.a\n\
+             /util_libs/libplatsupport/include\n\
+             /util_libs/libplatsupport/plat_include/imx6\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![BuiltLibrary {
+                name: "platsupport".to_string(),
+                archive: PathBuf::from("/build/util_libs/libplatsupport/libplatsupport.a"),
+                include_dirs: vec![
+                    PathBuf::from("/util_libs/libplatsupport/include"),
+                    PathBuf::from("/util_libs/libplatsupport/plat_include/imx6"),
+                ],
+            }],
+            built_libraries(build_dir.path(), &config).unwrap()
+        );
     }
 }
@@ -0,0 +1,220 @@
+//! Watching the output of a simulation for the patterns that end a `selfe test`
+
+use crate::model::TestSettings;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::Child;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What ends a test, with the defaults of `TestSettings` filled in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expectations {
+    pub success: Vec<String>,
+    pub failure: Vec<String>,
+    pub timeout: Duration,
+}
+
+impl Expectations {
+    pub fn from_settings(settings: &TestSettings) -> Self {
+        Expectations {
+            success: settings
+                .success
+                .clone()
+                .unwrap_or_else(|| vec!["TEST PASSED".to_string()]),
+            failure: settings
+                .failure
+                .clone()
+                .unwrap_or_else(|| vec!["*** Panic:".to_string()]),
+            timeout: Duration::from_secs(settings.timeout_secs.unwrap_or(300)),
+        }
+    }
+
+    /// The verdict a line of output brings, if any. Failure wins when a line matches both.
+    pub fn match_line(&self, line: &str) -> Option<Verdict> {
+        if let Some(p) = self.failure.iter().find(|p| line.contains(p.as_str())) {
+            Some(Verdict::Failed {
+                pattern: p.clone(),
+                line: line.to_string(),
+            })
+        } else {
+            self.success
+                .iter()
+                .find(|p| line.contains(p.as_str()))
+                .map(|p| Verdict::Passed {
+                    pattern: p.clone(),
+                    line: line.to_string(),
+                })
+        }
+    }
+}
+
+/// How a test ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Passed {
+        pattern: String,
+        line: String,
+    },
+    Failed {
+        pattern: String,
+        line: String,
+    },
+    TimedOut(Duration),
+    /// The simulation exited before any pattern was seen, with this code
+    /// unless it was killed by a signal. Exiting with 0 passes the test.
+    Exited(Option<i32>),
+}
+
+impl Verdict {
+    pub fn is_success(&self) -> bool {
+        matches!(self, Verdict::Passed { .. } | Verdict::Exited(Some(0)))
+    }
+}
+
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            Verdict::Passed { pattern, .. } => {
+                f.write_fmt(format_args!("passed, matched \"{}\"", pattern))
+            }
+            Verdict::Failed { pattern, line } => {
+                f.write_fmt(format_args!("failed, matched \"{}\" in: {}", pattern, line))
+            }
+            Verdict::TimedOut(timeout) => f.write_fmt(format_args!(
+                "timed out after {}s without a result",
+                timeout.as_secs()
+            )),
+            Verdict::Exited(Some(0)) => f.write_str("passed, the simulation exited with code 0"),
+            Verdict::Exited(Some(code)) => f.write_fmt(format_args!(
+                "failed, the simulation exited with code {}",
+                code
+            )),
+            Verdict::Exited(None) => f.write_str("simulation was killed without a result"),
+        }
+    }
+}
+
+/// Read lines from `output` until one brings a verdict, or the timeout passes,
+/// echoing each to `echo`. `Ok(None)` when the output ends first.
+pub fn watch_output<R: Read + Send + 'static, W: Write>(
+    output: R,
+    expectations: &Expectations,
+    mut echo: W,
+) -> Result<Option<Verdict>, String> {
+    let (tx, rx) = mpsc::channel();
+    // Reading blocks, so it is left to a thread that outlives a timeout
+    thread::spawn(move || {
+        let mut reader = BufReader::new(output);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if tx.send(String::from_utf8_lossy(&buf).into_owned()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    let deadline = Instant::now() + expectations.timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(remaining) {
+            Ok(line) => {
+                echo.write_all(line.as_bytes())
+                    .and_then(|_| echo.flush())
+                    .map_err(|e| format!("failed to echo simulation output: {}", e))?;
+                if let Some(verdict) = expectations.match_line(line.trim_end()) {
+                    return Ok(Some(verdict));
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                return Ok(Some(Verdict::TimedOut(expectations.timeout)))
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(None),
+        }
+    }
+}
+
+/// Watch the piped stdout of `child` for a verdict, killing it once one is reached
+pub fn watch_child<W: Write>(
+    child: &mut Child,
+    expectations: &Expectations,
+    echo: W,
+) -> Result<Verdict, String> {
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| "the simulation's stdout is not piped".to_string())?;
+    let verdict = watch_output(stdout, expectations, echo);
+    match verdict {
+        Ok(None) => {
+            let status = child
+                .wait()
+                .map_err(|e| format!("failed to wait for the simulation: {}", e))?;
+            Ok(Verdict::Exited(status.code()))
+        }
+        other => {
+            // It may have exited already, which is fine
+            let _ = child.kill();
+            let _ = child.wait();
+            other.map(|v| v.expect("a verdict was reached"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Command, Stdio};
+
+    fn expectations(timeout_secs: u64) -> Expectations {
+        Expectations::from_settings(&TestSettings {
+            timeout_secs: Some(timeout_secs),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn lines_are_matched_against_patterns() {
+        let e = expectations(1);
+        assert_eq!(None, e.match_line("Hello aarch64 world!"));
+        assert!(e.match_line("all TEST PASSED").unwrap().is_success());
+        assert_eq!(
+            Some(Verdict::Failed {
+                pattern: "*** Panic:".to_string(),
+                line: "*** Panic: TEST PASSED".to_string()
+            }),
+            e.match_line("*** Panic: TEST PASSED")
+        );
+    }
+
+    #[test]
+    fn output_is_watched_until_a_verdict() {
+        let mut echo = Vec::new();
+        let output: &'static [u8] = b"booting\r\nTEST PASSED\r\nafterwards\n";
+        let verdict = watch_output(output, &expectations(5), &mut echo).unwrap();
+        assert!(verdict.unwrap().is_success());
+        assert_eq!(b"booting\r\nTEST PASSED\r\n".to_vec(), echo);
+        let ended = watch_output(&b"no verdict"[..], &expectations(5), Vec::new()).unwrap();
+        assert_eq!(None, ended);
+    }
+
+    #[test]
+    fn hung_children_time_out_and_are_killed() {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg("echo booting; exec sleep 30")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let started = Instant::now();
+        let verdict = watch_child(&mut child, &expectations(1), Vec::new()).unwrap();
+        assert_eq!(Verdict::TimedOut(Duration::from_secs(1)), verdict);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(child.try_wait().unwrap().is_some());
+    }
+}
@@ -0,0 +1,149 @@
+//! Debugging a simulation with gdb, through QEMU's gdb server
+
+use crate::artifacts::ArtifactKind;
+use crate::compilation::toolchain_path;
+use crate::host::find_program;
+use crate::model::contextualized::Contextualized;
+use crate::run::{boot_artifact, Boot};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The port of the gdb server QEMU starts for `-s`
+pub const GDB_PORT: u16 = 1234;
+
+/// The gdb for the platform: `<cross_compiler_prefix>gdb`, or else
+/// gdb-multiarch when that is not installed but gdb-multiarch is
+pub fn gdb_program(config: &Contextualized) -> String {
+    let prefix = match config.build.cross_compiler_prefix {
+        Some(ref prefix) if !prefix.is_empty() => prefix,
+        _ => return "gdb".to_string(),
+    };
+    let cross_gdb = format!("{}gdb", prefix);
+    let path = toolchain_path(config);
+    let path = path.as_deref();
+    if find_program(&cross_gdb, path).is_none() && find_program("gdb-multiarch", path).is_some() {
+        "gdb-multiarch".to_string()
+    } else {
+        cross_gdb
+    }
+}
+
+/// gdb commands connecting to the simulation, with the symbols of the kernel
+/// ELF and of the root task loaded at the addresses they are linked for
+pub fn gdbinit(kernel_elf: &Path, root_task: Option<&Path>) -> Result<String, String> {
+    let mut script = String::from("# Written by selfe simulate --gdb\nset confirm off\n");
+    script.push_str(&format!("symbol-file {}\n", gdb_quote(kernel_elf)));
+    if let Some(root_task) = root_task {
+        let text = elf_section_address(root_task, ".text")?.ok_or_else(|| {
+            format!(
+                "The root task {} has no .text section to load symbols for",
+                root_task.display()
+            )
+        })?;
+        script.push_str(&format!(
+            "add-symbol-file {} {:#x}\n",
+            gdb_quote(root_task),
+            text
+        ));
+    }
+    script.push_str(&format!("target remote :{}\n", GDB_PORT));
+    Ok(script)
+}
+
+/// Write the gdbinit for `boot` to `.gdbinit` in its build dir, returning its path
+pub fn write_gdbinit(boot: &Boot, config: &Contextualized) -> Result<PathBuf, String> {
+    let kernel_elf = boot_artifact(boot, ArtifactKind::KernelElf).ok_or_else(|| {
+        format!(
+            "No kernel ELF was found in {} to load symbols from",
+            boot.build_dir.display()
+        )
+    })?;
+    let root_task = config
+        .build
+        .root_task
+        .as_ref()
+        .map(|r| r.image_path.as_path());
+    let script = gdbinit(&kernel_elf.path, root_task)?;
+    let path = boot.build_dir.join(".gdbinit");
+    fs::write(&path, script).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+fn gdb_quote(path: &Path) -> String {
+    format!("\"{}\"", path.display().to_string().replace('"', "\\\""))
+}
+
+/// The address of the named section of an ELF file, if it has that section
+pub fn elf_section_address(path: &Path, section: &str) -> Result<Option<u64>, String> {
+    let data = fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let malformed = || format!("{} is not a well-formed ELF file", path.display());
+    if data.len() < 6 || &data[..4] != b"\x7fELF" {
+        return Err(malformed());
+    }
+    let is_64 = data[4] == 2;
+    let is_le = data[5] == 1;
+    let read = |offset: usize, size: usize| -> Option<u64> {
+        let bytes = data.get(offset..offset + size)?;
+        let mut value = 0u64;
+        for i in 0..size {
+            let byte = if is_le { bytes[size - 1 - i] } else { bytes[i] };
+            value = (value << 8) | u64::from(byte);
+        }
+        Some(value)
+    };
+    let word = if is_64 { 8 } else { 4 };
+    // Offsets of the header fields and section header fields used, by class
+    let (shoff, shentsize, shnum, shstrndx) = if is_64 {
+        (0x28, 0x3a, 0x3c, 0x3e)
+    } else {
+        (0x20, 0x2e, 0x30, 0x32)
+    };
+    let (sh_addr, sh_offset) = if is_64 { (0x10, 0x18) } else { (0x0c, 0x10) };
+
+    let headers = || -> Option<Option<u64>> {
+        let shoff = read(shoff, word)? as usize;
+        let shentsize = read(shentsize, 2)? as usize;
+        let shnum = read(shnum, 2)? as usize;
+        let strtab_header = shoff + read(shstrndx, 2)? as usize * shentsize;
+        let strtab = read(strtab_header + sh_offset, word)? as usize;
+        for i in 0..shnum {
+            let header = shoff + i * shentsize;
+            let name_start = strtab + read(header, 4)? as usize;
+            let name_len = data.get(name_start..)?.iter().position(|b| *b == 0)?;
+            if &data[name_start..name_start + name_len] == section.as_bytes() {
+                return Some(Some(read(header + sh_addr, word)?));
+            }
+        }
+        Some(None)
+    };
+    headers().ok_or_else(malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn sections_are_found_in_elf_files() {
+        // The test executable is an ELF file on the hosts this runs on
+        let exe = env::current_exe().unwrap();
+        if fs::read(&exe).unwrap().get(..4) != Some(&b"\x7fELF"[..]) {
+            return;
+        }
+        assert!(elf_section_address(&exe, ".text").unwrap().is_some());
+        assert_eq!(None, elf_section_address(&exe, ".no_such_section").unwrap());
+        let script = gdbinit(Path::new("/build/kernel/kernel.elf"), Some(&exe)).unwrap();
+        assert!(script.contains("symbol-file \"/build/kernel/kernel.elf\"\n"));
+        assert!(script.contains("add-symbol-file"));
+        assert!(script.ends_with("target remote :1234\n"));
+    }
+
+    #[test]
+    fn non_elf_files_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("not-elf");
+        fs::write(&path, b"#!/bin/sh\n").unwrap();
+        assert!(elf_section_address(&path, ".text").is_err());
+    }
+}
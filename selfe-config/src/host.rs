@@ -0,0 +1,499 @@
+//! Checking the host for the tools that building and simulating need, for
+//! `selfe platforms` and `selfe doctor`
+
+use crate::compilation::{tool_version, toolchain_path};
+use crate::model::contextualized::Contextualized;
+use crate::model::{Compiler, Generator};
+use crate::run::default_qemu_binary;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Python modules imported by the seL4 build's scripts, with the pip
+/// packages providing them
+pub const PYTHON_MODULES: &[(&str, &str)] = &[
+    ("jinja2", "Jinja2"),
+    ("ply", "ply"),
+    ("six", "six"),
+    ("future", "future"),
+    ("yaml", "PyYAML"),
+    ("jsonschema", "jsonschema"),
+    ("pyfdt", "pyfdt"),
+    ("elftools", "pyelftools"),
+];
+
+/// A program needed for a platform, and where it was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tool {
+    /// What it is needed for, like "compiler" or "qemu"
+    pub purpose: &'static str,
+    pub program: String,
+    /// Where it is on the PATH, when installed
+    pub path: Option<PathBuf>,
+}
+
+/// Where `program` is on `path`, or this process's PATH when that is `None`.
+/// Programs given with a dir are looked for there.
+pub fn find_program(program: &str, path: Option<&OsStr>) -> Option<PathBuf> {
+    if Path::new(program).components().count() > 1 {
+        let program = PathBuf::from(program);
+        return if program.is_file() {
+            Some(program)
+        } else {
+            None
+        };
+    }
+    let path = path
+        .map(OsStr::to_os_string)
+        .or_else(|| env::var_os("PATH"))?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// The programs needed to build and simulate for `config`: the compiler,
+/// cmake and the build tool, looked for as the build does with any
+/// `toolchain_dir`, then python and QEMU
+pub fn required_tools(config: &Contextualized) -> Vec<Tool> {
+    let path = toolchain_path(config);
+    let path = path.as_deref();
+    let tool = |purpose, program: &str, path| Tool {
+        purpose,
+        program: program.to_string(),
+        path: find_program(program, path),
+    };
+
+    let compiler = match (
+        config.build.compiler,
+        config.build.cross_compiler_prefix.as_ref(),
+    ) {
+        (Compiler::Clang, _) => "clang".to_string(),
+        (Compiler::Gcc, Some(prefix)) => format!("{}gcc", prefix),
+        (Compiler::Gcc, None) => "gcc".to_string(),
+    };
+    let build_tool = build_tool(config.build.generator, path);
+    let mut tools = vec![
+        tool("compiler", &compiler, path),
+        tool("cmake", "cmake", path),
+        tool("build tool", build_tool, path),
+        tool("python", "python3", path),
+    ];
+    let qemu = config
+        .simulate
+        .binary
+        .as_deref()
+        .or_else(|| default_qemu_binary(config.context.sel4_arch));
+    if let Some(qemu) = qemu {
+        tools.push(tool("qemu", qemu, None));
+    }
+    tools
+}
+
+/// The build tool of the configured generator. As with `compilation::select_generator`,
+/// ninja is preferred when none is configured, and make used when only it is installed.
+pub fn build_tool(generator: Option<Generator>, path: Option<&OsStr>) -> &'static str {
+    match generator {
+        Some(generator) => generator.build_tool(),
+        None if find_program("ninja", path).is_none() && find_program("make", path).is_some() => {
+            "make"
+        }
+        None => "ninja",
+    }
+}
+
+/// The modules of `PYTHON_MODULES` that `python` can't import, with their pip packages
+pub fn missing_python_modules(python: &Path) -> Result<Vec<(&'static str, &'static str)>, String> {
+    let script = "import importlib.util, sys\n\
+                  for m in sys.argv[1:]:\n    \
+                  if importlib.util.find_spec(m) is None: print(m)\n";
+    let output = Command::new(python)
+        .arg("-c")
+        .arg(script)
+        .args(PYTHON_MODULES.iter().map(|(module, _)| module))
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| format!("Can't run {}: {}", python.display(), e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed to check for modules ({})",
+            python.display(),
+            output.status
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let missing: Vec<&str> = stdout.lines().map(str::trim).collect();
+    Ok(PYTHON_MODULES
+        .iter()
+        .filter(|(module, _)| missing.contains(module))
+        .cloned()
+        .collect())
+}
+
+/// A check of the host by `selfe doctor`, with how to fix it when it fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    /// What was checked, like "cmake" or "arm-linux-gnueabihf-gcc"
+    pub name: String,
+    /// What was found, like the version
+    pub found: Option<String>,
+    /// Why the check failed
+    pub problem: Option<String>,
+    pub fix: Option<String>,
+}
+
+impl Check {
+    pub fn is_ok(&self) -> bool {
+        self.problem.is_none()
+    }
+
+    fn failed(name: &str, found: Option<String>, problem: String, fix: String) -> Check {
+        Check {
+            name: name.to_string(),
+            found,
+            problem: Some(problem),
+            fix: Some(fix),
+        }
+    }
+}
+
+/// The first dotted version number within a line of `--version` output
+pub fn parse_version(line: &str) -> Option<Vec<u32>> {
+    line.split(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .filter(|word| word.contains('.'))
+        .map(|word| {
+            word.split('.')
+                .map(str::parse)
+                .collect::<Result<Vec<u32>, _>>()
+        })
+        .find_map(Result::ok)
+}
+
+/// Check that `program --version` runs, and reports at least `minimum` when given
+pub fn check_version(
+    program: &str,
+    path: Option<&OsStr>,
+    minimum: Option<&[u32]>,
+    install: &str,
+) -> Check {
+    let version = match tool_version(program, path) {
+        Some(version) => version,
+        None => {
+            return Check::failed(
+                program,
+                None,
+                "not found, or `--version` failed".to_string(),
+                install.to_string(),
+            )
+        }
+    };
+    match (minimum, parse_version(&version)) {
+        (Some(minimum), Some(found)) if found.as_slice() < minimum => {
+            let minimum = minimum
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(".");
+            Check::failed(
+                program,
+                Some(version),
+                format!("version {} or later is needed", minimum),
+                install.to_string(),
+            )
+        }
+        _ => Check {
+            name: program.to_string(),
+            found: Some(version),
+            problem: None,
+            fix: None,
+        },
+    }
+}
+
+/// How to install the compiler named by a `cross_compiler_prefix`, or plain gcc
+pub fn compiler_install_hint(cross_compiler_prefix: Option<&str>) -> String {
+    match cross_compiler_prefix.map(|p| p.trim_end_matches('-')) {
+        Some(triple) if !triple.is_empty() => format!(
+            "install the {} cross compiler, like the gcc-{} package on Debian and Ubuntu, \
+             or set toolchain_dir in [build.*] to one",
+            triple, triple
+        ),
+        _ => "install gcc, like the build-essential package on Debian and Ubuntu".to_string(),
+    }
+}
+
+/// Check the build tool run by cmake: ninja 1.7 or later, or make
+fn check_build_tool(program: &str, path: Option<&OsStr>) -> Check {
+    match program {
+        "make" => check_version(
+            program,
+            path,
+            None,
+            "install make, like the build-essential package on Debian and Ubuntu",
+        ),
+        _ => check_version(
+            program,
+            path,
+            Some(&[1, 7]),
+            "install ninja: apt install ninja-build, or pip3 install --user ninja",
+        ),
+    }
+}
+
+/// How to install a QEMU binary, like qemu-system-arm
+pub fn qemu_install_hint(binary: &str) -> String {
+    let package = match binary {
+        "qemu-system-x86_64" => "qemu-system-x86",
+        "qemu-system-aarch64" => "qemu-system-arm",
+        other => other,
+    };
+    format!(
+        "install QEMU: apt install {}, dnf install {}, or brew install qemu",
+        package, binary
+    )
+}
+
+/// Where libclang is, which bindgen needs to generate the selfe-sys bindings:
+/// in `LIBCLANG_PATH`, `llvm-config --libdir`, or the usual library dirs
+pub fn find_libclang() -> Option<PathBuf> {
+    let mut dirs: Vec<PathBuf> = env::var_os("LIBCLANG_PATH")
+        .map(|dir| vec![PathBuf::from(dir)])
+        .unwrap_or_default();
+    if let Ok(output) = Command::new("llvm-config")
+        .arg("--libdir")
+        .stderr(Stdio::null())
+        .output()
+    {
+        if output.status.success() {
+            dirs.push(PathBuf::from(
+                String::from_utf8_lossy(&output.stdout).trim(),
+            ));
+        }
+    }
+    for dir in [
+        "/usr/lib",
+        "/usr/lib64",
+        "/usr/local/lib",
+        "/usr/lib/x86_64-linux-gnu",
+        "/usr/lib/aarch64-linux-gnu",
+        "/Library/Developer/CommandLineTools/usr/lib",
+    ]
+    .iter()
+    {
+        dirs.push(PathBuf::from(dir));
+    }
+    // Versioned installs, like /usr/lib/llvm-14/lib
+    if let Ok(entries) = fs::read_dir("/usr/lib") {
+        let mut llvm_dirs: Vec<PathBuf> = entries
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| matches!(p.file_name().and_then(OsStr::to_str), Some(n) if n.starts_with("llvm")))
+            .map(|p| p.join("lib"))
+            .collect();
+        llvm_dirs.sort();
+        dirs.extend(llvm_dirs.into_iter().rev());
+    }
+    dirs.iter().find_map(|dir| {
+        fs::read_dir(dir)
+            .ok()?
+            .flatten()
+            .map(|e| e.path())
+            .find(|p| {
+                let name = p
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                name.starts_with("libclang")
+                    && !name.starts_with("libclang-cpp")
+                    && (name.contains(".so") || name.ends_with(".dylib") || name.ends_with(".dll"))
+            })
+    })
+}
+
+/// The checks of `selfe doctor`: git, cmake, python3 and its modules, and
+/// libclang, then the compilers, build tools and QEMU binaries of each of
+/// `configs`, or the build tool that would be picked when there are none
+pub fn doctor_checks(configs: &[Contextualized]) -> Vec<Check> {
+    let mut checks = vec![
+        check_version(
+            "git",
+            None,
+            None,
+            "install git, like the git package on Debian and Ubuntu",
+        ),
+        check_version(
+            "cmake",
+            None,
+            Some(&[3, 12]),
+            "install cmake 3.12 or later: apt install cmake, or pip3 install --user cmake",
+        ),
+        check_version(
+            "python3",
+            None,
+            Some(&[3, 5]),
+            "install python 3.5 or later, like the python3 and python3-pip packages",
+        ),
+    ];
+    if let Some(python) = find_program("python3", None) {
+        checks.push(match missing_python_modules(&python) {
+            Ok(ref missing) if missing.is_empty() => Check {
+                name: "python modules".to_string(),
+                found: Some(
+                    PYTHON_MODULES
+                        .iter()
+                        .map(|(module, _)| *module)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+                problem: None,
+                fix: None,
+            },
+            Ok(missing) => Check::failed(
+                "python modules",
+                None,
+                format!(
+                    "missing {}",
+                    missing
+                        .iter()
+                        .map(|(module, _)| *module)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                format!(
+                    "pip3 install --user {}",
+                    missing
+                        .iter()
+                        .map(|(_, package)| *package)
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                ),
+            ),
+            Err(e) => Check::failed(
+                "python modules",
+                None,
+                e,
+                "check that python3 runs".to_string(),
+            ),
+        });
+    }
+    checks.push(match find_libclang() {
+        Some(path) => Check {
+            name: "libclang".to_string(),
+            found: Some(path.display().to_string()),
+            problem: None,
+            fix: None,
+        },
+        None => Check::failed(
+            "libclang",
+            None,
+            "not found, and bindgen needs it to build selfe-sys".to_string(),
+            "install libclang: apt install libclang-dev, or brew install llvm, \
+             or set LIBCLANG_PATH to the dir holding it"
+                .to_string(),
+        ),
+    });
+
+    // Without a config, the build tool is the one picked when no generator is configured
+    if configs.is_empty() {
+        checks.push(check_build_tool(build_tool(None, None), None));
+    }
+
+    let mut checked: Vec<String> = Vec::new();
+    for config in configs.iter() {
+        let path = toolchain_path(config);
+        for tool in required_tools(config) {
+            if checked.contains(&tool.program) {
+                continue;
+            }
+            checked.push(tool.program.clone());
+            match tool.purpose {
+                "compiler" => checks.push(check_version(
+                    &tool.program,
+                    path.as_deref(),
+                    None,
+                    &compiler_install_hint(config.build.cross_compiler_prefix.as_deref()),
+                )),
+                "build tool" => checks.push(check_build_tool(&tool.program, path.as_deref())),
+                "qemu" => checks.push(check_version(
+                    &tool.program,
+                    None,
+                    None,
+                    &qemu_install_hint(&tool.program),
+                )),
+                _ => (),
+            }
+        }
+    }
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Arch, Platform, SeL4Arch};
+
+    #[test]
+    fn tools_are_found_for_a_platform() {
+        let dir = tempfile::tempdir().unwrap();
+        let sh = find_program("sh", None).expect("sh is on the PATH");
+        assert_eq!(
+            Some(sh.clone()),
+            find_program(&sh.display().to_string(), None)
+        );
+        assert_eq!(None, find_program("sh", Some(dir.path().as_os_str())));
+
+        let mut config = Contextualized::from_full(
+            &crate::model::get_default_config(),
+            Arch::Arm,
+            SeL4Arch::Aarch32,
+            true,
+            Platform("sabre".to_string()),
+            None,
+        )
+        .unwrap();
+        config.build.cross_compiler_prefix = Some("arm-linux-gnueabihf-".to_string());
+        config.simulate.binary = Some("/no/such/qemu-system-arm".to_string());
+        let tools = required_tools(&config);
+        let programs: Vec<&str> = tools.iter().map(|t| t.program.as_str()).collect();
+        assert_eq!("arm-linux-gnueabihf-gcc", programs[0]);
+        assert!(programs.contains(&"cmake"));
+        assert_eq!(None, tools.last().unwrap().path);
+    }
+
+    #[test]
+    fn versions_are_checked() {
+        assert_eq!(Some(vec![3, 22, 1]), parse_version("cmake version 3.22.1"));
+        assert_eq!(
+            Some(vec![8, 2, 1]),
+            parse_version("QEMU emulator version 8.2.1 (Debian 1:8.2.1+ds-1)")
+        );
+        assert_eq!(None, parse_version("ninja, unversioned"));
+        let missing = check_version("no-such-selfe-tool", None, None, "install it");
+        assert!(!missing.is_ok());
+        assert_eq!(Some("install it".to_string()), missing.fix);
+        assert!(
+            compiler_install_hint(Some("arm-linux-gnueabihf-")).contains("gcc-arm-linux-gnueabihf")
+        );
+    }
+
+    #[test]
+    fn doctor_checks_the_configured_build_tool() {
+        let mut config = Contextualized::from_full(
+            &crate::model::get_default_config(),
+            Arch::Arm,
+            SeL4Arch::Aarch32,
+            true,
+            Platform("sabre".to_string()),
+            None,
+        )
+        .unwrap();
+        config.build.generator = Some(Generator::UnixMakefiles);
+        let names: Vec<String> = doctor_checks(&[config])
+            .into_iter()
+            .map(|check| check.name)
+            .collect();
+        assert!(names.contains(&"make".to_string()));
+        assert!(!names.contains(&"ninja".to_string()));
+    }
+}
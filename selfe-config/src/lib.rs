@@ -1,3 +1,12 @@
+pub mod artifacts;
+pub mod build_cache;
 pub mod build_helpers;
+pub mod build_info;
 pub mod compilation;
+pub mod expect;
+pub mod gdb;
+pub mod host;
 pub mod model;
+pub mod root_task;
+pub mod run;
+pub mod watch;
@@ -2,12 +2,19 @@ use clap::{crate_version, App, AppSettings, Arg, SubCommand};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::str::FromStr;
-use std::{env, fs};
+use std::{env, fs, io};
 
 use selfe_config::compilation::{
-    build_sel4, resolve_sel4_sources, ResolvedSeL4Source, SeL4BuildMode, SeL4BuildOutcome,
+    build_sel4_with_output, resolve_sel4_sources, ResolvedSeL4Source, SeL4BuildMode,
+    SeL4BuildOutcome,
 };
+use selfe_config::expect::Verdict;
+use selfe_config::model::contextualized::RootTask;
+use selfe_config::model::full::Full;
+use selfe_config::model::Runner;
 use selfe_config::model::{Arch, Platform, SeL4Arch};
+use selfe_config::root_task;
+use selfe_config::run::{Boot, CommandTemplate, RunBackend};
 
 /// Walk up the directory tree from `start_dir`, looking for "sel4.toml"
 fn find_sel4_toml(start_dir: &Path) -> Option<PathBuf> {
@@ -29,26 +36,179 @@ fn find_sel4_toml(start_dir: &Path) -> Option<PathBuf> {
 }
 
 pub struct BuildParams {
-    sel4_arch: SeL4Arch,
+    /// When absent, the platform's default from sel4.toml
+    sel4_arch: Option<SeL4Arch>,
     arch: Option<Arch>,
     platform: Platform,
     is_debug: bool,
     is_verbose: bool,
+    message_format: MessageFormat,
+}
+
+/// How the results of a build are reported on stdout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageFormat {
+    Human,
+    /// A single JSON object, with all other output sent to stderr
+    Json,
+}
+
+impl FromStr for MessageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(MessageFormat::Human),
+            "json" => Ok(MessageFormat::Json),
+            _ => Err(format!("Unknown message format: {}", s)),
+        }
+    }
+}
+
+/// Why a command failed. Each kind has its own exit code, clear of the 0, 1
+/// and 2 of test results, though `selfe simulate` exits with whatever code
+/// the simulation reports.
+#[derive(Debug)]
+pub enum CliError {
+    /// Invalid arguments, or a sel4.toml that is missing or invalid
+    Config(String),
+    /// Fetching sources, or building the root task or kernel
+    Build(String),
+    /// Starting or carrying out a simulation or run
+    Run(String),
+    /// Anything else, like removing old builds
+    Other(String),
+}
+
+impl CliError {
+    pub const CONFIG_EXIT_CODE: i32 = 3;
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Config(_) => CliError::CONFIG_EXIT_CODE,
+            CliError::Build(_) => 4,
+            CliError::Run(_) => 5,
+            CliError::Other(_) => 1,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            CliError::Config(_) => "config",
+            CliError::Build(_) => "build",
+            CliError::Run(_) => "run",
+            CliError::Other(_) => "other",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            CliError::Config(m) | CliError::Build(m) | CliError::Run(m) | CliError::Other(m) => m,
+        }
+    }
+
+    /// Report on stderr, or as a JSON object on stdout for `--message-format json`
+    fn report(&self, message_format: MessageFormat) {
+        match message_format {
+            MessageFormat::Human => eprintln!("error: {}", self),
+            MessageFormat::Json => println!(
+                "{}",
+                serde_json::json!({
+                    "reason": "error",
+                    "kind": self.kind(),
+                    "message": self.message(),
+                    "exit_code": self.exit_code(),
+                })
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        f.write_str(self.message())
+    }
 }
 
 pub struct SimulateParams {
     build: BuildParams,
     serial_override: Option<String>,
     extra_qemu_args: Option<Vec<String>>,
+    /// Start halted, waiting for gdb, with a .gdbinit written for it
+    gdb: bool,
+    /// Run the platform's gdb as well, for as long as the simulation
+    launch_gdb: bool,
+}
+
+/// For `selfe test`. Patterns and timeout given here replace those of sel4.toml's [test]
+pub struct TestParams {
+    simulate: SimulateParams,
+    success: Option<Vec<String>>,
+    failure: Option<Vec<String>>,
+    timeout_secs: Option<u64>,
+}
+
+/// For building every configured target, see `multi`
+pub struct BuildAllParams {
+    /// Overrides the platform defaults when sel4.toml lists no targets, and otherwise selects by it
+    sel4_arch: Option<SeL4Arch>,
+    arch: Option<Arch>,
+    /// When present, only targets of this profile are built
+    is_debug: Option<bool>,
+    is_verbose: bool,
+    message_format: MessageFormat,
+    /// How many kernel builds run at once
+    jobs: usize,
+}
+
+/// For `selfe platforms` and `selfe doctor`
+pub struct PlatformsParams {
+    message_format: MessageFormat,
+}
+
+pub struct CleanParams {
+    build_dir: Option<PathBuf>,
+    keep: Option<usize>,
+    stale: bool,
+    dry_run: bool,
 }
 
 enum Execution {
     Build(BuildParams),
+    BuildAll(BuildAllParams),
     Simulate(SimulateParams),
+    Run(SimulateParams),
+    Test(TestParams),
+    Watch(SimulateParams),
+    Platforms(PlatformsParams),
+    Doctor(PlatformsParams),
+    Clean(CleanParams),
+}
+
+/// A clap validator for arguments parsed as a `T`, like a `SeL4Arch`
+fn parses_as<T: FromStr>(what: &'static str) -> impl Fn(String) -> Result<(), String> {
+    move |v| {
+        v.parse::<T>()
+            .map(|_| ())
+            .map_err(|_| format!("{} is not a known {} value", v, what))
+    }
+}
+
+fn is_positive_integer(v: String) -> Result<(), String> {
+    match v.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("{} is not a positive integer", v)),
+    }
+}
+
+/// The value of an argument, which its validator has checked parses
+fn parsed<T: FromStr>(matches: &clap::ArgMatches<'_>, name: &str) -> Option<T> {
+    matches.value_of(name).and_then(|v| v.parse().ok())
 }
 
 trait AppExt {
     fn add_build_params(self) -> Self;
+    fn add_build_all_params(self) -> Self;
 }
 
 impl<'a, 'b> AppExt for App<'a, 'b> {
@@ -57,15 +217,18 @@ impl<'a, 'b> AppExt for App<'a, 'b> {
             Arg::with_name("sel4_arch")
                 .long("sel4_arch")
                 .value_name("SEL4_ARCH")
-                .required(true)
-                .help("seL4 architecture (sel4_arch), like x86_64 or aarch32"),
+                .validator(parses_as::<SeL4Arch>("sel4_arch"))
+                .help(
+                    "seL4 architecture (sel4_arch), like x86_64 or aarch32. \
+                     If not specified, the platform's sel4_arch in sel4.toml is used.",
+                ),
         )
         .arg(
             Arg::with_name("platform")
                 .short("p")
                 .long("platform")
                 .value_name("PLATFORM")
-                .required(true)
+                .required_unless("all")
                 .help("seL4 platform, like pc99 or imx6 or sabre"),
         )
         .arg(
@@ -94,23 +257,72 @@ impl<'a, 'b> AppExt for App<'a, 'b> {
                 .long("arch")
                 .takes_value(true)
                 .value_name("ARCH")
+                .validator(parses_as::<Arch>("arch"))
                 .help(
                     "explicitly specify arch, as sel4 uses the term (arm, x86, or riscv). \
                      If not specified, this is automatically derived from sel4_arch.",
                 ),
         )
+        .arg(
+            Arg::with_name("message-format")
+                .long("message-format")
+                .value_name("FMT")
+                .possible_values(&["human", "json"])
+                .default_value("human")
+                .help("how to report the build results on stdout"),
+        )
+    }
+
+    fn add_build_all_params(self) -> Self {
+        self.arg(
+            Arg::with_name("all")
+                .long("all")
+                .takes_value(false)
+                .conflicts_with("platform")
+                .help(
+                    "build each of the [[targets]] in sel4.toml, or else every [build.*] platform \
+                     in both profiles. --sel4_arch, --debug and --release narrow the selection.",
+                ),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .value_name("N")
+                .requires("all")
+                .validator(is_positive_integer)
+                .help("with --all, the number of kernel builds to run at once (default 2)"),
+        )
     }
 }
 
 impl Execution {
     fn get_or_run_help() -> Self {
         // TODO - naming / piping / phrasing
-        let mut app = App::new("selfe")
+        let app = App::new("selfe")
             .version(crate_version!())
             .about("builds and runs seL4 applications")
-            .subcommand(SubCommand::with_name("build").add_build_params())
+            .subcommand(
+                SubCommand::with_name("build")
+                    .add_build_params()
+                    .add_build_all_params(),
+            )
             .subcommand(SubCommand::with_name("simulate").add_build_params()
                 .setting(AppSettings::AllowLeadingHyphen) // needed for simulate serial overrides
+                .arg(
+                    Arg::with_name("gdb")
+                        .long("gdb")
+                        .takes_value(false)
+                        .help("Start qemu halted, waiting for gdb on port 1234, and write a .gdbinit for it in the build dir \
+                               that loads the kernel and root task symbols"),
+                )
+                .arg(
+                    Arg::with_name("launch-gdb")
+                        .long("launch-gdb")
+                        .takes_value(false)
+                        .help("As with --gdb, then run the platform's gdb (<cross_compiler_prefix>gdb) with that .gdbinit. \
+                               qemu is stopped when gdb exits."),
+                )
                 .arg(
                     Arg::with_name("serial-override")
                         .long("serial-override")
@@ -125,38 +337,187 @@ impl Execution {
                         .last(true)
                         .help("Additional unparsed arguments passed directly to the qemu command "),
                 )
+            )
+            .subcommand(SubCommand::with_name("run").add_build_params()
+                .about("builds, then boots the images with the platform's [run.*] backend from sel4.toml, qemu by default")
+                .setting(AppSettings::AllowLeadingHyphen)
+                .arg(
+                    Arg::with_name("serial-override")
+                        .long("serial-override")
+                        .value_name("SERIAL-OVERRIDE")
+                        .required(false)
+                        .help("For the qemu backend, these contents will be added as qemu arguments in place of the default `--serial` definitions"),
+                )
+                .arg(
+                    Arg::with_name("extra-qemu-args")
+                        .value_name("ARGS")
+                        .multiple(true)
+                        .required(false)
+                        .last(true)
+                        .help("Additional arguments appended to the qemu command, or to the run command"),
+                )
+            )
+            .subcommand(SubCommand::with_name("test").add_build_params()
+                .about("builds, then simulates until a success or failure pattern is printed, or the timeout passes. \
+                        Exits 0 on success, 1 on failure, and 2 on timeout.")
+                .setting(AppSettings::AllowLeadingHyphen)
+                .arg(
+                    Arg::with_name("expect")
+                        .long("expect")
+                        .value_name("PATTERN")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("A line containing this passes the test. Defaults to [test] success in sel4.toml, else \"TEST PASSED\""),
+                )
+                .arg(
+                    Arg::with_name("expect-failure")
+                        .long("expect-failure")
+                        .value_name("PATTERN")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("A line containing this fails the test. Defaults to [test] failure in sel4.toml, else \"*** Panic:\""),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .value_name("SECS")
+                        .validator(is_positive_integer)
+                        .help("Fail the test if no pattern is seen in this many seconds. Defaults to [test] timeout_secs in sel4.toml, else 300"),
+                )
+                .arg(
+                    Arg::with_name("serial-override")
+                        .long("serial-override")
+                        .value_name("SERIAL-OVERRIDE")
+                        .required(false)
+                        .help("If present, these contents will be added as qemu arguments in place of the default `--serial` definitions"),
+                )
+                .arg(
+                    Arg::with_name("extra-qemu-args")
+                        .value_name("ARGS")
+                        .multiple(true)
+                        .required(false)
+                        .last(true)
+                        .help("Additional arguments appended to the qemu command"),
+                )
+            )
+            .subcommand(SubCommand::with_name("watch").add_build_params()
+                .about("builds and simulates, then rebuilds and restarts the simulation whenever sel4.toml, \
+                        the dir holding it or the [watch] paths change. selfe-arc archive inputs outside \
+                        the dir holding sel4.toml are only watched when listed in [watch] paths. \
+                        qemu's stdin is not connected.")
+                .setting(AppSettings::AllowLeadingHyphen)
+                .arg(
+                    Arg::with_name("serial-override")
+                        .long("serial-override")
+                        .value_name("SERIAL-OVERRIDE")
+                        .required(false)
+                        .help("If present, these contents will be added as qemu arguments in place of the default `--serial` definitions"),
+                )
+                .arg(
+                    Arg::with_name("extra-qemu-args")
+                        .value_name("ARGS")
+                        .multiple(true)
+                        .required(false)
+                        .last(true)
+                        .help("Additional arguments appended to the qemu command"),
+                )
+            )
+            .subcommand(SubCommand::with_name("platforms")
+                .about("lists the platforms of sel4.toml, with the sel4_arch and cross compiler of each, \
+                        and reports the tools missing to build and simulate them. Exits 1 if anything is missing.")
+                .arg(
+                    Arg::with_name("message-format")
+                        .long("message-format")
+                        .value_name("FMT")
+                        .possible_values(&["human", "json"])
+                        .default_value("human")
+                        .help("how to report the platforms on stdout"),
+                )
+            )
+            .subcommand(SubCommand::with_name("doctor")
+                .about("checks the host for git, cmake, ninja, python3 and the python modules of the seL4 build, \
+                        libclang, and the cross compilers and qemu binaries of the platforms in sel4.toml, \
+                        saying how to fix what is missing. Exits 1 if any check fails.")
+                .arg(
+                    Arg::with_name("message-format")
+                        .long("message-format")
+                        .value_name("FMT")
+                        .possible_values(&["human", "json"])
+                        .default_value("human")
+                        .help("how to report the checks on stdout"),
+                )
+            )
+            .subcommand(SubCommand::with_name("clean")
+                .about("reports disk usage of cached seL4 builds, and removes old ones")
+                .arg(
+                    Arg::with_name("build-dir")
+                        .long("build-dir")
+                        .value_name("DIR")
+                        .help("The sel4-build dir to clean. Defaults to the sel4-build dir of the shared seL4 cache, target/sel4/build/sel4-build next to sel4.toml unless SEL4_CACHE_DIR is set"),
+                )
+                .arg(
+                    Arg::with_name("keep")
+                        .long("keep")
+                        .value_name("N")
+                        .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|_| format!("{} is not a non-negative integer", v)))
+                        .help("Remove all but the N most recently used builds"),
+                )
+                .arg(
+                    Arg::with_name("stale")
+                        .long("stale")
+                        .takes_value(false)
+                        .help("Remove builds whose inputs no longer correspond to any context of sel4.toml"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .takes_value(false)
+                        .help("Report what would be removed without removing anything"),
+                )
             );
-        let matches = app.clone().get_matches();
+        let matches = match app.clone().get_matches_safe() {
+            Ok(matches) => matches,
+            Err(e) => match e.kind {
+                clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+                _ => {
+                    eprintln!("{}", e.message);
+                    std::process::exit(CliError::CONFIG_EXIT_CODE);
+                }
+            },
+        };
 
         fn parse_build_params(matches: &clap::ArgMatches<'_>) -> BuildParams {
             let is_verbose = matches.is_present("verbose");
+            let message_format = parsed(matches, "message-format").unwrap_or(MessageFormat::Human);
             let is_debug = !matches.is_present("release");
-            let raw_sel4_arch = matches
-                .value_of("sel4_arch")
-                .expect("Missing required arch argument");
-            let sel4_arch = SeL4Arch::from_str(raw_sel4_arch)
-                .expect("sel4_arch argument is not a known sel4_arch value.");
-
-            let platform = Platform(
-                matches
-                    .value_of("platform")
-                    .expect("Missing required platform argument")
-                    .to_owned(),
-            );
-
-            let arch = match matches.value_of("arch") {
-                Some(s) => {
-                    Some(Arch::from_str(s).expect("arch argument is not a known arch value"))
-                }
-                None => None,
-            };
+            // Required, unless --all is given, which is handled by parse_build_all_params
+            let platform = Platform(matches.value_of("platform").unwrap_or_default().to_owned());
 
             BuildParams {
-                sel4_arch,
-                arch,
+                sel4_arch: parsed(matches, "sel4_arch"),
+                arch: parsed(matches, "arch"),
                 platform,
                 is_debug,
                 is_verbose,
+                message_format,
+            }
+        }
+
+        fn parse_build_all_params(matches: &clap::ArgMatches<'_>) -> BuildAllParams {
+            let is_debug = if matches.is_present("debug") {
+                Some(true)
+            } else if matches.is_present("release") {
+                Some(false)
+            } else {
+                None
+            };
+            BuildAllParams {
+                sel4_arch: parsed(matches, "sel4_arch"),
+                arch: parsed(matches, "arch"),
+                is_debug,
+                is_verbose: matches.is_present("verbose"),
+                message_format: parsed(matches, "message-format").unwrap_or(MessageFormat::Human),
+                jobs: parsed(matches, "jobs").unwrap_or(2),
             }
         }
 
@@ -167,141 +528,414 @@ impl Execution {
                 .values_of("extra-qemu-args")
                 .map(|vals| vals.map(ToString::to_string).collect());
 
+            let launch_gdb = matches.is_present("launch-gdb");
             SimulateParams {
                 build,
                 serial_override,
                 extra_qemu_args,
+                gdb: launch_gdb || matches.is_present("gdb"),
+                launch_gdb,
+            }
+        }
+
+        fn parse_test_params(matches: &clap::ArgMatches<'_>) -> TestParams {
+            let patterns = |name| {
+                matches
+                    .values_of(name)
+                    .map(|vals| vals.map(ToString::to_string).collect())
+            };
+            TestParams {
+                simulate: parse_simulate_params(matches),
+                success: patterns("expect"),
+                failure: patterns("expect-failure"),
+                timeout_secs: parsed(matches, "timeout"),
+            }
+        }
+
+        fn parse_clean_params(matches: &clap::ArgMatches<'_>) -> CleanParams {
+            CleanParams {
+                build_dir: matches.value_of("build-dir").map(PathBuf::from),
+                keep: parsed(matches, "keep"),
+                stale: matches.is_present("stale"),
+                dry_run: matches.is_present("dry-run"),
             }
         }
 
         if let Some(matches) = matches.subcommand_matches("build") {
-            Execution::Build(parse_build_params(matches))
+            if matches.is_present("all") {
+                Execution::BuildAll(parse_build_all_params(matches))
+            } else {
+                Execution::Build(parse_build_params(matches))
+            }
         } else if let Some(matches) = matches.subcommand_matches("simulate") {
             Execution::Simulate(parse_simulate_params(matches))
+        } else if let Some(matches) = matches.subcommand_matches("run") {
+            Execution::Run(parse_simulate_params(matches))
+        } else if let Some(matches) = matches.subcommand_matches("test") {
+            Execution::Test(parse_test_params(matches))
+        } else if let Some(matches) = matches.subcommand_matches("watch") {
+            Execution::Watch(parse_simulate_params(matches))
+        } else if let Some(matches) = matches.subcommand_matches("platforms") {
+            Execution::Platforms(PlatformsParams {
+                message_format: parsed(matches, "message-format").unwrap_or(MessageFormat::Human),
+            })
+        } else if let Some(matches) = matches.subcommand_matches("doctor") {
+            Execution::Doctor(PlatformsParams {
+                message_format: parsed(matches, "message-format").unwrap_or(MessageFormat::Human),
+            })
+        } else if let Some(matches) = matches.subcommand_matches("clean") {
+            Execution::Clean(parse_clean_params(matches))
         } else {
-            let _ = app.print_help();
-            panic!()
+            let _ = app.write_help(&mut io::stderr());
+            eprintln!();
+            std::process::exit(CliError::CONFIG_EXIT_CODE);
         }
     }
 }
 
 fn main() {
-    // TODO - Exit code management
     let e = Execution::get_or_run_help();
-    match e {
-        Execution::Build(b) => {
-            let (outcome, _config) = &build_kernel(&b);
-            print_kernel_paths(outcome);
-        }
-        Execution::Simulate(s) => {
-            let (outcome, config) = build_kernel(&s.build);
-            if let SeL4BuildOutcome::Kernel {
-                kernel_path,
-                root_image_path,
-                ..
-            } = outcome
-            {
-                simulate::run_simulate(&s, &kernel_path, &root_image_path, &config)
-                    .expect("Simulation failed");
-            } else {
-                panic!("Should not have built a static lib when a kernel is expected")
+    let message_format = e.message_format();
+    let code = match e.execute() {
+        Ok(code) => code,
+        Err(e) => {
+            e.report(message_format);
+            e.exit_code()
+        }
+    };
+    std::process::exit(code);
+}
+
+impl Execution {
+    fn message_format(&self) -> MessageFormat {
+        match self {
+            Execution::Build(b) => b.message_format,
+            Execution::BuildAll(b) => b.message_format,
+            Execution::Simulate(s) | Execution::Run(s) | Execution::Watch(s) => {
+                s.build.message_format
             }
+            Execution::Test(t) => t.simulate.build.message_format,
+            Execution::Platforms(p) | Execution::Doctor(p) => p.message_format,
+            Execution::Clean(_) => MessageFormat::Human,
+        }
+    }
 
-            panic!("simulate subcommand not yet supported");
+    /// Carry out the command, returning the code to exit with
+    fn execute(self) -> Result<i32, CliError> {
+        match self {
+            Execution::Build(b) => {
+                let (outcome, _config) = &build_kernel(&b)?;
+                match b.message_format {
+                    MessageFormat::Human => print_kernel_paths(outcome),
+                    MessageFormat::Json => println!("{}", kernel_json(outcome)),
+                }
+                Ok(0)
+            }
+            Execution::BuildAll(b) => multi::run_build_all(&b),
+            Execution::Simulate(s) => {
+                let (outcome, config) = build_kernel(&s.build)?;
+                let code =
+                    simulate::run_simulate(&s, &boot(&outcome)?, &config).map_err(CliError::Run)?;
+                if s.build.message_format == MessageFormat::Json {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "reason": "simulation-finished",
+                            "exit_code": code,
+                        })
+                    );
+                }
+                Ok(code)
+            }
+            Execution::Run(r) => {
+                let (outcome, config) = build_kernel(&r.build)?;
+                let boot = boot(&outcome)?;
+                match config.run {
+                    Runner::Qemu => simulate::Qemu { params: &r }.run(&boot, &config),
+                    Runner::Command(ref template) if r.serial_override.is_none() => {
+                        CommandTemplate::new(
+                            template.clone(),
+                            r.extra_qemu_args.clone().unwrap_or_default(),
+                        )
+                        .run(&boot, &config)
+                    }
+                    Runner::Command(_) => {
+                        return Err(CliError::Config(
+                            "--serial-override only applies to the qemu backend".to_string(),
+                        ))
+                    }
+                }
+                .map_err(CliError::Run)?;
+                if r.build.message_format == MessageFormat::Json {
+                    println!("{}", serde_json::json!({ "reason": "run-finished" }));
+                }
+                Ok(0)
+            }
+            Execution::Test(t) => {
+                let (outcome, config) = build_kernel(&t.simulate.build)?;
+                let verdict =
+                    simulate::run_test(&t, &boot(&outcome)?, &config).map_err(CliError::Run)?;
+                let code = match verdict {
+                    _ if verdict.is_success() => 0,
+                    Verdict::TimedOut(_) => 2,
+                    _ => 1,
+                };
+                match t.simulate.build.message_format {
+                    MessageFormat::Human => eprintln!("test {}", verdict),
+                    MessageFormat::Json => println!(
+                        "{}",
+                        serde_json::json!({
+                            "reason": "test-finished",
+                            "success": verdict.is_success(),
+                            "message": verdict.to_string(),
+                            "exit_code": code,
+                        })
+                    ),
+                }
+                Ok(code)
+            }
+            Execution::Watch(w) => watch::run_watch(&w),
+            Execution::Platforms(p) => platforms::run_platforms(&p),
+            Execution::Doctor(d) => platforms::run_doctor(&d),
+            Execution::Clean(c) => clean::run_clean(&c).map(|_| 0),
         }
     }
 }
 
+/// The images of a kernel build
+fn boot(outcome: &SeL4BuildOutcome) -> Result<Boot<'_>, CliError> {
+    Boot::from_outcome(outcome).ok_or_else(|| {
+        CliError::Build("the build produced a library where a kernel was expected".to_string())
+    })
+}
+
+/// Locate sel4.toml in the current directory tree, or via SEL4_CONFIG_PATH
+fn find_config_file() -> Result<PathBuf, CliError> {
+    let pwd = env::current_dir()
+        .map_err(|e| CliError::Config(format!("Can't read the current dir: {}", e)))?;
+    match find_sel4_toml(&pwd) {
+        Some(path) => Ok(path),
+        None => env::var_os("SEL4_CONFIG_PATH")
+            .map(PathBuf::from)
+            .ok_or_else(|| {
+                CliError::Config(
+                    "sel4.toml was not found in the current tree, and SEL4_CONFIG_PATH was not set"
+                        .to_string(),
+                )
+            }),
+    }
+}
+
+/// The dir holding sel4.toml
+fn config_file_dir(config_file_path: &Path) -> &Path {
+    config_file_path.parent().unwrap_or_else(|| Path::new("."))
+}
+
+/// The seL4 cache shared with the selfe-sys build scripts of root tasks built
+/// from `config_file_dir`, which use `target` there unless `CARGO_TARGET_DIR` is set
+fn shared_cache_dir(config_file_dir: &Path) -> PathBuf {
+    let target_dir = match env::var_os("CARGO_TARGET_DIR") {
+        Some(dir) if !dir.is_empty() => config_file_dir.join(dir),
+        _ => config_file_dir.join("target"),
+    };
+    selfe_config::build_cache::shared_cache_dir(&target_dir)
+}
+
 fn build_kernel(
     build_params: &BuildParams,
-) -> (
-    SeL4BuildOutcome,
-    selfe_config::model::contextualized::Contextualized,
-) {
-    let is_debug = build_params.is_debug;
-    let pwd = &env::current_dir().unwrap();
-    let config_file_path = find_sel4_toml(&pwd).unwrap_or_else(|| {
-        let cfg = env::var("SEL4_CONFIG_PATH")
-            .expect("sel4.toml was not found in the current tree, and SEL4_CONFIG was not set");
-        PathBuf::from(&cfg)
-    });
-    let config_file_dir = config_file_path
-        .parent()
-        .expect("Can't get parent of config file path");
-
-    let config_content = fs::read_to_string(&config_file_path)
-        .unwrap_or_else(|_| panic!("Can't read config file: {}", config_file_path.display()));
-
-    let config = selfe_config::model::contextualized::Contextualized::from_str(
-        &config_content,
+) -> Result<
+    (
+        SeL4BuildOutcome,
+        selfe_config::model::contextualized::Contextualized,
+    ),
+    CliError,
+> {
+    let (config_file_path, mut config) = load_config(build_params)?;
+    let sources = resolve_sources(&config, &config_file_path, build_params)?;
+    let outcome = build_images(build_params, &config_file_path, &mut config, &sources)?;
+    Ok((outcome, config))
+}
+
+/// The sel4.toml found by `find_config_file`, contextualized for the build
+fn load_config(
+    build_params: &BuildParams,
+) -> Result<(PathBuf, selfe_config::model::contextualized::Contextualized), CliError> {
+    let (config_file_path, full) = load_full_config()?;
+    let config_file_dir = config_file_dir(&config_file_path);
+
+    let sel4_arch = build_params
+        .sel4_arch
+        .or_else(|| full.default_sel4_arch(&build_params.platform))
+        .ok_or_else(|| {
+            CliError::Config(format!(
+                "No sel4_arch given, and [build.{}] in sel4.toml declares neither sel4_arch nor rust_target",
+                build_params.platform
+            ))
+        })?;
+    let config = selfe_config::model::contextualized::Contextualized::from_full(
+        &full,
         build_params
             .arch
-            .unwrap_or_else(|| Arch::from_sel4_arch(build_params.sel4_arch)),
-        build_params.sel4_arch,
-        is_debug,
+            .unwrap_or_else(|| Arch::from_sel4_arch(sel4_arch)),
+        sel4_arch,
+        build_params.is_debug,
         build_params.platform.clone(),
         Some(config_file_dir),
     )
-    .expect("Can't process config");
-
-    let out_dir = config_file_dir.join("target").join("sel4");
+    .map_err(|e| CliError::Config(format!("Can't process sel4.toml: {}", e)))?;
+    Ok((config_file_path, config))
+}
 
-    let ResolvedSeL4Source {
-        kernel_dir,
-        tools_dir,
-        util_libs_dir,
-    } = resolve_sel4_sources(
+/// Fetch or locate the seL4 sources within the shared cache
+fn resolve_sources(
+    config: &selfe_config::model::contextualized::Contextualized,
+    config_file_path: &Path,
+    build_params: &BuildParams,
+) -> Result<ResolvedSeL4Source, CliError> {
+    let out_dir = shared_cache_dir(config_file_dir(config_file_path));
+    resolve_sel4_sources(
         &config.sel4_sources,
         &out_dir.join("source"),
         build_params.is_verbose,
     )
-    .expect("resolve sel4 source");
+    .map_err(|e| CliError::Build(format!("Can't resolve the seL4 sources: {}", e)))
+}
+
+/// Build the root task, then the kernel and output images. The kernel build
+/// dir is named for its inputs, so when only the root task has changed, just
+/// the images are rebuilt.
+fn build_images(
+    build_params: &BuildParams,
+    config_file_path: &Path,
+    config: &mut selfe_config::model::contextualized::Contextualized,
+    sources: &ResolvedSeL4Source,
+) -> Result<SeL4BuildOutcome, CliError> {
+    let reporter = Reporter(build_params.message_format);
+    build_root_task(config, config_file_path, &reporter).map_err(CliError::Build)?;
+
+    // Build the kernel and output images, showing just the latest line of
+    // output unless verbose. The full output is logged in the build dir.
+    let out_dir = shared_cache_dir(config_file_dir(config_file_path));
+    let mut progress = progress::ProgressLine::new();
+    let outcome = build_sel4_with_output(
+        &out_dir.join("build"),
+        sources,
+        config,
+        SeL4BuildMode::Kernel,
+        &mut |step, line| {
+            if build_params.is_verbose {
+                reporter.report(line);
+            } else {
+                progress.update(step, line);
+            }
+        },
+    );
+    progress.finish();
+    outcome.map_err(|e| CliError::Build(e.to_string()))
+}
+
+/// Read and parse the sel4.toml found by `find_config_file`
+fn load_full_config() -> Result<(PathBuf, Full), CliError> {
+    let config_file_path = find_config_file()?;
+    let config_content = fs::read_to_string(&config_file_path).map_err(|e| {
+        CliError::Config(format!(
+            "Can't read config file {}: {}",
+            config_file_path.display(),
+            e
+        ))
+    })?;
+    let full = config_content.parse().map_err(|e| {
+        CliError::Config(format!(
+            "Can't process {}: {}",
+            config_file_path.display(),
+            e
+        ))
+    })?;
+    Ok((config_file_path, full))
+}
+
+/// Where status lines go, so stdout holds just the build results when they are to be parsed
+#[derive(Clone, Copy)]
+struct Reporter(MessageFormat);
+
+impl Reporter {
+    fn report(&self, line: &str) {
+        match self.0 {
+            MessageFormat::Human => println!("{}", line),
+            MessageFormat::Json => eprintln!("{}", line),
+        }
+    }
+
+    fn stdout(&self) -> Stdio {
+        match self.0 {
+            MessageFormat::Human => Stdio::inherit(),
+            MessageFormat::Json => Stdio::from(io::stderr()),
+        }
+    }
+}
 
-    let root_task = config.build.root_task.as_ref()
-        .unwrap_or_else(|| panic!("root task information, particularly a root_task_image path must be supplied in [build.platform.profile], here [build.{}.{}]",
-        config.context.platform, if config.context.is_debug { "debug"} else { "release"})).clone();
+/// Build the root task: with the configured `make_root_task` command, if any,
+/// or else with cargo for the platform's `rust_target`, in which case the
+/// executable cargo reports becomes the root task image of `config`
+fn build_root_task(
+    config: &mut selfe_config::model::contextualized::Contextualized,
+    config_file_path: &Path,
+    reporter: &Reporter,
+) -> Result<(), String> {
+    let config_file_dir = config_file_dir(config_file_path);
+    let root_task = match config.build.root_task {
+        Some(ref root_task) => root_task,
+        None => {
+            let rust_target = config.build.rust_target.clone().ok_or_else(|| {
+                format!(
+                    "root task information must be supplied, either a rust_target in [build.{platform}], \
+                     or particularly a root_task_image path in [build.platform.profile], here [build.{platform}.{profile}]",
+                    platform = config.context.platform,
+                    profile = if config.context.is_debug { "debug" } else { "release" }
+                )
+            })?;
+            reporter.report(&format!(
+                "Building the root task with cargo for {}",
+                rust_target
+            ));
+            let image_path = root_task::build_with_cargo(config, &rust_target, config_file_path)?;
+            config.build.root_task = Some(RootTask {
+                make_command: None,
+                image_path,
+            });
+            return Ok(());
+        }
+    };
 
-    if let Some(make_root_task_command) = root_task.make_command {
-        // Build the root task
+    if let Some(make_root_task_command) = &root_task.make_command {
         let mut build_cmd = Command::new("sh");
         build_cmd
             .arg("-c")
-            .arg(&make_root_task_command)
-            .current_dir(&config_file_dir)
-            .env("SEL4_CONFIG_PATH", &config_file_path)
-            .env("SEL4_PLATFORM", &config.context.platform.to_string())
-            .env("SEL4_OVERRIDE_ARCH", &config.context.arch.to_string())
-            .env(
-                "SEL4_OVERRIDE_SEL4_ARCH",
-                &config.context.sel4_arch.to_string(),
-            )
-            .stdout(Stdio::inherit())
+            .arg(make_root_task_command)
+            .current_dir(config_file_dir)
+            .envs(root_task::sel4_env(config, config_file_path))
+            .stdout(reporter.stdout())
             .stderr(Stdio::inherit());
 
-        println!(
+        reporter.report(&format!(
             "Running root task build command:\n    SEL4_CONFIG_PATH={} SEL4_PLATFORM={} {}",
             config_file_path.display(),
             &config.context.platform,
-            &make_root_task_command
-        );
-        let output = build_cmd.output().expect("Failed to execute build command");
-        assert!(output.status.success());
+            make_root_task_command
+        ));
+        let status = build_cmd
+            .status()
+            .map_err(|e| format!("Failed to execute root task build command: {}", e))?;
+        if !status.success() {
+            return Err(format!(
+                "Root task build command failed ({}): {}",
+                status, make_root_task_command
+            ));
+        }
     } else {
-        println!("No make_root_task command supplied, skipping an explicit build for it.")
+        reporter.report("No make_root_task command supplied, skipping an explicit build for it.")
     }
-
-    // Build the kernel and output images
-    (
-        build_sel4(
-            &out_dir.join("build"),
-            &kernel_dir,
-            &tools_dir,
-            &util_libs_dir,
-            &config,
-            SeL4BuildMode::Kernel,
-        ),
-        config,
-    )
+    Ok(())
 }
 
 /// Print out the kernel-build-variant paths,
@@ -315,6 +949,7 @@ fn print_kernel_paths(outcome: &SeL4BuildOutcome) {
             build_dir,
             kernel_path,
             root_image_path,
+            ..
         } => {
             println!("{}", build_dir.display());
             println!("{}", kernel_path.display());
@@ -325,21 +960,1046 @@ fn print_kernel_paths(outcome: &SeL4BuildOutcome) {
     }
 }
 
-mod simulate {
-    use crate::SimulateParams;
+/// The kernel-build-variant results as a JSON object,
+/// panic if the wrong variant
+fn kernel_json(outcome: &SeL4BuildOutcome) -> serde_json::Value {
+    match outcome {
+        SeL4BuildOutcome::StaticLib { .. } => {
+            panic!("Should not be making a static lib when a kernel is expected")
+        }
+        SeL4BuildOutcome::Kernel {
+            build_dir,
+            kernel_path,
+            root_image_path,
+            artifacts,
+        } => serde_json::json!({
+            "reason": "kernel-built",
+            "build_dir": build_dir.display().to_string(),
+            "kernel_path": kernel_path.display().to_string(),
+            "root_image_path": root_image_path.as_ref().map(|p| p.display().to_string()),
+            "artifacts": artifacts.iter().map(|a| a.to_json()).collect::<Vec<_>>(),
+        }),
+    }
+}
+
+mod multi {
+    use crate::{
+        build_root_task, config_file_dir, kernel_json, load_full_config, shared_cache_dir,
+        BuildAllParams, CliError, MessageFormat, Reporter,
+    };
+    use selfe_config::compilation::{
+        build_sel4_with_output, resolve_sel4_sources, SeL4BuildMode, SeL4BuildOutcome,
+    };
     use selfe_config::model::contextualized::Contextualized;
-    use selfe_config::model::{SeL4Arch, SingleValue};
+    use selfe_config::model::full::{Full, Target};
+    use selfe_config::model::Arch;
+    use std::sync::Mutex;
+    use std::thread;
+
+    /// Build each selected target: root tasks one at a time, since they typically
+    /// share a cargo target dir, and then the kernels `params.jobs` at a time,
+    /// which is safe as each configuration has its own build dir.
+    /// The exit code is that of a build failure when any target failed.
+    pub fn run_build_all(params: &BuildAllParams) -> Result<i32, CliError> {
+        let reporter = Reporter(params.message_format);
+        let (config_file_path, full) = load_full_config()?;
+        let config_file_dir = config_file_dir(&config_file_path);
+        let targets = select_targets(&full, params).map_err(CliError::Config)?;
+
+        let out_dir = shared_cache_dir(config_file_dir);
+        let sources = resolve_sel4_sources(
+            &full.sel4.sources.relative_to(&Some(config_file_dir)),
+            &out_dir.join("source"),
+            params.is_verbose,
+        )
+        .map_err(|e| CliError::Build(format!("Can't resolve the seL4 sources: {}", e)))?;
+
+        let mut results: Vec<Option<Result<SeL4BuildOutcome, String>>> = Vec::new();
+        let mut pending = Vec::new();
+        for (i, target) in targets.iter().enumerate() {
+            reporter.report(&format!("Building the root task for {}", label(target)));
+            let prepared = Contextualized::from_full(
+                &full,
+                params
+                    .arch
+                    .unwrap_or_else(|| Arch::from_sel4_arch(target.sel4_arch)),
+                target.sel4_arch,
+                target.is_debug,
+                target.platform.clone(),
+                Some(config_file_dir),
+            )
+            .map_err(|e| e.to_string())
+            .and_then(|mut config| {
+                build_root_task(&mut config, &config_file_path, &reporter).map(|_| config)
+            });
+            match prepared {
+                Ok(config) => {
+                    results.push(None);
+                    pending.push((i, config));
+                }
+                Err(e) => {
+                    eprintln!("error: {}: {}", label(target), e);
+                    results.push(Some(Err(e)));
+                }
+            }
+        }
+
+        let queue = Mutex::new(pending.into_iter());
+        let finished = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for _ in 0..params.jobs {
+                scope.spawn(|| loop {
+                    let next = queue.lock().expect("queue lock poisoned").next();
+                    let (i, config) = match next {
+                        Some(n) => n,
+                        None => break,
+                    };
+                    let target = &targets[i];
+                    reporter.report(&format!("Building the kernel for {}", label(target)));
+                    let outcome = build_sel4_with_output(
+                        &out_dir.join("build"),
+                        &sources,
+                        &config,
+                        SeL4BuildMode::Kernel,
+                        &mut |_, line| {
+                            if params.is_verbose {
+                                reporter.report(&format!("[{}] {}", label(target), line));
+                            }
+                        },
+                    )
+                    .map_err(|e| e.to_string());
+                    if let Err(ref e) = outcome {
+                        eprintln!("error: {}: {}", label(target), e);
+                    }
+                    finished
+                        .lock()
+                        .expect("results lock poisoned")
+                        .push((i, outcome));
+                });
+            }
+        });
+        for (i, outcome) in finished.into_inner().expect("results lock poisoned") {
+            results[i] = Some(outcome);
+        }
+
+        let results: Vec<(&Target, Result<SeL4BuildOutcome, String>)> = targets
+            .iter()
+            .zip(
+                results
+                    .into_iter()
+                    .map(|r| r.expect("target was not built")),
+            )
+            .collect();
+        match params.message_format {
+            MessageFormat::Human => print_summary(&results),
+            MessageFormat::Json => {
+                for (target, result) in results.iter() {
+                    let mut message = match result {
+                        Ok(outcome) => kernel_json(outcome),
+                        Err(e) => serde_json::json!({
+                            "reason": "build-failed",
+                            "error": e,
+                        }),
+                    };
+                    let object = message.as_object_mut().expect("messages are objects");
+                    object.insert("platform".into(), target.platform.to_string().into());
+                    object.insert("sel4_arch".into(), target.sel4_arch.to_string().into());
+                    object.insert("profile".into(), profile(target).into());
+                    println!("{}", message);
+                }
+            }
+        }
+        let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+        if failed > 0 {
+            Ok(CliError::Build(format!("{} targets failed", failed)).exit_code())
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// The configured targets, or else every platform with a [build.*] table in both profiles
+    fn select_targets(full: &Full, params: &BuildAllParams) -> Result<Vec<Target>, String> {
+        let targets: Vec<Target> = if full.targets.is_empty() {
+            let mut targets = Vec::new();
+            for (platform, build) in full.build.iter() {
+                let sel4_arch = params
+                    .sel4_arch
+                    .or_else(|| build.default_sel4_arch())
+                    .ok_or_else(|| {
+                        format!(
+                            "sel4.toml lists no [[targets]], and [build.{}] declares neither sel4_arch nor rust_target, so --sel4_arch is needed",
+                            platform
+                        )
+                    })?;
+                for is_debug in [true, false].iter() {
+                    targets.push(Target {
+                        platform: selfe_config::model::Platform(platform.clone()),
+                        sel4_arch,
+                        is_debug: *is_debug,
+                    });
+                }
+            }
+            targets
+        } else {
+            full.targets
+                .iter()
+                .filter(|t| params.sel4_arch.map_or(true, |a| a == t.sel4_arch))
+                .cloned()
+                .collect()
+        };
+        let targets: Vec<Target> = targets
+            .into_iter()
+            .filter(|t| params.is_debug.map_or(true, |d| d == t.is_debug))
+            .collect();
+        if targets.is_empty() {
+            return Err("No targets selected to build".to_string());
+        }
+        Ok(targets)
+    }
+
+    fn profile(target: &Target) -> &'static str {
+        if target.is_debug {
+            "debug"
+        } else {
+            "release"
+        }
+    }
+
+    fn label(target: &Target) -> String {
+        format!(
+            "{} {} {}",
+            target.platform,
+            target.sel4_arch,
+            profile(target)
+        )
+    }
+
+    fn print_summary(results: &[(&Target, Result<SeL4BuildOutcome, String>)]) {
+        let rows: Vec<[String; 4]> = results
+            .iter()
+            .map(|(target, result)| match result {
+                Ok(SeL4BuildOutcome::Kernel {
+                    kernel_path,
+                    root_image_path,
+                    ..
+                }) => [
+                    label(target),
+                    "ok".to_string(),
+                    kernel_path.display().to_string(),
+                    root_image_path
+                        .as_ref()
+                        .map_or("-".to_string(), |p| p.display().to_string()),
+                ],
+                Ok(SeL4BuildOutcome::StaticLib { .. }) => {
+                    panic!("Should not be making a static lib when a kernel is expected")
+                }
+                Err(_) => [
+                    label(target),
+                    "FAILED".to_string(),
+                    "-".to_string(),
+                    "-".to_string(),
+                ],
+            })
+            .collect();
+        let header = [
+            "TARGET".to_string(),
+            "RESULT".to_string(),
+            "KERNEL IMAGE".to_string(),
+            "ROOT IMAGE".to_string(),
+        ];
+        let mut widths = [0; 4];
+        for row in std::iter::once(&header).chain(rows.iter()) {
+            for (w, cell) in widths.iter_mut().zip(row.iter()) {
+                *w = (*w).max(cell.len());
+            }
+        }
+        for row in std::iter::once(&header).chain(rows.iter()) {
+            println!(
+                "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2]
+            );
+        }
+        let failed = rows.iter().filter(|r| r[1] != "ok").count();
+        println!("{} targets built, {} failed", rows.len() - failed, failed);
+    }
+}
+
+mod progress {
+    use selfe_config::compilation::BuildStep;
+    use std::io::{self, IsTerminal, Write};
+
+    const MAX_WIDTH: usize = 100;
+
+    /// A single line of stderr, rewritten with each new line of build output.
+    /// Nothing is shown when stderr is not a terminal.
+    pub struct ProgressLine {
+        enabled: bool,
+        shown: bool,
+    }
+
+    impl ProgressLine {
+        pub fn new() -> Self {
+            ProgressLine {
+                enabled: io::stderr().is_terminal(),
+                shown: false,
+            }
+        }
+
+        pub fn update(&mut self, step: BuildStep, line: &str) {
+            if !self.enabled {
+                return;
+            }
+            let label = match step {
+                BuildStep::Configure => "Configuring",
+                BuildStep::Build => "Building",
+            };
+            let text: String = format!("{}: {}", label, line.trim())
+                .chars()
+                .take(MAX_WIDTH)
+                .collect();
+            let mut stderr = io::stderr();
+            let _ = write!(stderr, "\r\x1b[K{}", text);
+            let _ = stderr.flush();
+            self.shown = true;
+        }
+
+        /// Clear the line, if anything was shown on it
+        pub fn finish(&mut self) {
+            if self.shown {
+                eprint!("\r\x1b[K");
+                self.shown = false;
+            }
+        }
+    }
+}
+
+mod clean {
+    use crate::{config_file_dir, find_config_file, load_full_config, shared_cache_dir};
+    use crate::{CleanParams, CliError};
+    use selfe_config::build_cache::{list_builds, plan_removals, remove_build};
+    use selfe_config::model::full::Full;
+    use std::time::SystemTime;
+
+    pub fn run_clean(params: &CleanParams) -> Result<(), CliError> {
+        // sel4.toml is only needed to find the cache, or to judge which builds are stale
+        let config_file_path = if params.stale || params.build_dir.is_none() {
+            Some(find_config_file()?)
+        } else {
+            None
+        };
+        let config_file_dir = config_file_path.as_deref().map(config_file_dir);
+        let cache_dir = match (&params.build_dir, config_file_dir) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(config_file_dir)) => shared_cache_dir(config_file_dir)
+                .join("build")
+                .join("sel4-build"),
+            (None, None) => unreachable!("sel4.toml is looked up without a build dir"),
+        };
+
+        let config: Option<Full> = if params.stale {
+            Some(load_full_config()?.1)
+        } else {
+            None
+        };
+
+        let builds = list_builds(&cache_dir)
+            .map_err(|e| CliError::Other(format!("Can't read {}: {}", cache_dir.display(), e)))?;
+        let removals = plan_removals(
+            &builds,
+            params.keep,
+            config.as_ref().map(|c| (c, config_file_dir)),
+        );
+
+        let mut kept_bytes = 0;
+        let mut removed_bytes = 0;
+        for (build, removal) in builds.iter().zip(removals.iter()) {
+            let description = match build.info {
+                Some(ref info) => {
+                    let context = |key: &str| {
+                        info.inputs
+                            .context
+                            .get(key)
+                            .map(String::as_str)
+                            .unwrap_or("?")
+                            .to_string()
+                    };
+                    format!(
+                        "{} {} {} {}",
+                        info.inputs.build_mode,
+                        context("platform"),
+                        context("sel4_arch"),
+                        context("profile")
+                    )
+                }
+                None => "(no build info)".to_string(),
+            };
+            let last_used = age(build.last_used());
+            let status = match removal {
+                None => {
+                    kept_bytes += build.size_bytes;
+                    "kept".to_string()
+                }
+                Some(reason) if params.dry_run => {
+                    removed_bytes += build.size_bytes;
+                    format!("would remove ({})", reason)
+                }
+                Some(reason) => match remove_build(&build.dir) {
+                    Ok(true) => {
+                        removed_bytes += build.size_bytes;
+                        format!("removed ({})", reason)
+                    }
+                    Ok(false) => {
+                        kept_bytes += build.size_bytes;
+                        "kept (in use by another build)".to_string()
+                    }
+                    Err(e) => {
+                        return Err(CliError::Other(format!(
+                            "Can't remove {}: {}",
+                            build.dir.display(),
+                            e
+                        )))
+                    }
+                },
+            };
+            println!(
+                "{:>10}  {:>9}  {}  {}  {}",
+                human_size(build.size_bytes),
+                last_used,
+                build.dir.display(),
+                description,
+                status
+            );
+        }
+        println!(
+            "{} builds in {}: {} kept, {} {}",
+            builds.len(),
+            cache_dir.display(),
+            human_size(kept_bytes),
+            human_size(removed_bytes),
+            if params.dry_run {
+                "would be removed"
+            } else {
+                "removed"
+            }
+        );
+        Ok(())
+    }
+
+    fn human_size(bytes: u64) -> String {
+        const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut size = bytes as f64;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            format!("{} {}", bytes, UNITS[0])
+        } else {
+            format!("{:.1} {}", size, UNITS[unit])
+        }
+    }
+
+    fn age(last_used: Option<SystemTime>) -> String {
+        let secs = match last_used.and_then(|t| SystemTime::now().duration_since(t).ok()) {
+            Some(d) => d.as_secs(),
+            None => return "unknown".to_string(),
+        };
+        if secs < 60 * 60 {
+            format!("{}m ago", secs / 60)
+        } else if secs < 60 * 60 * 24 {
+            format!("{}h ago", secs / (60 * 60))
+        } else {
+            format!("{}d ago", secs / (60 * 60 * 24))
+        }
+    }
+}
+
+mod platforms {
+    use crate::{
+        config_file_dir, find_config_file, load_full_config, CliError, MessageFormat,
+        PlatformsParams,
+    };
+    use selfe_config::host::{self, Check, Tool};
+    use selfe_config::model::contextualized::Contextualized;
+    use selfe_config::model::full::Full;
+    use selfe_config::model::{Arch, Platform, SeL4Arch};
     use std::path::{Path, PathBuf};
-    use std::process::{Command, Stdio};
 
+    /// What a platform of sel4.toml resolves to, and what it needs
+    struct Description {
+        platform: Platform,
+        sel4_arch: Option<SeL4Arch>,
+        cross_compiler_prefix: Option<String>,
+        /// The tables declaring it, like "build.sabre"
+        tables: Vec<String>,
+        /// Why it can't be built, like a profile failing to resolve
+        problems: Vec<String>,
+        tools: Vec<Tool>,
+    }
+
+    impl Description {
+        fn is_ready(&self) -> bool {
+            self.problems.is_empty() && self.tools.iter().all(|t| t.path.is_some())
+        }
+    }
+
+    /// Describe each platform, then check the python modules of the seL4
+    /// build. Returns 1 when anything is missing, and 0 otherwise.
+    pub fn run_platforms(params: &PlatformsParams) -> Result<i32, CliError> {
+        let (config_file_path, full) = load_full_config()?;
+        let base_dir = config_file_dir(&config_file_path);
+        let mut all_ready = true;
+        let mut pythons: Vec<PathBuf> = Vec::new();
+        for platform in full.platforms() {
+            let description = describe(&full, platform, base_dir);
+            all_ready &= description.is_ready();
+            for tool in description.tools.iter() {
+                if let (true, Some(path)) = (tool.purpose == "python", &tool.path) {
+                    if !pythons.contains(path) {
+                        pythons.push(path.clone());
+                    }
+                }
+            }
+            report(&description, params.message_format);
+        }
+        for python in pythons.iter() {
+            let missing = host::missing_python_modules(python).map_err(CliError::Other)?;
+            all_ready &= missing.is_empty();
+            report_python_modules(python, &missing, params.message_format);
+        }
+        Ok(if all_ready { 0 } else { 1 })
+    }
+
+    /// Check the host, and the compilers and QEMU binaries of the platforms of
+    /// sel4.toml when there is one. Returns 1 when any check fails, and 0 otherwise.
+    pub fn run_doctor(params: &PlatformsParams) -> Result<i32, CliError> {
+        let mut configs = Vec::new();
+        let mut config_check = None;
+        match find_config_file().and_then(|_| load_full_config()) {
+            Ok((config_file_path, full)) => {
+                let base_dir = config_file_dir(&config_file_path);
+                for platform in full.platforms() {
+                    let sel4_arch = match full.default_sel4_arch(&platform) {
+                        Some(sel4_arch) => sel4_arch,
+                        None => continue,
+                    };
+                    if let Ok(config) = Contextualized::from_full(
+                        &full,
+                        Arch::from_sel4_arch(sel4_arch),
+                        sel4_arch,
+                        true,
+                        platform,
+                        Some(base_dir),
+                    ) {
+                        configs.push(config);
+                    }
+                }
+            }
+            Err(_) if find_config_file().is_err() => {
+                if params.message_format == MessageFormat::Human {
+                    println!("No sel4.toml was found, so no platform's tools are checked");
+                }
+            }
+            Err(e) => {
+                config_check = Some(Check {
+                    name: "sel4.toml".to_string(),
+                    found: None,
+                    problem: Some(e.to_string()),
+                    fix: Some("fix sel4.toml, then check again".to_string()),
+                })
+            }
+        }
+        let checks: Vec<Check> = config_check
+            .into_iter()
+            .chain(host::doctor_checks(&configs))
+            .collect();
+        for check in checks.iter() {
+            report_check(check, params.message_format);
+        }
+        let failed = checks.iter().filter(|c| !c.is_ok()).count();
+        if params.message_format == MessageFormat::Human {
+            match failed {
+                0 => println!("All {} checks passed", checks.len()),
+                n => println!("{} of {} checks failed", n, checks.len()),
+            }
+        }
+        Ok(if failed == 0 { 0 } else { 1 })
+    }
+
+    fn report_check(check: &Check, message_format: MessageFormat) {
+        match message_format {
+            MessageFormat::Human => {
+                match (&check.problem, &check.found) {
+                    (None, found) => println!(
+                        "ok    {}: {}",
+                        check.name,
+                        found.as_deref().unwrap_or("found")
+                    ),
+                    (Some(problem), _) => println!("FAIL  {}: {}", check.name, problem),
+                }
+                if let Some(ref fix) = check.fix {
+                    println!("      fix: {}", fix);
+                }
+            }
+            MessageFormat::Json => println!(
+                "{}",
+                serde_json::json!({
+                    "reason": "check",
+                    "name": check.name,
+                    "ok": check.is_ok(),
+                    "found": check.found,
+                    "problem": check.problem,
+                    "fix": check.fix,
+                })
+            ),
+        }
+    }
+
+    fn describe(full: &Full, platform: Platform, base_dir: &Path) -> Description {
+        let mut tables = Vec::new();
+        if full.build.contains_key(&platform.0) {
+            tables.push(format!("build.{}", platform));
+        }
+        if full.sel4.config.contextual.contains_key(&platform.0) {
+            tables.push(format!("sel4.config.{}", platform));
+        }
+        let sel4_arch = full.default_sel4_arch(&platform);
+        let mut description = Description {
+            platform,
+            sel4_arch,
+            cross_compiler_prefix: None,
+            tables,
+            problems: Vec::new(),
+            tools: Vec::new(),
+        };
+        let sel4_arch = match sel4_arch {
+            Some(sel4_arch) => sel4_arch,
+            None => {
+                description.problems.push(format!(
+                    "no sel4_arch, declare sel4_arch or rust_target in [build.{}]",
+                    description.platform
+                ));
+                return description;
+            }
+        };
+        let mut resolved = Vec::new();
+        for &(is_debug, profile) in [(true, "debug"), (false, "release")].iter() {
+            match Contextualized::from_full(
+                full,
+                Arch::from_sel4_arch(sel4_arch),
+                sel4_arch,
+                is_debug,
+                description.platform.clone(),
+                Some(base_dir),
+            ) {
+                Ok(config) => resolved.push(config),
+                Err(e) => description
+                    .problems
+                    .push(format!("the {} profile can't be resolved: {}", profile, e)),
+            }
+        }
+        if let Some(config) = resolved.first() {
+            description.cross_compiler_prefix = config.build.cross_compiler_prefix.clone();
+            description.tools = host::required_tools(config);
+        }
+        description
+    }
+
+    fn report(description: &Description, message_format: MessageFormat) {
+        match message_format {
+            MessageFormat::Human => {
+                println!(
+                    "{} ({}, {}) from [{}]",
+                    description.platform,
+                    description
+                        .sel4_arch
+                        .map(|a| a.to_string())
+                        .unwrap_or_else(|| "no sel4_arch".to_string()),
+                    description
+                        .cross_compiler_prefix
+                        .as_deref()
+                        .unwrap_or("no cross_compiler_prefix"),
+                    description.tables.join("], [")
+                );
+                for problem in description.problems.iter() {
+                    println!("    error: {}", problem);
+                }
+                for tool in description.tools.iter() {
+                    println!(
+                        "    {:<10}  {:<28}  {}",
+                        tool.purpose,
+                        tool.program,
+                        tool.path
+                            .as_ref()
+                            .map(|p| p.display().to_string())
+                            .unwrap_or_else(|| "MISSING".to_string())
+                    );
+                }
+            }
+            MessageFormat::Json => println!(
+                "{}",
+                serde_json::json!({
+                    "reason": "platform",
+                    "platform": description.platform.to_string(),
+                    "sel4_arch": description.sel4_arch.map(|a| a.to_string()),
+                    "cross_compiler_prefix": description.cross_compiler_prefix,
+                    "tables": description.tables,
+                    "problems": description.problems,
+                    "tools": description.tools.iter().map(|t| serde_json::json!({
+                        "purpose": t.purpose,
+                        "program": t.program,
+                        "path": t.path.as_ref().map(|p| p.display().to_string()),
+                    })).collect::<Vec<_>>(),
+                    "ready": description.is_ready(),
+                })
+            ),
+        }
+    }
+
+    fn report_python_modules(
+        python: &Path,
+        missing: &[(&'static str, &'static str)],
+        message_format: MessageFormat,
+    ) {
+        match message_format {
+            MessageFormat::Human if missing.is_empty() => {
+                println!("{} has the modules the seL4 build needs", python.display())
+            }
+            MessageFormat::Human => println!(
+                "{} is missing the modules {}, install them with: pip3 install --user {}",
+                python.display(),
+                missing
+                    .iter()
+                    .map(|(module, _)| *module)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                missing
+                    .iter()
+                    .map(|(_, package)| *package)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            MessageFormat::Json => println!(
+                "{}",
+                serde_json::json!({
+                    "reason": "python-modules",
+                    "python": python.display().to_string(),
+                    "missing": missing.iter().map(|(module, _)| *module).collect::<Vec<_>>(),
+                })
+            ),
+        }
+    }
+}
+
+mod watch {
+    use crate::{
+        boot, build_images, kernel_json, load_config, resolve_sources, simulate, CliError,
+        MessageFormat, Reporter, SimulateParams,
+    };
+    use selfe_config::watch::Watcher;
+    use std::path::PathBuf;
+    use std::process::Child;
+    use std::thread;
+    use std::time::Duration;
+
+    /// How often the watched files are checked for changes
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    /// Build and simulate, then rebuild and restart the simulation each time
+    /// the inputs change, until interrupted. sel4.toml is only reloaded, and
+    /// the seL4 sources resolved again, when it is among the changes.
+    pub fn run_watch(params: &SimulateParams) -> Result<i32, CliError> {
+        let reporter = Reporter(params.build.message_format);
+        let (mut config_file_path, mut base_config) = load_config(&params.build)?;
+        // Snapshots are taken before building, so changes saved during a build bring another
+        let mut watcher = Watcher::new(&base_config, &config_file_path);
+        let mut sources = resolve_sources(&base_config, &config_file_path, &params.build)?;
+        loop {
+            let mut config = base_config.clone();
+            let mut qemu =
+                match build_images(&params.build, &config_file_path, &mut config, &sources)
+                    .and_then(|outcome| {
+                        if params.build.message_format == MessageFormat::Json {
+                            println!("{}", kernel_json(&outcome));
+                        }
+                        simulate::spawn_simulation(params, &boot(&outcome)?, &config)
+                            .map_err(CliError::Run)
+                    }) {
+                    Ok(child) => Some(child),
+                    Err(e) => {
+                        e.report(params.build.message_format);
+                        reporter.report("Waiting for changes");
+                        None
+                    }
+                };
+
+            let changes = wait_for_changes(&mut watcher, &mut qemu, &config, &reporter);
+            if let Some(ref mut child) = qemu {
+                // It may have exited already, which is fine
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            match params.build.message_format {
+                MessageFormat::Human => {
+                    reporter.report(&format!("{} changed, rebuilding", describe(&changes)))
+                }
+                MessageFormat::Json => println!(
+                    "{}",
+                    serde_json::json!({
+                        "reason": "watch-changed",
+                        "paths": changes.iter().map(|p| p.display().to_string()).collect::<Vec<_>>(),
+                    })
+                ),
+            }
+
+            if changes.contains(&config_file_path) {
+                // A broken sel4.toml is reported, and the last good one kept
+                // until it changes again
+                match load_config(&params.build).and_then(|(path, config)| {
+                    let watcher = Watcher::new(&config, &path);
+                    let sources = resolve_sources(&config, &path, &params.build)?;
+                    Ok((path, config, sources, watcher))
+                }) {
+                    Ok(reloaded) => {
+                        config_file_path = reloaded.0;
+                        base_config = reloaded.1;
+                        sources = reloaded.2;
+                        watcher = reloaded.3;
+                    }
+                    Err(e) => e.report(params.build.message_format),
+                }
+            }
+        }
+    }
+
+    /// Wait for the watched files to change, then for them to settle, so that
+    /// a save touching several files brings a single rebuild. The simulation
+    /// exiting meanwhile is reported.
+    fn wait_for_changes(
+        watcher: &mut Watcher,
+        qemu: &mut Option<Child>,
+        config: &selfe_config::model::contextualized::Contextualized,
+        reporter: &Reporter,
+    ) -> Vec<PathBuf> {
+        let mut changes: Vec<PathBuf> = Vec::new();
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            if let Some(status) = qemu.as_mut().and_then(|c| c.try_wait().ok().flatten()) {
+                *qemu = None;
+                let code = status.code().map(|code| simulate::exit_code(config, code));
+                match reporter.0 {
+                    MessageFormat::Human => reporter.report(&format!(
+                        "The simulation exited ({}), waiting for changes",
+                        code.map(|c| format!("code {}", c))
+                            .unwrap_or_else(|| status.to_string())
+                    )),
+                    MessageFormat::Json => println!(
+                        "{}",
+                        serde_json::json!({
+                            "reason": "simulation-finished",
+                            "exit_code": code,
+                        })
+                    ),
+                }
+            }
+            let latest = watcher.changes();
+            if latest.is_empty() && !changes.is_empty() {
+                changes.sort();
+                changes.dedup();
+                return changes;
+            }
+            changes.extend(latest);
+        }
+    }
+
+    fn describe(changes: &[PathBuf]) -> String {
+        match changes {
+            [path] => path.display().to_string(),
+            [path, rest @ ..] => format!("{} and {} more", path.display(), rest.len()),
+            [] => "Nothing".to_string(),
+        }
+    }
+}
+
+mod simulate {
+    use crate::{MessageFormat, Reporter, SimulateParams, TestParams};
+    use selfe_config::expect::{self, Expectations, Verdict};
+    use selfe_config::gdb;
+    use selfe_config::model::contextualized::Contextualized;
+    use selfe_config::model::SingleValue;
+    use selfe_config::run::{self, Boot, RunBackend, SerialConnection};
+    use std::io::{self, Write};
+    use std::path::Path;
+    use std::process::{Child, Command, Stdio};
+    use std::time::Duration;
+
+    /// The `selfe run` backend for simulation, as with `selfe simulate`
+    pub struct Qemu<'a> {
+        pub params: &'a SimulateParams,
+    }
+
+    impl RunBackend for Qemu<'_> {
+        fn run(&mut self, boot: &Boot, config: &Contextualized) -> Result<(), String> {
+            match run_simulate(self.params, boot, config)? {
+                0 => Ok(()),
+                code => Err(format!("the simulation exited with code {}", code)),
+            }
+        }
+    }
+
+    /// Simulate until qemu exits, returning the code given to selfe-runtime's `exit`
+    /// when the exit device is enabled, and otherwise qemu's own
     pub fn run_simulate(
         simulate_params: &SimulateParams,
-        kernel_path: &Path,
-        root_image_path: &Option<PathBuf>,
+        boot: &Boot,
         config: &Contextualized,
-    ) -> Result<(), String> {
-        let binary = determine_binary(config)?
-            .ok_or_else(|| "Could not determine the appropriate QEMU binary".to_string())?;
+    ) -> Result<i32, String> {
+        let reporter = Reporter(simulate_params.build.message_format);
+        let mut command = qemu_command(simulate_params, boot, config)?;
+        command.stdout(reporter.stdout()).stderr(Stdio::inherit());
+
+        if simulate_params.build.is_verbose {
+            reporter.report(&format!("Running qemu: {:?}", &command));
+        }
+        if simulate_params.gdb {
+            let gdbinit = gdb::write_gdbinit(boot, config)?;
+            let gdb = gdb::gdb_program(config);
+            if simulate_params.launch_gdb {
+                return run_with_gdb(command, &gdb, &gdbinit);
+            }
+            eprintln!(
+                "qemu is waiting for gdb on port {}, attach with: {} -x {}",
+                gdb::GDB_PORT,
+                gdb,
+                gdbinit.display()
+            );
+        }
+        let status = command
+            .status()
+            .map_err(|e| format!("failed to run qemu: {:?}", e))?;
+        match status.code() {
+            Some(code) => Ok(exit_code(config, code)),
+            None => Err(format!("qemu was terminated ({})", status)),
+        }
+    }
+
+    /// Run gdb in the foreground, with qemu's output alongside, stopping qemu once gdb exits
+    fn run_with_gdb(mut qemu: Command, gdb: &str, gdbinit: &Path) -> Result<i32, String> {
+        // The terminal is gdb's, rather than the qemu monitor's
+        qemu.stdin(Stdio::null());
+        let mut child = qemu
+            .spawn()
+            .map_err(|e| format!("failed to run qemu: {:?}", e))?;
+        let gdb_status = Command::new(gdb)
+            .arg("-x")
+            .arg(gdbinit)
+            .status()
+            .map_err(|e| {
+                format!(
+                    "failed to run {}, is it installed and on the PATH? {}",
+                    gdb, e
+                )
+            });
+        let _ = child.kill();
+        let _ = child.wait();
+        match gdb_status? {
+            status if status.success() => Ok(0),
+            status => Err(format!("{} failed ({})", gdb, status)),
+        }
+    }
+
+    /// Start a simulation in the background, for `selfe watch`. Without a
+    /// terminal on stdin, as qemu is killed rather than left to restore it.
+    pub fn spawn_simulation(
+        simulate_params: &SimulateParams,
+        boot: &Boot,
+        config: &Contextualized,
+    ) -> Result<Child, String> {
+        let reporter = Reporter(simulate_params.build.message_format);
+        let mut command = qemu_command(simulate_params, boot, config)?;
+        command
+            .stdin(Stdio::null())
+            .stdout(reporter.stdout())
+            .stderr(Stdio::inherit());
+        if simulate_params.build.is_verbose {
+            reporter.report(&format!("Running qemu: {:?}", &command));
+        }
+        command
+            .spawn()
+            .map_err(|e| format!("failed to run qemu: {:?}", e))
+    }
+
+    pub fn exit_code(config: &Contextualized, qemu_status: i32) -> i32 {
+        if config.simulate.exit_device == Some(true) {
+            run::simulation_exit_code(config.context.sel4_arch, qemu_status)
+        } else {
+            qemu_status
+        }
+    }
+
+    /// Simulate until the output brings a verdict, killing qemu once it does
+    pub fn run_test(
+        test_params: &TestParams,
+        boot: &Boot,
+        config: &Contextualized,
+    ) -> Result<Verdict, String> {
+        let simulate_params = &test_params.simulate;
+        let mut command = qemu_command(simulate_params, boot, config)?;
+        // Without a terminal on stdin, so a test can't be stalled in the qemu monitor
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+
+        let mut expectations = Expectations::from_settings(&config.test);
+        if let Some(ref success) = test_params.success {
+            expectations.success = success.clone();
+        }
+        if let Some(ref failure) = test_params.failure {
+            expectations.failure = failure.clone();
+        }
+        if let Some(timeout_secs) = test_params.timeout_secs {
+            expectations.timeout = Duration::from_secs(timeout_secs);
+        }
+
+        if simulate_params.build.is_verbose {
+            eprintln!("Running qemu: {:?}", &command);
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|e| format!("failed to run qemu: {:?}", e))?;
+        // Only the result goes to stdout for --message-format json
+        let echo: Box<dyn Write> = match simulate_params.build.message_format {
+            MessageFormat::Human => Box::new(io::stdout()),
+            MessageFormat::Json => Box::new(io::stderr()),
+        };
+        match expect::watch_child(&mut child, &expectations, echo)? {
+            Verdict::Exited(Some(code)) => Ok(Verdict::Exited(Some(exit_code(config, code)))),
+            verdict => Ok(verdict),
+        }
+    }
+
+    fn qemu_command(
+        simulate_params: &SimulateParams,
+        boot: &Boot,
+        config: &Contextualized,
+    ) -> Result<Command, String> {
+        let kernel_path = boot.kernel_path;
+        let settings = &config.simulate;
+        let binary = match settings.binary {
+            Some(ref binary) => binary.as_str(),
+            None => run::default_qemu_binary(config.context.sel4_arch).ok_or_else(|| {
+                "Could not determine the appropriate QEMU binary, supply one in [simulate.PLATFORM]"
+                    .to_string()
+            })?,
+        };
         if !kernel_path.exists() {
             return Err(format!(
                 "Supplied kernel_path {} does not exist",
@@ -348,11 +2008,11 @@ mod simulate {
         }
 
         let mut command = Command::new(binary);
-        if let Some(root_image_path) = root_image_path {
+        if let Some(root_image_path) = boot.root_image_path {
             if !root_image_path.exists() {
                 return Err(format!(
                     "Supplied root_image_path {} does not exist",
-                    kernel_path.display()
+                    root_image_path.display()
                 ));
             }
             command
@@ -366,69 +2026,79 @@ mod simulate {
                 .arg(format!("{}", kernel_path.display()));
         }
 
-        let machine = determine_machine(config)?;
-        if let Some(machine_entries) = &machine {
-            for entry in machine_entries {
-                command.arg("-machine").arg(entry);
+        command.args(run::machine_args(settings));
+        if settings.cpu.is_none() {
+            if let Some(cpu) = determine_cpu_with_properties(config) {
+                command.arg("-cpu").arg(cpu);
             }
         }
 
-        if let Some(cpu) = determine_cpu_with_properties(config) {
-            command.arg("-cpu").arg(cpu);
-        }
-
-        match config.context.sel4_arch {
-            SeL4Arch::Aarch64 => {
-                command.arg("-smp").arg("1");
-            }
-            _ => (),
-        }
-
         command.arg("-nographic").arg("-s");
+        if simulate_params.gdb {
+            command.arg("-S");
+        }
 
         if let Some(serial_override) = &simulate_params.serial_override {
             command.args(serial_override.split_whitespace());
         } else {
-            if config.context.platform.0 == "sabre" {
-                command.arg("-serial").arg("null");
+            let reporter = Reporter(simulate_params.build.message_format);
+            for connection in run::serial_connections(config, boot.build_dir) {
+                command.arg("-serial").arg(&connection.qemu_arg);
+                report_serial_connection(&reporter, &connection);
             }
-            command.arg("-serial").arg("mon:stdio");
         }
-        command.arg("-m").arg("size=2048M");
-
-        if let Some(extra_qemu_args) = &simulate_params.extra_qemu_args {
-            command.args(extra_qemu_args.iter());
+        if settings.exit_device == Some(true) {
+            command.args(run::exit_device_args(config.context.sel4_arch));
         }
 
-        command.stdout(Stdio::inherit()).stderr(Stdio::inherit());
-
-        if simulate_params.build.is_verbose {
-            println!("Running qemu: {:?}", &command);
-        }
-        let output = command
-            .output()
-            .map_err(|e| format!("failed to run qemu: {:?}", e))?;
-        if output.status.success() {
-            Ok(())
-        } else {
-            Err("Non-success output status for qemu".into())
+        command.args(run::device_args(config, boot.build_dir)?);
+        if settings.virtio_console == Some(true) {
+            let reporter = Reporter(simulate_params.build.message_format);
+            let path = run::virtio_console_path(boot.build_dir);
+            match reporter.0 {
+                MessageFormat::Human => {
+                    reporter.report(&format!("virtio console: socket {}", path.display()))
+                }
+                MessageFormat::Json => println!(
+                    "{}",
+                    serde_json::json!({
+                        "reason": "virtio-console",
+                        "path": path.display().to_string(),
+                    })
+                ),
+            }
         }
-    }
 
-    fn determine_machine(config: &Contextualized) -> Result<Option<Vec<&'static str>>, String> {
-        match config.context.platform.0.as_ref() {
-            "imx6" | "sabre" | "sabrelite" => Ok(Some(vec!["sabrelite"])),
-            "virt" | "tx1" => Ok(Some(vec!["virt,gic_version=2", "virtualization=true"])),
-            _ => Ok(None),
+        if let Some(extra_args) = &settings.extra_args {
+            command.args(extra_args.iter());
         }
+        if let Some(extra_qemu_args) = &simulate_params.extra_qemu_args {
+            command.args(extra_qemu_args.iter());
+        }
+        Ok(command)
     }
 
-    fn determine_binary(config: &Contextualized) -> Result<Option<&'static str>, String> {
-        match config.context.sel4_arch {
-            SeL4Arch::X86_64 => Ok(Some("qemu-system-x86_64")),
-            SeL4Arch::Aarch32 | SeL4Arch::ArmHyp => Ok(Some("qemu-system-arm")),
-            SeL4Arch::Aarch64 => Ok(Some("qemu-system-aarch64")),
-            _ => Ok(None),
+    /// Say where a serial port can be found, when that is not the terminal
+    fn report_serial_connection(reporter: &Reporter, connection: &SerialConnection) {
+        let location = match (&connection.path, connection.kind) {
+            (Some(path), _) => path.display().to_string(),
+            (None, "pty") => "a pty, named by qemu as it starts".to_string(),
+            _ => return,
+        };
+        match reporter.0 {
+            MessageFormat::Human => reporter.report(&format!(
+                "serial{}: {} {}",
+                connection.index, connection.kind, location
+            )),
+            MessageFormat::Json => println!(
+                "{}",
+                serde_json::json!({
+                    "reason": "serial-port",
+                    "index": connection.index,
+                    "kind": connection.kind,
+                    "path": connection.path.as_ref().map(|p| p.display().to_string()),
+                })
+            ),
         }
     }
 
@@ -440,10 +2110,6 @@ mod simulate {
                     _ => (),
                 }
             }
-            match config.context.platform.0.as_ref() {
-                "tx1" | "virt" => return Some("cortex-a57"),
-                _ => (),
-            }
             None
         }
 
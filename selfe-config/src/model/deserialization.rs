@@ -1,5 +1,8 @@
 use super::full;
-use super::{GitTarget, RepoSource, SeL4Sources, SingleValue};
+use super::{
+    Compiler, Generator, GitTarget, NetworkSettings, Platform, PortForward, RepoSource, Runner,
+    SeL4Arch, SeL4Sources, SerialPort, SimulateSettings, SingleValue, TestSettings, WatchSettings,
+};
 use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::path::PathBuf;
@@ -12,6 +15,11 @@ pub(crate) struct Raw {
     pub(crate) sel4: RawSeL4,
     pub(crate) build: Option<BTreeMap<String, full::PlatformBuild>>,
     pub(crate) metadata: BTreeMap<String, TomlValue>,
+    pub(crate) targets: Vec<full::Target>,
+    pub(crate) run: BTreeMap<String, Runner>,
+    pub(crate) simulate: BTreeMap<String, full::PlatformSimulate>,
+    pub(crate) test: TestSettings,
+    pub(crate) watch: WatchSettings,
 }
 
 /// Internal intermediate representation of the sel4 portion of the toml format
@@ -19,6 +27,8 @@ pub(crate) struct RawSeL4 {
     pub(crate) kernel: TomlTable,
     pub(crate) tools: TomlTable,
     pub(crate) util_libs: TomlTable,
+    pub(crate) sel4_libs: Option<TomlTable>,
+    pub(crate) libraries: Vec<String>,
     pub(crate) build_dir: Option<PathBuf>,
     pub(crate) config: BTreeMap<String, TomlValue>,
 }
@@ -43,6 +53,11 @@ pub enum ImportError {
         extra_keys: Vec<String>,
     },
     InvalidSeL4Source,
+    UnsupportedValue {
+        name: String,
+        value: String,
+        expected: &'static str,
+    },
     NoBuildSupplied {
         platform: String,
         profile: &'static str,
@@ -58,6 +73,7 @@ impl Display for ImportError {
             ImportError::NonSingleValue { found } => f.write_fmt(format_args!("Config toml contained a type problem where a singular value was expected but, {} was found", found)),
             ImportError::UnsupportedProperties { extra_keys } => f.write_fmt(format_args!("Config toml contained superfluous unsupported properties: {:?}.", extra_keys )),
             ImportError::InvalidSeL4Source => f.write_fmt(format_args!("Config toml's [sel4] table must contain either a single `version` property or all of the `kernel_dir`, `tools_dir`, and `util_libs_dir` properties.")),
+            ImportError::UnsupportedValue { name, value, expected } => f.write_fmt(format_args!("Config toml contained an unsupported value {:?} for {}, expected one of {}", value, name, expected)),
             ImportError::NoBuildSupplied { platform, profile } => f.write_fmt(format_args!("Config toml must contain a [build.platform.profile] table like [build.{}.{}] but none was supplied.", platform, profile)),
         }
     }
@@ -84,6 +100,11 @@ impl FromStr for Raw {
             let kernel = parse_required_table(table, "kernel")?;
             let tools = parse_required_table(table, "tools")?;
             let util_libs = parse_required_table(table, "util_libs")?;
+            let sel4_libs = match table.get("sel4_libs") {
+                Some(_) => Some(parse_required_table(table, "sel4_libs")?),
+                None => None,
+            };
+            let libraries = parse_optional_string_array(table, "libraries")?.unwrap_or_default();
             let build_dir = parse_optional_string(table, "build_dir")?.map(PathBuf::from);
 
             let mut config = BTreeMap::new();
@@ -104,6 +125,8 @@ impl FromStr for Raw {
                 kernel,
                 tools,
                 util_libs,
+                sel4_libs,
+                libraries,
                 build_dir,
                 config,
             })
@@ -144,8 +167,37 @@ impl FromStr for Raw {
             Ok(map)
         }
         fn parse_platform_build(table: &TomlTable) -> Result<full::PlatformBuild, ImportError> {
+            let sel4_arch = match parse_optional_string(table, "sel4_arch")? {
+                Some(a) => Some(parse_sel4_arch(&a)?),
+                None => None,
+            };
+            let rust_target = parse_optional_string(table, "rust_target")?;
             let cross_compiler_prefix = parse_optional_string(table, "cross_compiler_prefix")?;
             let toolchain_dir = parse_optional_string(table, "toolchain_dir")?.map(PathBuf::from);
+            let compiler = match parse_optional_string(table, "compiler")? {
+                Some(c) => {
+                    Some(
+                        Compiler::from_str(&c).map_err(|_| ImportError::UnsupportedValue {
+                            name: "compiler".to_string(),
+                            value: c.clone(),
+                            expected: "\"gcc\", \"clang\"",
+                        })?,
+                    )
+                }
+                None => None,
+            };
+            let triple = parse_optional_string(table, "triple")?;
+            let generator =
+                match parse_optional_string(table, "generator")? {
+                    Some(g) => Some(Generator::from_str(&g).map_err(|_| {
+                        ImportError::UnsupportedValue {
+                            name: "generator".to_string(),
+                            value: g.clone(),
+                            expected: "\"Ninja\", \"Unix Makefiles\"",
+                        }
+                    })?),
+                    None => None,
+                };
 
             fn parse_build_profile(
                 parent_table: &TomlTable,
@@ -161,11 +213,11 @@ impl FromStr for Raw {
                             )?),
                         }))
                     } else {
-                        return Err(ImportError::TypeMismatch {
+                        Err(ImportError::TypeMismatch {
                             name: profile_name.to_string(),
                             expected: "table",
                             found: v.type_str(),
-                        });
+                        })
                     }
                 } else {
                     Ok(None)
@@ -175,8 +227,13 @@ impl FromStr for Raw {
             let release_build_profile = parse_build_profile(table, "release")?;
 
             Ok(full::PlatformBuild {
+                sel4_arch,
+                rust_target,
                 cross_compiler_prefix,
                 toolchain_dir,
+                compiler,
+                triple,
+                generator,
                 debug_build_profile,
                 release_build_profile,
             })
@@ -219,10 +276,246 @@ impl FromStr for Raw {
             }
         }
 
+        fn parse_targets(
+            array: &[TomlValue],
+            build: &Option<BTreeMap<String, full::PlatformBuild>>,
+        ) -> Result<Vec<full::Target>, ImportError> {
+            let mut targets = Vec::new();
+            for v in array.iter() {
+                let table = v.as_table().ok_or_else(|| ImportError::TypeMismatch {
+                    name: "targets".to_string(),
+                    expected: "array of tables",
+                    found: v.type_str(),
+                })?;
+                let platform = Platform(parse_required_string(table, "platform")?);
+                // Without a sel4_arch, the target uses its platform's default
+                let sel4_arch = match parse_optional_string(table, "sel4_arch")? {
+                    Some(a) => parse_sel4_arch(&a)?,
+                    None => build
+                        .as_ref()
+                        .and_then(|b| b.get(&platform.0))
+                        .and_then(full::PlatformBuild::default_sel4_arch)
+                        .ok_or_else(|| ImportError::MissingProperty {
+                            name: format!(
+                                "sel4_arch of the {} target, or of [build.{}]",
+                                platform, platform
+                            ),
+                            expected_type: "string",
+                        })?,
+                };
+                // Without a profile, the target is built in both
+                let profiles: &[bool] = match parse_optional_string(table, "profile")?.as_deref() {
+                    None => &[true, false],
+                    Some("debug") => &[true],
+                    Some("release") => &[false],
+                    Some(other) => {
+                        return Err(ImportError::UnsupportedValue {
+                            name: "profile".to_string(),
+                            value: other.to_string(),
+                            expected: "\"debug\", \"release\"",
+                        })
+                    }
+                };
+                for is_debug in profiles.iter() {
+                    targets.push(full::Target {
+                        platform: platform.clone(),
+                        sel4_arch,
+                        is_debug: *is_debug,
+                    });
+                }
+            }
+            Ok(targets)
+        }
+
+        let targets = match top.get("targets") {
+            Some(targets_val) => parse_targets(
+                targets_val
+                    .as_array()
+                    .ok_or_else(|| ImportError::TypeMismatch {
+                        name: "targets".to_string(),
+                        expected: "array of tables",
+                        found: targets_val.type_str(),
+                    })?,
+                &build,
+            )?,
+            None => Vec::new(),
+        };
+
+        fn parse_runner(table: &TomlTable) -> Result<Runner, ImportError> {
+            match parse_optional_string(table, "backend")?.as_deref() {
+                None | Some("qemu") => Ok(Runner::Qemu),
+                Some("command") => Ok(Runner::Command(parse_required_string(table, "command")?)),
+                Some(other) => Err(ImportError::UnsupportedValue {
+                    name: "backend".to_string(),
+                    value: other.to_string(),
+                    expected: "\"qemu\", \"command\"",
+                }),
+            }
+        }
+
+        let mut run = BTreeMap::new();
+        if let Some(run_val) = top.get("run") {
+            let run_table = run_val
+                .as_table()
+                .ok_or_else(|| ImportError::TypeMismatch {
+                    name: "run".to_string(),
+                    expected: "table",
+                    found: run_val.type_str(),
+                })?;
+            for (k, v) in run_table.iter() {
+                let plat_table = v.as_table().ok_or_else(|| ImportError::TypeMismatch {
+                    name: k.to_string(),
+                    expected: "table",
+                    found: v.type_str(),
+                })?;
+                run.insert(k.to_string(), parse_runner(plat_table)?);
+            }
+        }
+
+        fn parse_simulate_settings(table: &TomlTable) -> Result<SimulateSettings, ImportError> {
+            // A single machine may be given as a plain string
+            let machine = match table.get("machine") {
+                Some(TomlValue::String(m)) => Some(vec![m.clone()]),
+                _ => parse_optional_string_array(table, "machine")?,
+            };
+            let smp = match table.get("smp") {
+                Some(v) => Some(
+                    v.as_integer()
+                        .filter(|n| *n > 0 && *n <= i64::from(u32::MAX))
+                        .ok_or_else(|| ImportError::TypeMismatch {
+                            name: "smp".to_string(),
+                            expected: "positive integer",
+                            found: v.type_str(),
+                        })? as u32,
+                ),
+                None => None,
+            };
+            Ok(SimulateSettings {
+                binary: parse_optional_string(table, "binary")?,
+                machine,
+                cpu: parse_optional_string(table, "cpu")?,
+                memory: parse_optional_string(table, "memory")?,
+                smp,
+                serial: match table.get("serial") {
+                    Some(TomlValue::Array(ports)) => Some(
+                        ports
+                            .iter()
+                            .map(parse_serial_port)
+                            .collect::<Result<Vec<_>, _>>()?,
+                    ),
+                    Some(other) => {
+                        return Err(ImportError::TypeMismatch {
+                            name: "serial".to_string(),
+                            expected: "array",
+                            found: other.type_str(),
+                        })
+                    }
+                    None => None,
+                },
+                extra_args: parse_optional_string_array(table, "extra_args")?,
+                exit_device: parse_optional_bool(table, "exit_device")?,
+                network: match table.get("network") {
+                    Some(TomlValue::Table(t)) => Some(parse_network_settings(t)?),
+                    Some(other) => {
+                        return Err(ImportError::TypeMismatch {
+                            name: "network".to_string(),
+                            expected: "table",
+                            found: other.type_str(),
+                        })
+                    }
+                    None => None,
+                },
+                disk: parse_optional_string(table, "disk")?.map(PathBuf::from),
+                virtio_console: parse_optional_bool(table, "virtio_console")?,
+            })
+        }
+
+        let mut simulate = BTreeMap::new();
+        if let Some(simulate_val) = top.get("simulate") {
+            let simulate_table =
+                simulate_val
+                    .as_table()
+                    .ok_or_else(|| ImportError::TypeMismatch {
+                        name: "simulate".to_string(),
+                        expected: "table",
+                        found: simulate_val.type_str(),
+                    })?;
+            for (k, v) in simulate_table.iter() {
+                let plat_table = v.as_table().ok_or_else(|| ImportError::TypeMismatch {
+                    name: k.to_string(),
+                    expected: "table",
+                    found: v.type_str(),
+                })?;
+                let profile = |name: &str| match plat_table.get(name) {
+                    Some(TomlValue::Table(t)) => parse_simulate_settings(t),
+                    Some(other) => Err(ImportError::TypeMismatch {
+                        name: name.to_string(),
+                        expected: "table",
+                        found: other.type_str(),
+                    }),
+                    None => Ok(SimulateSettings::default()),
+                };
+                simulate.insert(
+                    k.to_string(),
+                    full::PlatformSimulate {
+                        shared: parse_simulate_settings(plat_table)?,
+                        debug: profile("debug")?,
+                        release: profile("release")?,
+                    },
+                );
+            }
+        }
+
+        let test = match top.get("test") {
+            Some(TomlValue::Table(t)) => TestSettings {
+                success: parse_optional_string_array(t, "success")?,
+                failure: parse_optional_string_array(t, "failure")?,
+                timeout_secs: match t.get("timeout_secs") {
+                    Some(v) => Some(v.as_integer().filter(|n| *n > 0).ok_or_else(|| {
+                        ImportError::TypeMismatch {
+                            name: "timeout_secs".to_string(),
+                            expected: "positive integer",
+                            found: v.type_str(),
+                        }
+                    })? as u64),
+                    None => None,
+                },
+            },
+            Some(other) => {
+                return Err(ImportError::TypeMismatch {
+                    name: "test".to_string(),
+                    expected: "table",
+                    found: other.type_str(),
+                })
+            }
+            None => TestSettings::default(),
+        };
+
+        let watch = match top.get("watch") {
+            Some(TomlValue::Table(t)) => WatchSettings {
+                paths: parse_optional_string_array(t, "paths")?
+                    .map(|paths| paths.into_iter().map(PathBuf::from).collect()),
+                ignore: parse_optional_string_array(t, "ignore")?,
+            },
+            Some(other) => {
+                return Err(ImportError::TypeMismatch {
+                    name: "watch".to_string(),
+                    expected: "table",
+                    found: other.type_str(),
+                })
+            }
+            None => WatchSettings::default(),
+        };
+
         Ok(Raw {
             sel4,
             build,
             metadata,
+            targets,
+            run,
+            simulate,
+            test,
+            watch,
         })
     }
 }
@@ -262,21 +555,36 @@ impl FromStr for full::Full {
             sel4,
             build,
             metadata,
+            targets,
+            run,
+            simulate,
+            test,
+            watch,
         } = s.parse()?;
         let sources = SeL4Sources {
             kernel: parse_repo_source(&sel4.kernel)?,
             tools: parse_repo_source(&sel4.tools)?,
             util_libs: parse_repo_source(&sel4.util_libs)?,
+            sel4_libs: match sel4.sel4_libs {
+                Some(ref t) => Some(parse_repo_source(t)?),
+                None => None,
+            },
         };
 
         Ok(full::Full {
             sel4: full::SeL4 {
                 sources,
-		build_dir: sel4.build_dir,
+                build_dir: sel4.build_dir,
                 config: structure_property_tree(sel4.config)?,
+                libraries: sel4.libraries,
             },
             build: build.unwrap_or_default(),
             metadata: structure_property_tree(metadata)?,
+            targets,
+            run,
+            simulate,
+            test,
+            watch,
         })
     }
 }
@@ -295,6 +603,79 @@ fn parse_optional_string(table: &TomlTable, key: &str) -> Result<Option<String>,
     }
 }
 
+fn parse_optional_bool(table: &TomlTable, key: &str) -> Result<Option<bool>, ImportError> {
+    match table.get(key) {
+        Some(v) => Ok(Some(v.as_bool().ok_or_else(|| {
+            ImportError::TypeMismatch {
+                name: key.to_string(),
+                expected: "boolean",
+                found: v.type_str(),
+            }
+        })?)),
+        None => Ok(None),
+    }
+}
+
+fn parse_network_settings(table: &TomlTable) -> Result<NetworkSettings, ImportError> {
+    let forwards = parse_optional_string_array(table, "forwards")?
+        .unwrap_or_default()
+        .iter()
+        .map(|f| {
+            PortForward::from_str(f).map_err(|_| ImportError::UnsupportedValue {
+                name: "forwards".to_string(),
+                value: f.clone(),
+                expected: "a port forward like \"tcp:8080:80\"",
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(NetworkSettings { forwards })
+}
+
+/// A serial port is a string, either naming a kind of port or passed to QEMU
+/// as-is, or a table of one kind with a path, like `{ file = "serial1.log" }`.
+/// `{ port = "<string>" }` is the same as the string alone.
+fn parse_serial_port(value: &TomlValue) -> Result<SerialPort, ImportError> {
+    match value {
+        TomlValue::String(s) => Ok(match s.as_str() {
+            "stdio" => SerialPort::Stdio,
+            "file" => SerialPort::File(None),
+            "socket" => SerialPort::Socket(None),
+            "pty" => SerialPort::Pty,
+            "null" => SerialPort::Null,
+            _ => SerialPort::Raw(s.clone()),
+        }),
+        TomlValue::Table(t) if t.len() == 1 => {
+            let path = |key| parse_optional_string(t, key).map(|p| p.map(PathBuf::from));
+            if t.contains_key("file") {
+                Ok(SerialPort::File(path("file")?))
+            } else if t.contains_key("socket") {
+                Ok(SerialPort::Socket(path("socket")?))
+            } else if let Some(port @ TomlValue::String(_)) = t.get("port") {
+                parse_serial_port(port)
+            } else {
+                Err(ImportError::UnsupportedValue {
+                    name: "serial".to_string(),
+                    value: t.keys().cloned().collect::<Vec<_>>().join(", "),
+                    expected: "a table of \"file\", \"socket\" or \"port\"",
+                })
+            }
+        }
+        _ => Err(ImportError::TypeMismatch {
+            name: "serial".to_string(),
+            expected: "string, or a table with a file or socket path",
+            found: value.type_str(),
+        }),
+    }
+}
+
+fn parse_sel4_arch(value: &str) -> Result<SeL4Arch, ImportError> {
+    SeL4Arch::from_str(value).map_err(|_| ImportError::UnsupportedValue {
+        name: "sel4_arch".to_string(),
+        value: value.to_string(),
+        expected: "a known sel4_arch, like \"aarch32\" or \"x86_64\"",
+    })
+}
+
 fn parse_required_string(table: &TomlTable, key: &str) -> Result<String, ImportError> {
     if let Some(val) = table.get(key) {
         Ok(val
@@ -313,6 +694,34 @@ fn parse_required_string(table: &TomlTable, key: &str) -> Result<String, ImportE
     }
 }
 
+fn parse_optional_string_array(
+    table: &TomlTable,
+    key: &str,
+) -> Result<Option<Vec<String>>, ImportError> {
+    if let Some(val) = table.get(key) {
+        let array = val.as_array().ok_or_else(|| ImportError::TypeMismatch {
+            name: key.to_string(),
+            expected: "array",
+            found: val.type_str(),
+        })?;
+        let strings = array
+            .iter()
+            .map(|v| {
+                v.as_str()
+                    .map(ToOwned::to_owned)
+                    .ok_or_else(|| ImportError::TypeMismatch {
+                        name: key.to_string(),
+                        expected: "array of strings",
+                        found: v.type_str(),
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(strings))
+    } else {
+        Ok(None)
+    }
+}
+
 fn parse_repo_source(table: &TomlTable) -> Result<RepoSource, ImportError> {
     let path = parse_optional_string(table, "path")?;
     if let Some(path) = path {
@@ -335,24 +744,27 @@ fn parse_repo_source(table: &TomlTable) -> Result<RepoSource, ImportError> {
         let branch = parse_optional_string(table, "branch")?;
         let tag = parse_optional_string(table, "tag")?;
         let rev = parse_optional_string(table, "rev")?;
-        match (branch, tag, rev) {
-            (Some(b), None, None) => Ok(RepoSource::RemoteGit {
-                url,
-                target: GitTarget::Branch(b),
-            }),
-            (None, Some(t), None) => Ok(RepoSource::RemoteGit {
-                url,
-                target: GitTarget::Tag(t),
-            }),
-            (None, None, Some(r)) => Ok(RepoSource::RemoteGit {
-                url,
-                target: GitTarget::Rev(r),
-            }),
-            _ => Err(ImportError::MissingProperty {
-                name: "branch or tag or rev".to_string(),
-                expected_type: "string",
-            }),
-        }
+        let patches = parse_optional_string_array(table, "patches")?
+            .unwrap_or_default()
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+        let target = match (branch, tag, rev) {
+            (Some(b), None, None) => GitTarget::Branch(b),
+            (None, Some(t), None) => GitTarget::Tag(t),
+            (None, None, Some(r)) => GitTarget::Rev(r),
+            _ => {
+                return Err(ImportError::MissingProperty {
+                    name: "branch or tag or rev".to_string(),
+                    expected_type: "string",
+                })
+            }
+        };
+        Ok(RepoSource::RemoteGit {
+            url,
+            target,
+            patches,
+        })
     }
 }
 
@@ -242,29 +242,67 @@ pub struct SeL4Sources {
     pub kernel: RepoSource,
     pub tools: RepoSource,
     pub util_libs: RepoSource,
+    /// Only needed when building libraries from seL4_libs
+    pub sel4_libs: Option<RepoSource>,
 }
 
 impl SeL4Sources {
-    fn relative_to<P: AsRef<Path>>(&self, base_dir: &Option<P>) -> Self {
+    /// With local paths and patch files made relative to `base_dir`, as sel4.toml's are
+    pub fn relative_to<P: AsRef<Path>>(&self, base_dir: &Option<P>) -> Self {
         SeL4Sources {
             kernel: self.kernel.relative_to(base_dir),
             tools: self.tools.relative_to(base_dir),
             util_libs: self.util_libs.relative_to(base_dir),
+            sel4_libs: self.sel4_libs.as_ref().map(|s| s.relative_to(base_dir)),
+        }
+    }
+
+    /// Each configured source, along with its name
+    pub fn named(&self) -> Vec<(&'static str, &RepoSource)> {
+        let mut named = vec![
+            ("kernel", &self.kernel),
+            ("tools", &self.tools),
+            ("util_libs", &self.util_libs),
+        ];
+        if let Some(ref sel4_libs) = self.sel4_libs {
+            named.push(("sel4_libs", sel4_libs));
         }
+        named
     }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum RepoSource {
     LocalPath(PathBuf),
-    RemoteGit { url: String, target: GitTarget },
+    RemoteGit {
+        url: String,
+        target: GitTarget,
+        /// Patch files applied, in order, on top of the checked out target
+        patches: Vec<PathBuf>,
+    },
 }
 
 impl RepoSource {
     fn relative_to<P: AsRef<Path>>(&self, base_dir: &Option<P>) -> Self {
         match self {
             RepoSource::LocalPath(p) => RepoSource::LocalPath(p.relative_to(base_dir)),
-            s => s.clone(),
+            RepoSource::RemoteGit {
+                url,
+                target,
+                patches,
+            } => RepoSource::RemoteGit {
+                url: url.clone(),
+                target: target.clone(),
+                patches: patches.iter().map(|p| p.relative_to(base_dir)).collect(),
+            },
+        }
+    }
+
+    /// The patch files to be applied to this source, if any
+    pub fn patches(&self) -> &[PathBuf] {
+        match self {
+            RepoSource::LocalPath(_) => &[],
+            RepoSource::RemoteGit { patches, .. } => patches,
         }
     }
 }
@@ -291,6 +329,235 @@ impl GitTarget {
     }
 }
 
+/// The CMake generator used to build seL4
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Generator {
+    Ninja,
+    UnixMakefiles,
+}
+
+impl Generator {
+    /// The native build tool driven by this generator
+    pub fn build_tool(self) -> &'static str {
+        match self {
+            Generator::Ninja => "ninja",
+            Generator::UnixMakefiles => "make",
+        }
+    }
+}
+
+impl FromStr for Generator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Ninja" => Ok(Generator::Ninja),
+            "Unix Makefiles" => Ok(Generator::UnixMakefiles),
+            _ => Err("Unrecognized generator".to_string()),
+        }
+    }
+}
+
+impl Display for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Generator::Ninja => "Ninja",
+            Generator::UnixMakefiles => "Unix Makefiles",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The compiler family used to build seL4
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub enum Compiler {
+    /// Built with seL4's `gcc.cmake` toolchain file and `CROSS_COMPILER_PREFIX`
+    #[default]
+    Gcc,
+    /// Built with seL4's `llvm.cmake` toolchain file and `TRIPLE`
+    Clang,
+}
+
+impl FromStr for Compiler {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gcc" => Ok(Compiler::Gcc),
+            "clang" => Ok(Compiler::Clang),
+            _ => Err("Unrecognized compiler".to_string()),
+        }
+    }
+}
+
+impl Display for Compiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Compiler::Gcc => "gcc",
+            Compiler::Clang => "clang",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// How `selfe run` boots a platform's images
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub enum Runner {
+    /// Simulated, as with `selfe simulate`
+    #[default]
+    Qemu,
+    /// A shell command template, for loading onto hardware, see `run::CommandTemplate`
+    Command(String),
+}
+
+/// QEMU settings for `selfe simulate`. Absent settings are left to QEMU,
+/// except for the binary, which is chosen by sel4_arch
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct SimulateSettings {
+    pub binary: Option<String>,
+    /// Each is passed as a `-machine` argument
+    pub machine: Option<Vec<String>>,
+    pub cpu: Option<String>,
+    /// Passed as `-m`, like "2048M"
+    pub memory: Option<String>,
+    pub smp: Option<u32>,
+    /// The serial ports, in order. When absent, one on stdio
+    pub serial: Option<Vec<SerialPort>>,
+    pub extra_args: Option<Vec<String>>,
+    /// When true, QEMU gets the device through which selfe-runtime's `exit`
+    /// reports a code, see `run::exit_device_args`
+    pub exit_device: Option<bool>,
+    /// User-mode networking, attached to the platform's network device
+    pub network: Option<NetworkSettings>,
+    /// A raw disk image, attached to the platform's block device
+    pub disk: Option<PathBuf>,
+    /// When true, a virtio console on a unix socket in the build dir
+    pub virtio_console: Option<bool>,
+}
+
+/// User-mode networking for a simulation
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct NetworkSettings {
+    /// Ports of the host forwarded to the simulated target
+    pub forwards: Vec<PortForward>,
+}
+
+/// A host port forwarded to a port of the simulated target, written like
+/// "tcp:8080:80", or "8080:80" for tcp
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct PortForward {
+    pub protocol: Protocol,
+    pub host_port: u16,
+    pub guest_port: u16,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl FromStr for PortForward {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (protocol, ports) = match parts.as_slice() {
+            ["tcp", ports @ ..] => (Protocol::Tcp, ports),
+            ["udp", ports @ ..] => (Protocol::Udp, ports),
+            ports => (Protocol::Tcp, ports),
+        };
+        let port = |p: &str| {
+            p.parse::<u16>()
+                .ok()
+                .filter(|p| *p > 0)
+                .ok_or_else(|| format!("Invalid port \"{}\" in port forward \"{}\"", p, s))
+        };
+        match ports {
+            [host, guest] => Ok(PortForward {
+                protocol,
+                host_port: port(host)?,
+                guest_port: port(guest)?,
+            }),
+            _ => Err(format!(
+                "Port forwards are written like \"tcp:8080:80\", found \"{}\"",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for PortForward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let protocol = match self.protocol {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        };
+        write!(f, "{}:{}:{}", protocol, self.host_port, self.guest_port)
+    }
+}
+
+/// Where a serial port of a simulation is connected
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum SerialPort {
+    /// The terminal, shared with the QEMU monitor
+    Stdio,
+    /// A log file, by default `serial<N>.log` in the build dir
+    File(Option<PathBuf>),
+    /// A unix socket QEMU listens on, by default `serial<N>.sock` in the build dir
+    Socket(Option<PathBuf>),
+    /// A pseudo-terminal, which QEMU names as it starts
+    Pty,
+    /// Nothing
+    Null,
+    /// Any other QEMU `-serial` argument, like "tcp::4444,server=on"
+    Raw(String),
+}
+
+impl SimulateSettings {
+    /// These settings, with any set in `overrides` taking precedence
+    pub fn overridden_by(&self, overrides: &SimulateSettings) -> SimulateSettings {
+        fn pick<T: Clone>(base: &Option<T>, over: &Option<T>) -> Option<T> {
+            over.as_ref().or(base.as_ref()).cloned()
+        }
+        SimulateSettings {
+            binary: pick(&self.binary, &overrides.binary),
+            machine: pick(&self.machine, &overrides.machine),
+            cpu: pick(&self.cpu, &overrides.cpu),
+            memory: pick(&self.memory, &overrides.memory),
+            smp: pick(&self.smp, &overrides.smp),
+            serial: pick(&self.serial, &overrides.serial),
+            extra_args: pick(&self.extra_args, &overrides.extra_args),
+            exit_device: pick(&self.exit_device, &overrides.exit_device),
+            network: pick(&self.network, &overrides.network),
+            disk: pick(&self.disk, &overrides.disk),
+            virtio_console: pick(&self.virtio_console, &overrides.virtio_console),
+        }
+    }
+}
+
+/// Expectations for `selfe test`, matched against each line QEMU prints
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct TestSettings {
+    /// Lines containing any of these pass the test. When absent, "TEST PASSED"
+    pub success: Option<Vec<String>>,
+    /// Lines containing any of these fail the test. When absent, the
+    /// "*** Panic:" printed by selfe-runtime's panic handler
+    pub failure: Option<Vec<String>>,
+    /// How long the test may run before it is failed. When absent, 300
+    pub timeout_secs: Option<u64>,
+}
+
+/// What `selfe watch` watches, besides sel4.toml and the dir holding it
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct WatchSettings {
+    /// More files or dirs to watch, like selfe-arc inputs from outside the
+    /// root task crate
+    pub paths: Option<Vec<PathBuf>>,
+    /// Names of files or dirs not to watch, besides "target" and ".git"
+    pub ignore: Option<Vec<String>>,
+}
+
 pub mod full {
     use super::*;
     use std::collections::btree_map::BTreeMap;
@@ -300,19 +567,58 @@ pub mod full {
         pub sel4: SeL4,
         pub build: BTreeMap<String, PlatformBuild>,
         pub metadata: Metadata,
+        /// The combinations built by `selfe build --all`, in order
+        pub targets: Vec<Target>,
+        /// How `selfe run` boots each platform, by platform name
+        pub run: BTreeMap<String, Runner>,
+        /// QEMU settings for each platform, by platform name
+        pub simulate: BTreeMap<String, PlatformSimulate>,
+        /// Expectations for `selfe test`, shared by all platforms
+        pub test: TestSettings,
+        pub watch: WatchSettings,
+    }
+
+    #[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+    pub struct PlatformSimulate {
+        pub shared: SimulateSettings,
+        /// Overrides for debug builds
+        pub debug: SimulateSettings,
+        /// Overrides for release builds
+        pub release: SimulateSettings,
+    }
+
+    /// A platform, sel4_arch and profile combination to build
+    #[derive(Debug, Clone, Eq, PartialEq, Hash)]
+    pub struct Target {
+        pub platform: Platform,
+        pub sel4_arch: SeL4Arch,
+        pub is_debug: bool,
     }
 
     #[derive(Debug, Clone, PartialEq)]
     pub struct SeL4 {
         pub sources: SeL4Sources,
-	pub build_dir: Option<PathBuf>,
+        pub build_dir: Option<PathBuf>,
         pub config: Config,
+        /// Names of the CMake targets of additional util_libs or seL4_libs
+        /// libraries to build alongside libsel4, e.g. "platsupport"
+        pub libraries: Vec<String>,
     }
 
     #[derive(Debug, Clone, Eq, PartialEq, Default, Hash)]
     pub struct PlatformBuild {
+        /// The sel4_arch used for this platform unless another is requested
+        pub sel4_arch: Option<SeL4Arch>,
+        /// The Rust target triple of root tasks for this platform
+        pub rust_target: Option<String>,
         pub cross_compiler_prefix: Option<String>,
         pub toolchain_dir: Option<PathBuf>,
+        /// When absent, gcc is used
+        pub compiler: Option<Compiler>,
+        /// The clang target triple. When absent, derived from `cross_compiler_prefix`
+        pub triple: Option<String>,
+        /// When absent, Ninja is used if available, otherwise Unix Makefiles
+        pub generator: Option<Generator>,
         pub debug_build_profile: Option<PlatformBuildProfile>,
         pub release_build_profile: Option<PlatformBuildProfile>,
     }
@@ -323,9 +629,48 @@ pub mod full {
         pub root_task_image: PathBuf,
     }
 
+    impl PlatformBuild {
+        /// The declared `sel4_arch`, or else the one implied by `rust_target`
+        pub fn default_sel4_arch(&self) -> Option<SeL4Arch> {
+            self.sel4_arch.or_else(|| {
+                let rust_arch = self.rust_target.as_ref()?.split('-').next()?;
+                SeL4Arch::from_rust_arch(RustArch::from_str(rust_arch).ok()?)
+            })
+        }
+    }
+
+    impl Full {
+        /// The default sel4_arch of the named platform, see `PlatformBuild::default_sel4_arch`
+        pub fn default_sel4_arch(&self, platform: &Platform) -> Option<SeL4Arch> {
+            self.build
+                .get(&platform.0)
+                .and_then(PlatformBuild::default_sel4_arch)
+        }
+
+        /// The platforms with a [build.*] table or a [sel4.config.*] table, that
+        /// is, one not named for an arch or sel4_arch
+        pub fn platforms(&self) -> Vec<Platform> {
+            let configured =
+                self.sel4.config.contextual.keys().filter(|name| {
+                    Arch::from_str(name).is_err() && SeL4Arch::from_str(name).is_err()
+                });
+            let names: std::collections::BTreeSet<&String> =
+                self.build.keys().chain(configured).collect();
+            names
+                .into_iter()
+                .map(|name| Platform(name.clone()))
+                .collect()
+        }
+    }
+
     impl SeL4 {
         pub fn new(sources: SeL4Sources, build_dir: Option<PathBuf>, config: Config) -> Self {
-            SeL4 { sources, build_dir, config }
+            SeL4 {
+                sources,
+                build_dir,
+                config,
+                libraries: Vec::new(),
+            }
         }
     }
 
@@ -369,17 +714,26 @@ pub mod contextualized {
     #[derive(Debug, Clone, PartialEq, Hash)]
     pub struct Contextualized {
         pub sel4_sources: SeL4Sources,
-	pub build_dir: Option<PathBuf>,
+        pub build_dir: Option<PathBuf>,
         pub context: Context,
         pub sel4_config: BTreeMap<String, SingleValue>,
+        pub libraries: Vec<String>,
         pub build: Build,
         pub metadata: BTreeMap<String, SingleValue>,
+        pub run: Runner,
+        pub simulate: SimulateSettings,
+        pub test: TestSettings,
+        pub watch: WatchSettings,
     }
 
     #[derive(Debug, Clone, Eq, PartialEq, Default, Hash)]
     pub struct Build {
+        pub rust_target: Option<String>,
         pub cross_compiler_prefix: Option<String>,
         pub toolchain_dir: Option<PathBuf>,
+        pub compiler: Compiler,
+        pub triple: Option<String>,
+        pub generator: Option<Generator>,
         pub root_task: Option<RootTask>,
     }
 
@@ -454,11 +808,22 @@ pub mod contextualized {
                 make_command: bp.make_root_task,
                 image_path: bp.root_task_image.relative_to(&context.base_dir),
             });
+            let prefix = &platform_build.cross_compiler_prefix;
+            let triple = platform_build.triple.clone().or_else(|| {
+                prefix
+                    .as_ref()
+                    .map(|p| p.trim_end_matches('-').to_string())
+                    .filter(|t| !t.is_empty())
+            });
             let build = Build {
+                rust_target: platform_build.rust_target,
                 cross_compiler_prefix: platform_build.cross_compiler_prefix,
                 toolchain_dir: platform_build
                     .toolchain_dir
                     .map(|p| p.relative_to(&context.base_dir)),
+                compiler: platform_build.compiler.unwrap_or_default(),
+                triple,
+                generator: platform_build.generator,
                 root_task,
             };
 
@@ -489,15 +854,66 @@ pub mod contextualized {
             let metadata = resolve_context(&f.metadata, &context);
 
             let sel4_sources = f.sel4.sources.relative_to(&context.base_dir);
-	    let build_dir = f.sel4.build_dir.clone();
+            let build_dir = f.sel4.build_dir.clone();
+
+            let run = f
+                .run
+                .get(&context.platform.to_string())
+                .cloned()
+                .unwrap_or_default();
+            // As with `resolve_context`, platform tables take precedence over
+            // sel4_arch tables, which take precedence over arch tables
+            fn resolve_simulate(f: &full::Full, context: &Context) -> SimulateSettings {
+                [
+                    context.arch.to_string(),
+                    context.sel4_arch.to_string(),
+                    context.platform.to_string(),
+                ]
+                .iter()
+                .filter_map(|key| f.simulate.get(key))
+                .fold(SimulateSettings::default(), |settings, s| {
+                    settings
+                        .overridden_by(&s.shared)
+                        .overridden_by(if context.is_debug {
+                            &s.debug
+                        } else {
+                            &s.release
+                        })
+                })
+            }
+
+            // The default config's settings apply beneath this config's, so
+            // configs without `[simulate.*]` tables keep the settings that
+            // used to be built in
+            let mut simulate = resolve_simulate(&get_default_config(), &context)
+                .overridden_by(&resolve_simulate(f, &context));
+            for port in simulate.serial.iter_mut().flatten() {
+                if let SerialPort::File(Some(ref mut path))
+                | SerialPort::Socket(Some(ref mut path)) = port
+                {
+                    *path = path.relative_to(&context.base_dir);
+                }
+            }
+            if let Some(ref mut disk) = simulate.disk {
+                *disk = disk.relative_to(&context.base_dir);
+            }
+            let mut watch = f.watch.clone();
+            for path in watch.paths.iter_mut().flatten() {
+                *path = path.relative_to(&context.base_dir);
+            }
 
             Ok(Contextualized {
                 sel4_sources,
-		build_dir,
+                build_dir,
                 context,
                 sel4_config,
+                libraries: f.sel4.libraries.clone(),
                 build,
                 metadata,
+                run,
+                simulate,
+                test: f.test.clone(),
+                watch,
             })
         }
     }
@@ -516,12 +932,19 @@ mod tests {
                         kernel: RepoSource::LocalPath(PathBuf::from(".")),
                         tools: RepoSource::LocalPath(PathBuf::from(".")),
                         util_libs: RepoSource::LocalPath(PathBuf::from(".")),
+                        sel4_libs: None,
                     },
-		    build_dir: None,
+                    build_dir: None,
                     config: Default::default(),
+                    libraries: Vec::new(),
                 },
                 build: Default::default(),
                 metadata: Default::default(),
+                targets: Vec::new(),
+                run: Default::default(),
+                simulate: Default::default(),
+                test: Default::default(),
+                watch: Default::default(),
             }
         }
     }
@@ -533,10 +956,35 @@ mod tests {
         assert_eq!(
             RepoSource::RemoteGit {
                 url: "https://github.com/seL4/seL4".to_string(),
-                target: GitTarget::Rev("4d0f02c029560cae0e8d93727eb17d58bcecc2ac".to_string())
+                target: GitTarget::Rev("4d0f02c029560cae0e8d93727eb17d58bcecc2ac".to_string()),
+                patches: vec![],
             },
             f.sel4.sources.kernel
+        );
+        let platforms: Vec<String> = f.platforms().iter().map(|p| p.to_string()).collect();
+        assert_eq!(vec!["pc99", "sabre", "tx1", "virt"], platforms);
+    }
+
+    #[test]
+    fn default_content_has_simulate_settings() {
+        let c = contextualized::Contextualized::from_full(
+            &get_default_config(),
+            Arch::Arm,
+            SeL4Arch::Aarch32,
+            true,
+            Platform("sabre".to_owned()),
+            None,
         )
+        .unwrap();
+        assert_eq!(Some(vec!["sabrelite".to_string()]), c.simulate.machine);
+        assert_eq!(
+            Some(vec![
+                SerialPort::Null,
+                SerialPort::Raw("mon:stdio".to_string())
+            ]),
+            c.simulate.serial
+        );
+        assert_eq!(None, c.simulate.smp);
     }
 
     #[test]
@@ -546,8 +994,13 @@ mod tests {
         f.build.insert(
             expected.to_string(),
             full::PlatformBuild {
+                sel4_arch: None,
+                rust_target: None,
                 cross_compiler_prefix: None,
                 toolchain_dir: None,
+                compiler: None,
+                triple: None,
+                generator: None,
                 debug_build_profile: None,
                 release_build_profile: Some(full::PlatformBuildProfile {
                     make_root_task: Some("cmake".to_string()),
@@ -565,7 +1018,7 @@ mod tests {
         )
         .unwrap();
         assert_eq!(expected, c.context.platform);
-        assert_eq!(false, c.context.is_debug);
+        assert!(!c.context.is_debug);
         assert_eq!(Arch::Arm, c.context.arch);
         assert_eq!(SeL4Arch::Aarch32, c.context.sel4_arch);
         assert_eq!(
@@ -1,5 +1,8 @@
 use super::full;
-use super::{GitTarget, RepoSource, SeL4Sources, SingleValue};
+use super::{
+    GitTarget, RepoSource, Runner, SeL4Sources, SerialPort, SimulateSettings, SingleValue,
+    TestSettings, WatchSettings,
+};
 use std::collections::BTreeMap;
 use toml::ser::{to_string_pretty, Error as TomlSerError};
 use toml::value::{Table as TomlTable, Value as TomlValue};
@@ -7,6 +10,18 @@ use toml::value::{Table as TomlTable, Value as TomlValue};
 impl full::Full {
     fn to_toml(&self) -> TomlTable {
         let mut sel4 = serialize_sel4_sources(&self.sel4.sources);
+        if !self.sel4.libraries.is_empty() {
+            sel4.insert(
+                "libraries".to_string(),
+                TomlValue::Array(
+                    self.sel4
+                        .libraries
+                        .iter()
+                        .map(|l| TomlValue::String(l.clone()))
+                        .collect(),
+                ),
+            );
+        }
         let config = serialize_properties_tree(&self.sel4.config);
         if !config.is_empty() {
             sel4.insert_table("config", config);
@@ -21,6 +36,47 @@ impl full::Full {
         if !metadata.is_empty() {
             top.insert_table("metadata", metadata);
         }
+        if !self.run.is_empty() {
+            let mut run = TomlTable::new();
+            for (k, runner) in self.run.iter() {
+                run.insert_table(k.as_str(), serialize_runner(runner));
+            }
+            top.insert_table("run", run);
+        }
+        if !self.simulate.is_empty() {
+            let mut simulate = TomlTable::new();
+            for (k, plat) in self.simulate.iter() {
+                let mut plat_table = serialize_simulate_settings(&plat.shared);
+                for (name, settings) in [("debug", &plat.debug), ("release", &plat.release)].iter()
+                {
+                    let table = serialize_simulate_settings(settings);
+                    if !table.is_empty() {
+                        plat_table.insert_table(*name, table);
+                    }
+                }
+                simulate.insert_table(k.as_str(), plat_table);
+            }
+            top.insert_table("simulate", simulate);
+        }
+        let test = serialize_test_settings(&self.test);
+        if !test.is_empty() {
+            top.insert_table("test", test);
+        }
+        let watch = serialize_watch_settings(&self.watch);
+        if !watch.is_empty() {
+            top.insert_table("watch", watch);
+        }
+        if !self.targets.is_empty() {
+            top.insert(
+                "targets".to_string(),
+                TomlValue::Array(
+                    self.targets
+                        .iter()
+                        .map(|t| TomlValue::Table(serialize_target(t)))
+                        .collect(),
+                ),
+            );
+        }
         top
     }
 
@@ -35,6 +91,9 @@ fn serialize_sel4_sources(sources: &SeL4Sources) -> TomlTable {
     table.insert_table("kernel", serialize_repo_source(&sources.kernel));
     table.insert_table("tools", serialize_repo_source(&sources.tools));
     table.insert_table("util_libs", serialize_repo_source(&sources.util_libs));
+    if let Some(ref sel4_libs) = sources.sel4_libs {
+        table.insert_table("sel4_libs", serialize_repo_source(sel4_libs));
+    }
     table
 }
 
@@ -44,13 +103,28 @@ fn serialize_repo_source(source: &RepoSource) -> TomlTable {
         RepoSource::LocalPath(p) => {
             table.insert_str("path", format!("{}", p.display()));
         }
-        RepoSource::RemoteGit { url, target } => {
+        RepoSource::RemoteGit {
+            url,
+            target,
+            patches,
+        } => {
             table.insert_str("git", url.as_str());
             match target {
                 GitTarget::Branch(v) => table.insert_str("branch", v.as_str()),
                 GitTarget::Tag(v) => table.insert_str("tag", v.as_str()),
                 GitTarget::Rev(v) => table.insert_str("rev", v.as_str()),
             };
+            if !patches.is_empty() {
+                table.insert(
+                    "patches".to_string(),
+                    TomlValue::Array(
+                        patches
+                            .iter()
+                            .map(|p| TomlValue::String(format!("{}", p.display())))
+                            .collect(),
+                    ),
+                );
+            }
         }
     }
 
@@ -85,12 +159,27 @@ fn serialize_build(source: &BTreeMap<String, full::PlatformBuild>) -> Option<Tom
     let mut build = TomlTable::new();
     for (k, plat) in source.iter() {
         let mut plat_table = TomlTable::new();
+        if let Some(v) = plat.sel4_arch {
+            plat_table.insert_str("sel4_arch", v.to_string());
+        }
+        if let Some(ref v) = plat.rust_target {
+            plat_table.insert_str("rust_target", v.as_str());
+        }
         if let Some(ref v) = plat.cross_compiler_prefix {
             plat_table.insert_str("cross_compiler_prefix", v.as_str());
         }
         if let Some(ref v) = plat.toolchain_dir {
             plat_table.insert_str("toolchain_dir", format!("{}", v.display()));
         }
+        if let Some(v) = plat.compiler {
+            plat_table.insert_str("compiler", v.to_string());
+        }
+        if let Some(ref v) = plat.triple {
+            plat_table.insert_str("triple", v.as_str());
+        }
+        if let Some(v) = plat.generator {
+            plat_table.insert_str("generator", v.to_string());
+        }
 
         if let Some(t) = serialize_profile_build(&plat.debug_build_profile) {
             plat_table.insert_table("debug", t);
@@ -103,6 +192,133 @@ fn serialize_build(source: &BTreeMap<String, full::PlatformBuild>) -> Option<Tom
     Some(build)
 }
 
+fn string_array(values: &[String]) -> TomlValue {
+    TomlValue::Array(
+        values
+            .iter()
+            .map(|v| TomlValue::String(v.clone()))
+            .collect(),
+    )
+}
+
+fn serialize_simulate_settings(settings: &SimulateSettings) -> TomlTable {
+    let mut table = TomlTable::new();
+    if let Some(ref v) = settings.binary {
+        table.insert_str("binary", v.as_str());
+    }
+    if let Some(ref v) = settings.machine {
+        table.insert("machine".to_string(), string_array(v));
+    }
+    if let Some(ref v) = settings.cpu {
+        table.insert_str("cpu", v.as_str());
+    }
+    if let Some(ref v) = settings.memory {
+        table.insert_str("memory", v.as_str());
+    }
+    if let Some(v) = settings.smp {
+        table.insert("smp".to_string(), TomlValue::Integer(i64::from(v)));
+    }
+    if let Some(ref v) = settings.serial {
+        let mut ports: Vec<TomlValue> = v.iter().map(serialize_serial_port).collect();
+        // toml can't write an array mixing strings and tables, so when any
+        // port needs a table, the rest are written as `{ port = "..." }`
+        if ports.iter().any(|p| p.is_table()) {
+            for port in ports.iter_mut().filter(|p| !p.is_table()) {
+                let mut wrapped = TomlTable::new();
+                wrapped.insert("port".to_string(), port.clone());
+                *port = TomlValue::Table(wrapped);
+            }
+        }
+        table.insert("serial".to_string(), TomlValue::Array(ports));
+    }
+    if let Some(ref v) = settings.extra_args {
+        table.insert("extra_args".to_string(), string_array(v));
+    }
+    if let Some(v) = settings.exit_device {
+        table.insert("exit_device".to_string(), TomlValue::Boolean(v));
+    }
+    if let Some(ref v) = settings.network {
+        let forwards: Vec<String> = v.forwards.iter().map(ToString::to_string).collect();
+        let mut network = TomlTable::new();
+        network.insert("forwards".to_string(), string_array(&forwards));
+        table.insert_table("network", network);
+    }
+    if let Some(ref v) = settings.disk {
+        table.insert_str("disk", v.display().to_string());
+    }
+    if let Some(v) = settings.virtio_console {
+        table.insert("virtio_console".to_string(), TomlValue::Boolean(v));
+    }
+    table
+}
+
+fn serialize_serial_port(port: &SerialPort) -> TomlValue {
+    let path_table = |key: &str, path: &Option<std::path::PathBuf>| match path {
+        Some(p) => {
+            let mut table = TomlTable::new();
+            table.insert_str(key, p.display().to_string());
+            TomlValue::Table(table)
+        }
+        None => TomlValue::String(key.to_string()),
+    };
+    match port {
+        SerialPort::Stdio => TomlValue::String("stdio".to_string()),
+        SerialPort::File(path) => path_table("file", path),
+        SerialPort::Socket(path) => path_table("socket", path),
+        SerialPort::Pty => TomlValue::String("pty".to_string()),
+        SerialPort::Null => TomlValue::String("null".to_string()),
+        SerialPort::Raw(arg) => TomlValue::String(arg.clone()),
+    }
+}
+
+fn serialize_test_settings(settings: &TestSettings) -> TomlTable {
+    let mut table = TomlTable::new();
+    if let Some(ref v) = settings.success {
+        table.insert("success".to_string(), string_array(v));
+    }
+    if let Some(ref v) = settings.failure {
+        table.insert("failure".to_string(), string_array(v));
+    }
+    if let Some(v) = settings.timeout_secs {
+        table.insert("timeout_secs".to_string(), TomlValue::Integer(v as i64));
+    }
+    table
+}
+
+fn serialize_watch_settings(settings: &WatchSettings) -> TomlTable {
+    let mut table = TomlTable::new();
+    if let Some(ref v) = settings.paths {
+        let paths: Vec<String> = v.iter().map(|p| p.display().to_string()).collect();
+        table.insert("paths".to_string(), string_array(&paths));
+    }
+    if let Some(ref v) = settings.ignore {
+        table.insert("ignore".to_string(), string_array(v));
+    }
+    table
+}
+
+fn serialize_runner(runner: &Runner) -> TomlTable {
+    let mut table = TomlTable::new();
+    match runner {
+        Runner::Qemu => {
+            table.insert_str("backend", "qemu");
+        }
+        Runner::Command(command) => {
+            table.insert_str("backend", "command");
+            table.insert_str("command", command.as_str());
+        }
+    }
+    table
+}
+
+fn serialize_target(target: &full::Target) -> TomlTable {
+    let mut table = TomlTable::new();
+    table.insert_str("platform", target.platform.to_string());
+    table.insert_str("sel4_arch", target.sel4_arch.to_string());
+    table.insert_str("profile", if target.is_debug { "debug" } else { "release" });
+    table
+}
+
 fn serialize_profile_build(source: &Option<full::PlatformBuildProfile>) -> Option<TomlTable> {
     source.as_ref().map(|v| {
         let mut prof_table = TomlTable::new();
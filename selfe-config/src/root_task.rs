@@ -0,0 +1,152 @@
+//! Building a root task with cargo, for platforms that declare a `rust_target`
+
+use crate::model::contextualized::Contextualized;
+use serde_json::Value;
+use std::env;
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The environment that hands the seL4 configuration down to the selfe-sys
+/// build script of a root task, for both cargo and `make_root_task` builds
+pub fn sel4_env(config: &Contextualized, config_file_path: &Path) -> Vec<(&'static str, OsString)> {
+    vec![
+        ("SEL4_CONFIG_PATH", config_file_path.into()),
+        ("SEL4_PLATFORM", config.context.platform.to_string().into()),
+        ("SEL4_OVERRIDE_ARCH", config.context.arch.to_string().into()),
+        (
+            "SEL4_OVERRIDE_SEL4_ARCH",
+            config.context.sel4_arch.to_string().into(),
+        ),
+    ]
+}
+
+/// The cargo invocation building the root task for `rust_target` in the
+/// profile of `config`, from the dir holding sel4.toml
+pub fn cargo_build_command(
+    config: &Contextualized,
+    rust_target: &str,
+    config_file_path: &Path,
+) -> Command {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut command = Command::new(cargo);
+    command
+        .arg("build")
+        .arg("--target")
+        .arg(rust_target)
+        .arg("--message-format=json-render-diagnostics");
+    if !config.context.is_debug {
+        command.arg("--release");
+    }
+    if let Some(dir) = config_file_path.parent() {
+        command.current_dir(dir);
+    }
+    command.envs(sel4_env(config, config_file_path));
+    command
+}
+
+/// Build the root task with cargo, returning the path of the executable built.
+/// Diagnostics are shown on stderr.
+pub fn build_with_cargo(
+    config: &Contextualized,
+    rust_target: &str,
+    config_file_path: &Path,
+) -> Result<PathBuf, String> {
+    let mut command = cargo_build_command(config, rust_target, config_file_path);
+    command.stdout(Stdio::piped()).stderr(Stdio::inherit());
+    let mut child = command
+        .spawn()
+        .map_err(|e| format!("failed to run cargo: {}", e))?;
+    let stdout = child.stdout.take().expect("cargo stdout is piped");
+    let executable = built_executable(BufReader::new(stdout));
+    let status = child
+        .wait()
+        .map_err(|e| format!("failed to run cargo: {}", e))?;
+    if !status.success() {
+        return Err(format!("cargo failed to build the root task ({})", status));
+    }
+    executable
+}
+
+/// The single executable reported in cargo's `--message-format=json` output
+pub fn built_executable<R: BufRead>(messages: R) -> Result<PathBuf, String> {
+    let mut executables: Vec<PathBuf> = Vec::new();
+    for line in messages.lines() {
+        let line = line.map_err(|e| format!("failed to read cargo output: {}", e))?;
+        // Anything else on stdout, like build script output, is not ours to parse
+        let message: Value = match serde_json::from_str(&line) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        if message["reason"] != "compiler-artifact" {
+            continue;
+        }
+        let is_bin = message["target"]["kind"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|k| k == "bin");
+        if let (true, Some(executable)) = (is_bin, message["executable"].as_str()) {
+            let executable = PathBuf::from(executable);
+            if !executables.contains(&executable) {
+                executables.push(executable);
+            }
+        }
+    }
+    match executables.len() {
+        0 => Err("cargo did not report building an executable for the root task".to_string()),
+        1 => Ok(executables.remove(0)),
+        _ => Err(format!(
+            "cargo built several executables, so the root task is ambiguous: {}. \
+             Supply a root_task_image, and make_root_task, for this platform and profile instead.",
+            executables
+                .iter()
+                .map(|e| e.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact(kind: &str, executable: Option<&str>) -> String {
+        serde_json::json!({
+            "reason": "compiler-artifact",
+            "target": { "kind": [kind], "name": "example" },
+            "executable": executable,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn finds_the_built_executable() {
+        let messages = [
+            artifact("lib", None),
+            artifact("custom-build", None),
+            "not json".to_string(),
+            artifact("bin", Some("/app/target/armv7/debug/example")),
+            r#"{"reason":"build-finished","success":true}"#.to_string(),
+        ]
+        .join("\n");
+        assert_eq!(
+            PathBuf::from("/app/target/armv7/debug/example"),
+            built_executable(messages.as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn several_executables_are_ambiguous() {
+        let messages = [
+            artifact("bin", Some("/app/target/debug/one")),
+            artifact("bin", Some("/app/target/debug/two")),
+        ]
+        .join("\n");
+        let e = built_executable(messages.as_bytes()).unwrap_err();
+        assert!(e.contains("one") && e.contains("two"));
+        assert!(built_executable(artifact("lib", None).as_bytes()).is_err());
+    }
+}
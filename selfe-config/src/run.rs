@@ -0,0 +1,611 @@
+//! Booting built images, in simulation or on hardware, via a `RunBackend`
+
+use crate::artifacts::{Artifact, ArtifactKind};
+use crate::compilation::SeL4BuildOutcome;
+use crate::model::contextualized::Contextualized;
+use crate::model::{Protocol, SeL4Arch, SerialPort, SimulateSettings};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The images and other artifacts of a kernel build, to be booted
+pub struct Boot<'a> {
+    pub build_dir: &'a Path,
+    pub kernel_path: &'a Path,
+    pub root_image_path: Option<&'a Path>,
+    pub artifacts: &'a [Artifact],
+}
+
+impl<'a> Boot<'a> {
+    /// The images of a kernel build, or `None` for a library build
+    pub fn from_outcome(outcome: &'a SeL4BuildOutcome) -> Option<Boot<'a>> {
+        match outcome {
+            SeL4BuildOutcome::StaticLib { .. } => None,
+            SeL4BuildOutcome::Kernel {
+                build_dir,
+                kernel_path,
+                root_image_path,
+                artifacts,
+            } => Some(Boot {
+                build_dir,
+                kernel_path,
+                root_image_path: root_image_path.as_deref(),
+                artifacts,
+            }),
+        }
+    }
+}
+
+/// A way of booting a build, such as QEMU or a lab's loader scripts
+pub trait RunBackend {
+    /// Boot the images, returning once the run is over
+    fn run(&mut self, boot: &Boot, config: &Contextualized) -> Result<(), String>;
+}
+
+/// Runs a shell command template, from the dir holding sel4.toml.
+///
+/// The placeholders `{kernel}`, `{root_image}`, `{build_dir}`, `{platform}`,
+/// `{sel4_arch}` and `{profile}` are replaced by shell-quoted values, and
+/// the same values are in the environment as `SELFE_KERNEL`,
+/// `SELFE_ROOT_IMAGE` and so on, along with `SELFE_<KIND>` for the first
+/// artifact of each kind, like `SELFE_DTB` or `SELFE_KERNEL_ELF`.
+pub struct CommandTemplate {
+    pub template: String,
+    /// Appended to the expanded command
+    pub extra_args: Vec<String>,
+}
+
+impl CommandTemplate {
+    pub fn new(template: String, extra_args: Vec<String>) -> Self {
+        CommandTemplate {
+            template,
+            extra_args,
+        }
+    }
+
+    /// The command line to run, with placeholders expanded
+    pub fn expand(&self, boot: &Boot, config: &Contextualized) -> String {
+        let mut command = self.template.clone();
+        for (name, value) in values(boot, config).iter() {
+            command = command.replace(&format!("{{{}}}", name), &shell_quote(value));
+        }
+        for arg in self.extra_args.iter() {
+            command.push(' ');
+            command.push_str(&shell_quote(arg));
+        }
+        command
+    }
+}
+
+impl RunBackend for CommandTemplate {
+    fn run(&mut self, boot: &Boot, config: &Contextualized) -> Result<(), String> {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(self.expand(boot, config));
+        if let Some(ref dir) = config.context.base_dir {
+            command.current_dir(dir);
+        }
+        for (name, value) in values(boot, config).iter() {
+            command.env(format!("SELFE_{}", name.to_uppercase()), value);
+        }
+        for artifact in boot.artifacts.iter().rev() {
+            // In reverse, so the first of each kind wins
+            command.env(
+                format!("SELFE_{}", artifact.kind.as_str().to_uppercase()),
+                OsString::from(&artifact.path),
+            );
+        }
+        let status = command
+            .status()
+            .map_err(|e| format!("failed to run {}: {}", self.template, e))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Run command failed ({}): {}",
+                status, self.template
+            ))
+        }
+    }
+}
+
+/// The values available to command templates, by name
+fn values(boot: &Boot, config: &Contextualized) -> Vec<(&'static str, String)> {
+    vec![
+        ("kernel", boot.kernel_path.display().to_string()),
+        (
+            "root_image",
+            boot.root_image_path
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+        ),
+        ("build_dir", boot.build_dir.display().to_string()),
+        ("platform", config.context.platform.to_string()),
+        ("sel4_arch", config.context.sel4_arch.to_string()),
+        (
+            "profile",
+            if config.context.is_debug {
+                "debug"
+            } else {
+                "release"
+            }
+            .to_string(),
+        ),
+    ]
+}
+
+/// The QEMU binary for simulating `sel4_arch`, when none is configured
+pub fn default_qemu_binary(sel4_arch: SeL4Arch) -> Option<&'static str> {
+    match sel4_arch {
+        SeL4Arch::X86_64 => Some("qemu-system-x86_64"),
+        SeL4Arch::Aarch32 | SeL4Arch::ArmHyp => Some("qemu-system-arm"),
+        SeL4Arch::Aarch64 => Some("qemu-system-aarch64"),
+        _ => None,
+    }
+}
+
+/// The QEMU arguments for the configured machine, cpu, smp and memory
+pub fn machine_args(settings: &SimulateSettings) -> Vec<String> {
+    let mut args = Vec::new();
+    for entry in settings.machine.iter().flatten() {
+        args.push("-machine".to_string());
+        args.push(entry.clone());
+    }
+    if let Some(ref cpu) = settings.cpu {
+        args.push("-cpu".to_string());
+        args.push(cpu.clone());
+    }
+    if let Some(smp) = settings.smp {
+        args.push("-smp".to_string());
+        args.push(smp.to_string());
+    }
+    if let Some(ref memory) = settings.memory {
+        args.push("-m".to_string());
+        args.push(memory.clone());
+    }
+    args
+}
+
+/// The QEMU arguments adding the device through which selfe-runtime's `exit`
+/// ends a simulation: `isa-debug-exit` on x86_64, and semihosting on arm
+pub fn exit_device_args(sel4_arch: SeL4Arch) -> Vec<&'static str> {
+    match sel4_arch {
+        SeL4Arch::X86_64 => vec!["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"],
+        SeL4Arch::Aarch32 | SeL4Arch::Aarch64 | SeL4Arch::ArmHyp => vec![
+            "-semihosting-config",
+            "enable=on,target=native,userspace=on",
+        ],
+        _ => vec![],
+    }
+}
+
+/// The code passed to selfe-runtime's `exit`, from the status QEMU exited
+/// with when it had the exit device. `isa-debug-exit` makes QEMU exit with
+/// `((code + 1) << 1) | 1`, while other statuses, like QEMU's own failures,
+/// are kept as they are.
+pub fn simulation_exit_code(sel4_arch: SeL4Arch, qemu_status: i32) -> i32 {
+    match sel4_arch {
+        SeL4Arch::X86_64 if qemu_status >= 3 && qemu_status % 2 == 1 => (qemu_status - 3) / 2,
+        _ => qemu_status,
+    }
+}
+
+/// A serial port of a simulation, as connected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialConnection {
+    /// The port number, from 0, as in `serial<N>`
+    pub index: usize,
+    /// "stdio", "file", "socket", "pty", "null", or "raw" for other QEMU arguments
+    pub kind: &'static str,
+    /// The QEMU `-serial` argument
+    pub qemu_arg: String,
+    /// The log file or socket, for those kinds
+    pub path: Option<PathBuf>,
+}
+
+/// The serial ports of the simulation, a single one on stdio unless configured.
+/// Log files and sockets without a path are put in `build_dir`.
+pub fn serial_connections(config: &Contextualized, build_dir: &Path) -> Vec<SerialConnection> {
+    let default_ports = [SerialPort::Stdio];
+    let ports = config.simulate.serial.as_deref().unwrap_or(&default_ports);
+    ports
+        .iter()
+        .enumerate()
+        .map(|(index, port)| {
+            let path_or = |path: &Option<PathBuf>, extension: &str| {
+                path.clone()
+                    .unwrap_or_else(|| build_dir.join(format!("serial{}.{}", index, extension)))
+            };
+            let (kind, qemu_arg, path) = match port {
+                SerialPort::Stdio => ("stdio", "mon:stdio".to_string(), None),
+                SerialPort::File(path) => {
+                    let path = path_or(path, "log");
+                    ("file", format!("file:{}", path.display()), Some(path))
+                }
+                SerialPort::Socket(path) => {
+                    let path = path_or(path, "sock");
+                    (
+                        "socket",
+                        format!("unix:{},server=on,wait=off", path.display()),
+                        Some(path),
+                    )
+                }
+                SerialPort::Pty => ("pty", "pty".to_string(), None),
+                SerialPort::Null => ("null", "null".to_string(), None),
+                SerialPort::Raw(arg) => ("raw", arg.clone(), None),
+            };
+            SerialConnection {
+                index,
+                kind,
+                qemu_arg,
+                path,
+            }
+        })
+        .collect()
+}
+
+/// How a platform's simulated machine takes devices
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceBus {
+    /// virtio PCI devices, as on pc99
+    Pci,
+    /// virtio MMIO devices, as on QEMU's arm virt machine
+    Mmio,
+    /// The board's own network and SD controllers, as on sabre, without virtio
+    Onboard,
+}
+
+fn device_bus(config: &Contextualized) -> Option<DeviceBus> {
+    match config.context.platform.to_string().as_str() {
+        "sabre" | "imx6" => Some(DeviceBus::Onboard),
+        "virt" | "qemu-arm-virt" | "tx1" => Some(DeviceBus::Mmio),
+        _ if config.context.sel4_arch == SeL4Arch::X86_64 => Some(DeviceBus::Pci),
+        _ => None,
+    }
+}
+
+/// The unix socket of the virtio console of a simulation
+pub fn virtio_console_path(build_dir: &Path) -> PathBuf {
+    build_dir.join("virtio-console.sock")
+}
+
+/// The QEMU arguments for the network, disk and virtio console configured for
+/// the platform, as virtio devices on pc99 and virt, and through the board's
+/// own controllers on sabre
+pub fn device_args(config: &Contextualized, build_dir: &Path) -> Result<Vec<String>, String> {
+    let settings = &config.simulate;
+    if settings.network.is_none()
+        && settings.disk.is_none()
+        && settings.virtio_console != Some(true)
+    {
+        return Ok(vec![]);
+    }
+    let bus = device_bus(config).ok_or_else(|| {
+        format!(
+            "Virtual devices are not supported for the {} platform, add them with extra_args",
+            config.context.platform
+        )
+    })?;
+    let virtio = |device: &str| match bus {
+        DeviceBus::Pci => format!("virtio-{}-pci", device),
+        _ => format!("virtio-{}-device", device),
+    };
+    let mut args = Vec::new();
+    if let Some(ref network) = settings.network {
+        let mut netdev = "user".to_string();
+        for forward in network.forwards.iter() {
+            let protocol = match forward.protocol {
+                Protocol::Tcp => "tcp",
+                Protocol::Udp => "udp",
+            };
+            netdev.push_str(&format!(
+                ",hostfwd={}::{}-:{}",
+                protocol, forward.host_port, forward.guest_port
+            ));
+        }
+        if bus == DeviceBus::Onboard {
+            args.extend(vec!["-nic".to_string(), netdev]);
+        } else {
+            args.extend(vec![
+                "-netdev".to_string(),
+                format!("{},id=net0", netdev),
+                "-device".to_string(),
+                format!("{},netdev=net0", virtio("net")),
+            ]);
+        }
+    }
+    if let Some(ref disk) = settings.disk {
+        let file = format!("file={},format=raw", qemu_option_value(disk));
+        if bus == DeviceBus::Onboard {
+            args.extend(vec!["-drive".to_string(), format!("{},if=sd", file)]);
+        } else {
+            args.extend(vec![
+                "-drive".to_string(),
+                format!("{},if=none,id=disk0", file),
+                "-device".to_string(),
+                format!("{},drive=disk0", virtio("blk")),
+            ]);
+        }
+    }
+    if settings.virtio_console == Some(true) {
+        if bus == DeviceBus::Onboard {
+            return Err(format!(
+                "The {} platform has no virtio transport for a virtio console, use a serial port",
+                config.context.platform
+            ));
+        }
+        args.extend(vec![
+            "-device".to_string(),
+            virtio("serial"),
+            "-chardev".to_string(),
+            format!(
+                "socket,id=console0,path={},server=on,wait=off",
+                qemu_option_value(&virtio_console_path(build_dir))
+            ),
+            "-device".to_string(),
+            "virtconsole,chardev=console0".to_string(),
+        ]);
+    }
+    Ok(args)
+}
+
+/// A path as a value in a QEMU option list, where commas are doubled
+fn qemu_option_value(path: &Path) -> String {
+    path.display().to_string().replace(',', ",,")
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// The first artifact of `kind` in the boot, if any
+pub fn boot_artifact<'a>(boot: &Boot<'a>, kind: ArtifactKind) -> Option<&'a Artifact> {
+    crate::artifacts::find_artifact(boot.artifacts, kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Arch, NetworkSettings, Platform, SeL4Arch};
+    use std::path::PathBuf;
+
+    fn config() -> Contextualized {
+        Contextualized::from_full(
+            &crate::model::get_default_config(),
+            Arch::Arm,
+            SeL4Arch::Aarch32,
+            true,
+            Platform("sabre".to_string()),
+            None,
+        )
+        .unwrap()
+    }
+
+    fn outcome() -> SeL4BuildOutcome {
+        SeL4BuildOutcome::Kernel {
+            build_dir: PathBuf::from("/build"),
+            kernel_path: PathBuf::from("/build/images/root_task-image-arm-imx6"),
+            root_image_path: None,
+            artifacts: vec![Artifact {
+                kind: ArtifactKind::Dtb,
+                path: PathBuf::from("/build/kernel/kernel.dtb"),
+                size_bytes: 1,
+            }],
+        }
+    }
+
+    /// Records what it was asked to boot
+    #[derive(Default)]
+    struct Fake {
+        booted: Vec<(PathBuf, Option<PathBuf>, String)>,
+    }
+
+    impl RunBackend for Fake {
+        fn run(&mut self, boot: &Boot, config: &Contextualized) -> Result<(), String> {
+            self.booted.push((
+                boot.kernel_path.to_path_buf(),
+                boot.root_image_path.map(Path::to_path_buf),
+                config.context.platform.to_string(),
+            ));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backends_receive_the_build_images() {
+        let outcome = outcome();
+        let boot = Boot::from_outcome(&outcome).unwrap();
+        let mut backend = Fake::default();
+        let backend_ref: &mut dyn RunBackend = &mut backend;
+        backend_ref.run(&boot, &config()).unwrap();
+        assert_eq!(
+            vec![(
+                PathBuf::from("/build/images/root_task-image-arm-imx6"),
+                None,
+                "sabre".to_string()
+            )],
+            backend.booted
+        );
+        assert_eq!(
+            Path::new("/build/kernel/kernel.dtb"),
+            boot_artifact(&boot, ArtifactKind::Dtb).unwrap().path
+        );
+        assert!(Boot::from_outcome(&SeL4BuildOutcome::StaticLib {
+            build_dir: PathBuf::from("/build")
+        })
+        .is_none());
+    }
+
+    #[test]
+    fn command_templates_are_expanded() {
+        let outcome = outcome();
+        let boot = Boot::from_outcome(&outcome).unwrap();
+        let template = CommandTemplate::new(
+            "tftp-load {platform} {kernel} {root_image}".to_string(),
+            vec!["--board's".to_string()],
+        );
+        assert_eq!(
+            "tftp-load 'sabre' '/build/images/root_task-image-arm-imx6' '' '--board'\\''s'",
+            template.expand(&boot, &config())
+        );
+    }
+
+    #[test]
+    fn exit_codes_are_recovered_from_qemu() {
+        assert_eq!(0, simulation_exit_code(SeL4Arch::X86_64, 3));
+        assert_eq!(126, simulation_exit_code(SeL4Arch::X86_64, 255));
+        assert_eq!(1, simulation_exit_code(SeL4Arch::X86_64, 1));
+        assert_eq!(0, simulation_exit_code(SeL4Arch::X86_64, 0));
+        assert_eq!(3, simulation_exit_code(SeL4Arch::Aarch64, 3));
+        assert!(exit_device_args(SeL4Arch::Aarch32).contains(&"-semihosting-config"));
+    }
+
+    #[test]
+    fn serial_ports_are_connected() {
+        let mut config = config();
+        config.simulate.serial = Some(vec![
+            SerialPort::Stdio,
+            SerialPort::Socket(None),
+            SerialPort::File(Some(PathBuf::from("/logs/telemetry.bin"))),
+            SerialPort::Raw("tcp::4444,server=on".to_string()),
+        ]);
+        let connections = serial_connections(&config, Path::new("/build"));
+        let args: Vec<&str> = connections.iter().map(|c| c.qemu_arg.as_str()).collect();
+        assert_eq!(
+            vec![
+                "mon:stdio",
+                "unix:/build/serial1.sock,server=on,wait=off",
+                "file:/logs/telemetry.bin",
+                "tcp::4444,server=on"
+            ],
+            args
+        );
+        assert_eq!(
+            Some(PathBuf::from("/build/serial1.sock")),
+            connections[1].path
+        );
+        config.simulate.serial = None;
+        assert_eq!(
+            vec!["stdio"],
+            serial_connections(&config, Path::new("/build"))
+                .iter()
+                .map(|c| c.kind)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn machine_settings_default_by_arch_and_platform() {
+        let content = r#"[sel4]
+kernel = { path = "." }
+tools = { path = "." }
+util_libs = { path = "." }
+
+[build.imx6]
+[build.rockpro64]
+[build.tx1]
+"#;
+        let args = |platform: &str, arch, sel4_arch| {
+            let config = Contextualized::from_str(
+                content,
+                arch,
+                sel4_arch,
+                true,
+                Platform(platform.to_string()),
+                None,
+            )
+            .unwrap();
+            machine_args(&config.simulate)
+        };
+        assert_eq!(
+            vec!["-machine", "sabrelite", "-m", "2048M"],
+            args("imx6", Arch::Arm, SeL4Arch::Aarch32)
+        );
+        assert_eq!(
+            vec!["-smp", "1", "-m", "2048M"],
+            args("rockpro64", Arch::Arm, SeL4Arch::Aarch64)
+        );
+        assert_eq!(
+            vec![
+                "-machine",
+                "virt,gic_version=2",
+                "-machine",
+                "virtualization=true",
+                "-cpu",
+                "cortex-a57",
+                "-smp",
+                "1",
+                "-m",
+                "2048M"
+            ],
+            args("tx1", Arch::Arm, SeL4Arch::Aarch64)
+        );
+    }
+
+    #[test]
+    fn virtual_devices_suit_the_platform() {
+        let mut config = config();
+        assert!(device_args(&config, Path::new("/build"))
+            .unwrap()
+            .is_empty());
+        config.simulate.network = Some(NetworkSettings {
+            forwards: vec!["8080:80".parse().unwrap()],
+        });
+        config.simulate.disk = Some(PathBuf::from("/images/disk,1.img"));
+        assert_eq!(
+            vec![
+                "-nic",
+                "user,hostfwd=tcp::8080-:80",
+                "-drive",
+                "file=/images/disk,,1.img,format=raw,if=sd"
+            ],
+            device_args(&config, Path::new("/build")).unwrap()
+        );
+        config.simulate.virtio_console = Some(true);
+        assert!(device_args(&config, Path::new("/build")).is_err());
+
+        config.context.platform = Platform("pc99".to_string());
+        config.context.sel4_arch = SeL4Arch::X86_64;
+        assert_eq!(
+            vec![
+                "-netdev",
+                "user,hostfwd=tcp::8080-:80,id=net0",
+                "-device",
+                "virtio-net-pci,netdev=net0",
+                "-drive",
+                "file=/images/disk,,1.img,format=raw,if=none,id=disk0",
+                "-device",
+                "virtio-blk-pci,drive=disk0",
+                "-device",
+                "virtio-serial-pci",
+                "-chardev",
+                "socket,id=console0,path=/build/virtio-console.sock,server=on,wait=off",
+                "-device",
+                "virtconsole,chardev=console0"
+            ],
+            device_args(&config, Path::new("/build")).unwrap()
+        );
+
+        config.context.platform = Platform("qemu-arm-virt".to_string());
+        config.context.sel4_arch = SeL4Arch::Aarch64;
+        let args = device_args(&config, Path::new("/build")).unwrap();
+        assert!(args.contains(&"virtio-net-device,netdev=net0".to_string()));
+        assert!(args.contains(&"virtio-serial-device".to_string()));
+
+        config.context.platform = Platform("tx1".to_string());
+        assert_eq!(args, device_args(&config, Path::new("/build")).unwrap());
+    }
+
+    #[test]
+    fn command_template_failures_are_reported() {
+        let outcome = outcome();
+        let boot = Boot::from_outcome(&outcome).unwrap();
+        let config = config();
+        let mut ok = CommandTemplate::new(
+            "test \"$SELFE_DTB\" = /build/kernel/kernel.dtb -a {profile} = debug".to_string(),
+            vec![],
+        );
+        assert!(ok.run(&boot, &config).is_ok());
+        let mut failing = CommandTemplate::new("exit 3".to_string(), vec![]);
+        assert!(failing.run(&boot, &config).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+//! Noticing changes to the inputs of a build, for `selfe watch`
+//!
+//! The watched files are polled, comparing their modification times and sizes
+//! between snapshots, which needs nothing from the platform beyond `std::fs`.
+
+use crate::model::contextualized::Contextualized;
+use crate::model::SerialPort;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Names that are never watched: build output and version control
+pub const ALWAYS_IGNORED: &[&str] = &["target", ".git"];
+
+/// The modification time and size of each watched file
+type Snapshot = BTreeMap<PathBuf, (Option<SystemTime>, u64)>;
+
+/// Watches sel4.toml, the dir holding it, which is usually the root task
+/// crate, and the `[watch] paths` of `config`
+pub struct Watcher {
+    roots: Vec<PathBuf>,
+    ignored_names: Vec<String>,
+    /// Written by the simulation, rather than inputs to the build
+    ignored_paths: Vec<PathBuf>,
+    snapshot: Snapshot,
+}
+
+impl Watcher {
+    pub fn new(config: &Contextualized, config_file_path: &Path) -> Self {
+        let mut roots = vec![config_file_path.to_path_buf()];
+        if let Some(dir) = config_file_path.parent() {
+            roots.push(dir.to_path_buf());
+        }
+        roots.extend(config.watch.paths.iter().flatten().cloned());
+        let mut watcher = Watcher {
+            roots,
+            ignored_names: ALWAYS_IGNORED
+                .iter()
+                .map(ToString::to_string)
+                .chain(config.watch.ignore.iter().flatten().cloned())
+                .collect(),
+            ignored_paths: simulation_outputs(config),
+            snapshot: Snapshot::new(),
+        };
+        watcher.snapshot = watcher.take_snapshot();
+        watcher
+    }
+
+    /// The files changed, added or removed since the watcher was created or
+    /// last asked
+    pub fn changes(&mut self) -> Vec<PathBuf> {
+        let current = self.take_snapshot();
+        let mut changed: Vec<PathBuf> = current
+            .iter()
+            .filter(|(path, state)| self.snapshot.get(*path) != Some(state))
+            .map(|(path, _)| path.clone())
+            .collect();
+        changed.extend(
+            self.snapshot
+                .keys()
+                .filter(|path| !current.contains_key(*path))
+                .cloned(),
+        );
+        changed.sort();
+        self.snapshot = current;
+        changed
+    }
+
+    fn take_snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+        for root in self.roots.iter() {
+            self.visit(root, &mut snapshot);
+        }
+        snapshot
+    }
+
+    fn visit(&self, path: &Path, snapshot: &mut Snapshot) {
+        if self.is_ignored(path) {
+            return;
+        }
+        // Symlinked dirs are not followed, so links back up the tree are harmless
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return,
+        };
+        if metadata.is_dir() {
+            if let Ok(entries) = fs::read_dir(path) {
+                for entry in entries.flatten() {
+                    self.visit(&entry.path(), snapshot);
+                }
+            }
+        } else {
+            let metadata = fs::metadata(path).unwrap_or(metadata);
+            snapshot.insert(
+                path.to_path_buf(),
+                (metadata.modified().ok(), metadata.len()),
+            );
+        }
+    }
+
+    fn is_ignored(&self, path: &Path) -> bool {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return false,
+        };
+        self.ignored_paths.iter().any(|p| p == path)
+            || self.ignored_names.iter().any(|ignored| {
+                if let Some(suffix) = ignored.strip_prefix('*') {
+                    name.ends_with(suffix)
+                } else {
+                    name == ignored.as_str()
+                }
+            })
+    }
+}
+
+/// The files a simulation writes to, which are not inputs to its build:
+/// serial logs and sockets given a path, and the disk image
+pub fn simulation_outputs(config: &Contextualized) -> Vec<PathBuf> {
+    let serial_paths = config
+        .simulate
+        .serial
+        .iter()
+        .flatten()
+        .filter_map(|port| match port {
+            SerialPort::File(path) | SerialPort::Socket(path) => path.clone(),
+            _ => None,
+        });
+    serial_paths.chain(config.simulate.disk.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Arch, Platform, SeL4Arch};
+
+    fn config(base_dir: &Path) -> Contextualized {
+        let mut config = Contextualized::from_full(
+            &crate::model::get_default_config(),
+            Arch::Arm,
+            SeL4Arch::Aarch32,
+            true,
+            Platform("sabre".to_string()),
+            Some(base_dir),
+        )
+        .unwrap();
+        config.simulate.disk = Some(base_dir.join("disk.img"));
+        config.watch.ignore = Some(vec!["*.swp".to_string()]);
+        config
+    }
+
+    #[test]
+    fn changed_inputs_are_noticed() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("sel4.toml");
+        fs::write(&config_file, "").unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::create_dir_all(dir.path().join("target/debug")).unwrap();
+        let main = dir.path().join("src/main.rs");
+        fs::write(&main, "fn main() {}").unwrap();
+
+        let mut watcher = Watcher::new(&config(dir.path()), &config_file);
+        assert!(watcher.changes().is_empty());
+
+        fs::write(&main, "fn main() { loop {} }").unwrap();
+        fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+        fs::write(dir.path().join("target/debug/root-task"), "").unwrap();
+        fs::write(dir.path().join("src/.main.rs.swp"), "").unwrap();
+        fs::write(dir.path().join("disk.img"), "").unwrap();
+        assert_eq!(
+            vec![dir.path().join("src/lib.rs"), main.clone()],
+            watcher.changes()
+        );
+        assert!(watcher.changes().is_empty());
+
+        fs::remove_file(&main).unwrap();
+        assert_eq!(vec![main], watcher.changes());
+    }
+}
@@ -1,6 +1,6 @@
 use selfe_config::model::*;
 use std::collections::btree_map::BTreeMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 const EXAMPLE: &str = r#"[build.sabre.debug]
 make_root_task = 'cmake debug'
@@ -63,7 +63,8 @@ fn full_parse_happy_path() {
         SeL4Sources {
             kernel: RepoSource::LocalPath(PathBuf::from("./deps/seL4")),
             tools: RepoSource::LocalPath(PathBuf::from("./deps/seL4_tools")),
-            util_libs: RepoSource::LocalPath(PathBuf::from("./deps/util_libs"))
+            util_libs: RepoSource::LocalPath(PathBuf::from("./deps/util_libs")),
+            sel4_libs: None,
         },
         f.sel4.sources
     );
@@ -158,14 +159,15 @@ fn happy_path_straight_to_contextualized() {
         SeL4Sources {
             kernel: RepoSource::LocalPath(PathBuf::from("./deps/seL4")),
             tools: RepoSource::LocalPath(PathBuf::from("./deps/seL4_tools")),
-            util_libs: RepoSource::LocalPath(PathBuf::from("./deps/util_libs"))
+            util_libs: RepoSource::LocalPath(PathBuf::from("./deps/util_libs")),
+            sel4_libs: None,
         },
         f.sel4_sources
     );
     assert_eq!(Arch::Arm, f.context.arch);
     assert_eq!(SeL4Arch::Aarch32, f.context.sel4_arch);
     assert_eq!(Platform("sabre".to_owned()), f.context.platform);
-    assert_eq!(true, f.context.is_debug);
+    assert!(f.context.is_debug);
     println!("{:#?}", f.sel4_config);
     assert_eq!(5, f.sel4_config.len());
     assert_eq!(
@@ -314,3 +316,506 @@ fn assert_contains_int(map: &BTreeMap<String, SingleValue>, key: &str, val: i64)
             .unwrap_or_else(|| panic!("Did not contain expected key {}", key))
     );
 }
+
+const WITH_PATCHES: &str = r#"[sel4.kernel]
+git = 'https://github.com/seL4/seL4'
+rev = '4d0f02c029560cae0e8d93727eb17d58bcecc2ac'
+patches = ['patches/virt.patch', 'patches/debug-hooks.patch']
+
+[sel4.tools]
+git = 'https://github.com/seL4/seL4_tools'
+branch = 'master'
+
+[sel4.util_libs]
+path = './deps/util_libs'
+"#;
+
+#[test]
+fn patches_round_trip() {
+    assert_round_trip_equivalence(WITH_PATCHES, false);
+}
+
+#[test]
+fn patches_are_contextualized_relative_to_config_dir() {
+    let mut f: full::Full = WITH_PATCHES.parse().expect("could not read toml");
+    f.build.insert("sabre".to_string(), Default::default());
+    let c = contextualized::Contextualized::from_full(
+        &f,
+        Arch::Arm,
+        SeL4Arch::Aarch32,
+        true,
+        Platform("sabre".to_string()),
+        Some(std::path::Path::new("/project")),
+    )
+    .expect("Could not contextualize");
+    assert_eq!(
+        &[
+            PathBuf::from("/project/patches/virt.patch"),
+            PathBuf::from("/project/patches/debug-hooks.patch")
+        ],
+        c.sel4_sources.kernel.patches()
+    );
+    assert!(c.sel4_sources.tools.patches().is_empty());
+}
+
+#[test]
+fn patches_not_allowed_for_local_paths() {
+    let content = r#"[sel4]
+kernel = { path = './deps/seL4', patches = ['patches/virt.patch'] }
+tools = { path = './deps/seL4_tools' }
+util_libs = { path = './deps/util_libs' }
+"#;
+    let result: Result<full::Full, ImportError> = content.parse();
+    match result {
+        Err(ImportError::UnsupportedProperties { extra_keys }) => {
+            assert_eq!(vec!["patches".to_string()], extra_keys)
+        }
+        other => panic!("Expected an UnsupportedProperties error, found {:?}", other),
+    }
+}
+
+#[test]
+fn generator_round_trip() {
+    let content = r#"[sel4]
+kernel = { path = './deps/seL4' }
+tools = { path = './deps/seL4_tools' }
+util_libs = { path = './deps/util_libs' }
+
+[build.sabre]
+generator = 'Unix Makefiles'
+"#;
+    assert_round_trip_equivalence(content, false);
+    let f: full::Full = content.parse().expect("could not read toml");
+    assert_eq!(
+        Some(Generator::UnixMakefiles),
+        f.build.get("sabre").unwrap().generator
+    );
+}
+
+#[test]
+fn unknown_generator_is_rejected() {
+    let content = r#"[sel4]
+kernel = { path = './deps/seL4' }
+tools = { path = './deps/seL4_tools' }
+util_libs = { path = './deps/util_libs' }
+
+[build.sabre]
+generator = 'Xcode'
+"#;
+    let result: Result<full::Full, ImportError> = content.parse();
+    match result {
+        Err(ImportError::UnsupportedValue { name, value, .. }) => {
+            assert_eq!("generator", name);
+            assert_eq!("Xcode", value);
+        }
+        other => panic!("Expected an UnsupportedValue error, found {:?}", other),
+    }
+}
+
+const WITH_LIBRARIES: &str = r#"[sel4]
+libraries = ['utils', 'platsupport', 'sel4platsupport']
+
+[sel4.kernel]
+path = './deps/seL4'
+
+[sel4.tools]
+path = './deps/seL4_tools'
+
+[sel4.util_libs]
+path = './deps/util_libs'
+
+[sel4.sel4_libs]
+path = './deps/seL4_libs'
+"#;
+
+#[test]
+fn libraries_round_trip() {
+    assert_round_trip_equivalence(WITH_LIBRARIES, false);
+}
+
+#[test]
+fn libraries_are_contextualized() {
+    let mut f: full::Full = WITH_LIBRARIES.parse().expect("could not read toml");
+    assert_eq!(
+        Some(RepoSource::LocalPath(PathBuf::from("./deps/seL4_libs"))),
+        f.sel4.sources.sel4_libs
+    );
+    f.build.insert("sabre".to_string(), Default::default());
+    let c = contextualized::Contextualized::from_full(
+        &f,
+        Arch::Arm,
+        SeL4Arch::Aarch32,
+        true,
+        Platform("sabre".to_string()),
+        Some(std::path::Path::new("/project")),
+    )
+    .expect("Could not contextualize");
+    assert_eq!(vec!["utils", "platsupport", "sel4platsupport"], c.libraries);
+    assert_eq!(
+        Some(RepoSource::LocalPath(PathBuf::from(
+            "/project/./deps/seL4_libs"
+        ))),
+        c.sel4_sources.sel4_libs
+    );
+    assert_eq!(4, c.sel4_sources.named().len());
+}
+
+const WITH_TARGETS: &str = r#"[sel4]
+kernel = { path = './deps/seL4' }
+tools = { path = './deps/seL4_tools' }
+util_libs = { path = './deps/util_libs' }
+
+[[targets]]
+platform = 'sabre'
+sel4_arch = 'aarch32'
+
+[[targets]]
+platform = 'virt'
+sel4_arch = 'aarch64'
+profile = 'release'
+"#;
+
+#[test]
+fn targets_expand_to_each_profile() {
+    let f: full::Full = WITH_TARGETS.parse().expect("could not read toml");
+    assert_eq!(
+        vec![
+            full::Target {
+                platform: Platform("sabre".to_string()),
+                sel4_arch: SeL4Arch::Aarch32,
+                is_debug: true,
+            },
+            full::Target {
+                platform: Platform("sabre".to_string()),
+                sel4_arch: SeL4Arch::Aarch32,
+                is_debug: false,
+            },
+            full::Target {
+                platform: Platform("virt".to_string()),
+                sel4_arch: SeL4Arch::Aarch64,
+                is_debug: false,
+            },
+        ],
+        f.targets
+    );
+    assert_round_trip_equivalence(WITH_TARGETS, false);
+}
+
+#[test]
+fn unknown_target_profile_is_rejected() {
+    let content = WITH_TARGETS.replace("'release'", "'fast'");
+    let result: Result<full::Full, ImportError> = content.parse();
+    match result {
+        Err(ImportError::UnsupportedValue { name, value, .. }) => {
+            assert_eq!("profile", name);
+            assert_eq!("fast", value);
+        }
+        other => panic!("Expected an UnsupportedValue error, found {:?}", other),
+    }
+}
+
+#[test]
+fn platforms_supply_default_sel4_arch() {
+    let content = r#"[sel4]
+kernel = { path = './deps/seL4' }
+tools = { path = './deps/seL4_tools' }
+util_libs = { path = './deps/util_libs' }
+
+[build.sabre]
+sel4_arch = 'aarch32'
+
+[build.virt]
+rust_target = 'aarch64-unknown-linux-gnu'
+
+[build.pc99]
+
+[[targets]]
+platform = 'virt'
+profile = 'debug'
+"#;
+    assert_round_trip_equivalence(content, false);
+    let f: full::Full = content.parse().expect("could not read toml");
+    assert_eq!(
+        Some(SeL4Arch::Aarch32),
+        f.default_sel4_arch(&Platform("sabre".to_string()))
+    );
+    assert_eq!(
+        Some(SeL4Arch::Aarch64),
+        f.default_sel4_arch(&Platform("virt".to_string()))
+    );
+    assert_eq!(None, f.default_sel4_arch(&Platform("pc99".to_string())));
+    assert_eq!(SeL4Arch::Aarch64, f.targets[0].sel4_arch);
+
+    let missing = content.replace("platform = 'virt'", "platform = 'pc99'");
+    match missing.parse::<full::Full>() {
+        Err(ImportError::MissingProperty { name, .. }) => assert!(name.contains("pc99")),
+        other => panic!("Expected a MissingProperty error, found {:?}", other),
+    }
+}
+
+#[test]
+fn run_backends_are_contextualized() {
+    let content = r#"[sel4]
+kernel = { path = './deps/seL4' }
+tools = { path = './deps/seL4_tools' }
+util_libs = { path = './deps/util_libs' }
+
+[build.sabre]
+[build.pc99]
+
+[run.sabre]
+backend = 'command'
+command = './lab/tftp-boot.sh {kernel}'
+"#;
+    assert_round_trip_equivalence(content, false);
+    let f: full::Full = content.parse().expect("could not read toml");
+    let contextualize = |platform: &str, arch, sel4_arch| {
+        contextualized::Contextualized::from_full(
+            &f,
+            arch,
+            sel4_arch,
+            true,
+            Platform(platform.to_string()),
+            None,
+        )
+        .expect("Could not contextualize")
+    };
+    assert_eq!(
+        Runner::Command("./lab/tftp-boot.sh {kernel}".to_string()),
+        contextualize("sabre", Arch::Arm, SeL4Arch::Aarch32).run
+    );
+    assert_eq!(
+        Runner::Qemu,
+        contextualize("pc99", Arch::X86, SeL4Arch::X86_64).run
+    );
+
+    let missing_command = content.replace("command = './lab/tftp-boot.sh {kernel}'", "");
+    match missing_command.parse::<full::Full>() {
+        Err(ImportError::MissingProperty { name, .. }) => assert_eq!("command", name),
+        other => panic!("Expected a MissingProperty error, found {:?}", other),
+    }
+}
+
+#[test]
+fn simulate_settings_are_contextualized_per_profile() {
+    let content = r#"[sel4]
+kernel = { path = './deps/seL4' }
+tools = { path = './deps/seL4_tools' }
+util_libs = { path = './deps/util_libs' }
+
+[build.virt]
+
+[simulate.virt]
+machine = ["virt,gic_version=2", "virtualization=true"]
+cpu = "cortex-a57"
+smp = 1
+memory = "2048M"
+extra_args = ["-d", "guest_errors"]
+
+[simulate.virt.release]
+smp = 4
+exit_device = true
+"#;
+    assert_round_trip_equivalence(content, false);
+    let f: full::Full = content.parse().expect("could not read toml");
+    let contextualize = |is_debug| {
+        contextualized::Contextualized::from_full(
+            &f,
+            Arch::Arm,
+            SeL4Arch::Aarch64,
+            is_debug,
+            Platform("virt".to_string()),
+            None,
+        )
+        .expect("Could not contextualize")
+        .simulate
+    };
+    let debug = contextualize(true);
+    assert_eq!(Some(1), debug.smp);
+    assert_eq!(Some("cortex-a57".to_string()), debug.cpu);
+    assert_eq!(None, debug.serial);
+    let release = contextualize(false);
+    assert_eq!(Some(4), release.smp);
+    assert_eq!(None, debug.exit_device);
+    assert_eq!(Some(true), release.exit_device);
+    assert_eq!(Some("2048M".to_string()), release.memory);
+    assert_eq!(
+        Some(vec!["-d".to_string(), "guest_errors".to_string()]),
+        release.extra_args
+    );
+}
+
+#[test]
+fn simulate_settings_default_when_absent() {
+    let content = r#"[sel4]
+kernel = { path = './deps/seL4' }
+tools = { path = './deps/seL4_tools' }
+util_libs = { path = './deps/util_libs' }
+
+[build.sabre]
+cross_compiler_prefix = "arm-linux-gnueabihf-"
+"#;
+    let f: full::Full = content.parse().expect("could not read toml");
+    assert!(f.simulate.is_empty());
+    let simulate = contextualized::Contextualized::from_full(
+        &f,
+        Arch::Arm,
+        SeL4Arch::Aarch32,
+        true,
+        Platform("sabre".to_string()),
+        None,
+    )
+    .expect("Could not contextualize")
+    .simulate;
+    assert_eq!(Some(vec!["sabrelite".to_string()]), simulate.machine);
+    assert_eq!(Some("2048M".to_string()), simulate.memory);
+    assert_eq!(
+        Some(vec![
+            SerialPort::Null,
+            SerialPort::Raw("mon:stdio".to_string())
+        ]),
+        simulate.serial
+    );
+}
+
+#[test]
+fn test_expectations_round_trip() {
+    let content = r#"[sel4]
+kernel = { path = './deps/seL4' }
+tools = { path = './deps/seL4_tools' }
+util_libs = { path = './deps/util_libs' }
+
+[build.virt]
+
+[test]
+success = ["all tests passed"]
+timeout_secs = 60
+"#;
+    assert_round_trip_equivalence(content, false);
+    let f: full::Full = content.parse().expect("could not read toml");
+    assert_eq!(Some(vec!["all tests passed".to_string()]), f.test.success);
+    assert_eq!(None, f.test.failure);
+    assert_eq!(Some(60), f.test.timeout_secs);
+    let bad = content.replace("timeout_secs = 60", "timeout_secs = 0");
+    assert!(bad.parse::<full::Full>().is_err());
+}
+
+#[test]
+fn serial_ports_are_structured() {
+    let content = r#"[sel4]
+kernel = { path = './deps/seL4' }
+tools = { path = './deps/seL4_tools' }
+util_libs = { path = './deps/util_libs' }
+
+[build.sabre]
+
+[simulate.sabre]
+serial = ["stdio", { socket = "telemetry.sock" }, "file", "pty", "null", "tcp::4444,server=on"]
+"#;
+    assert_round_trip_equivalence(content, false);
+    let c = contextualized::Contextualized::from_str(
+        content,
+        Arch::Arm,
+        SeL4Arch::Aarch32,
+        true,
+        Platform("sabre".to_string()),
+        Some(Path::new("/app")),
+    )
+    .expect("Could not contextualize");
+    assert_eq!(
+        Some(vec![
+            SerialPort::Stdio,
+            SerialPort::Socket(Some(PathBuf::from("/app/telemetry.sock"))),
+            SerialPort::File(None),
+            SerialPort::Pty,
+            SerialPort::Null,
+            SerialPort::Raw("tcp::4444,server=on".to_string()),
+        ]),
+        c.simulate.serial
+    );
+    let bad = content.replace(
+        "{ socket = \"telemetry.sock\" }",
+        "{ tty = \"/dev/ttyS0\" }",
+    );
+    assert!(bad.parse::<full::Full>().is_err());
+}
+
+#[test]
+fn virtual_devices_are_configured() {
+    let content = r#"[sel4]
+kernel = { path = './deps/seL4' }
+tools = { path = './deps/seL4_tools' }
+util_libs = { path = './deps/util_libs' }
+
+[build.pc99]
+
+[simulate.pc99]
+disk = "images/disk.img"
+virtio_console = true
+
+[simulate.pc99.network]
+forwards = ["tcp:8080:80", "5555:5555", "udp:5353:53"]
+
+[simulate.pc99.release]
+virtio_console = false
+"#;
+    assert_round_trip_equivalence(content, false);
+    let c = contextualized::Contextualized::from_str(
+        content,
+        Arch::X86,
+        SeL4Arch::X86_64,
+        false,
+        Platform("pc99".to_string()),
+        Some(Path::new("/app")),
+    )
+    .expect("Could not contextualize");
+    assert_eq!(Some(PathBuf::from("/app/images/disk.img")), c.simulate.disk);
+    assert_eq!(Some(false), c.simulate.virtio_console);
+    let forwards = c.simulate.network.expect("network is configured").forwards;
+    assert_eq!(
+        PortForward {
+            protocol: Protocol::Udp,
+            host_port: 5353,
+            guest_port: 53
+        },
+        forwards[2]
+    );
+    assert_eq!("tcp:5555:5555", forwards[1].to_string());
+    let bad = content.replace("udp:5353:53", "udp:5353");
+    assert!(bad.parse::<full::Full>().is_err());
+}
+
+#[test]
+fn watch_settings_are_contextualized() {
+    let content = r#"[sel4]
+kernel = { path = './deps/seL4' }
+tools = { path = './deps/seL4_tools' }
+util_libs = { path = './deps/util_libs' }
+
+[build.sabre]
+
+[watch]
+paths = ["../assets", "/opt/data"]
+ignore = ["*.swp", "node_modules"]
+"#;
+    assert_round_trip_equivalence(content, false);
+    let c = contextualized::Contextualized::from_str(
+        content,
+        Arch::Arm,
+        SeL4Arch::Aarch32,
+        true,
+        Platform("sabre".to_string()),
+        Some(Path::new("/app")),
+    )
+    .expect("Could not contextualize");
+    assert_eq!(
+        Some(vec![
+            PathBuf::from("/app/../assets"),
+            PathBuf::from("/opt/data")
+        ]),
+        c.watch.paths
+    );
+    assert_eq!(2, c.watch.ignore.map(|i| i.len()).unwrap_or_default());
+    let bad = content.replace("[\"../assets\", \"/opt/data\"]", "\"../assets\"");
+    assert!(bad.parse::<full::Full>().is_err());
+}
@@ -0,0 +1,104 @@
+//! Ending a simulation with an exit code, for `selfe simulate` and `selfe test`.
+//!
+//! On x86_64, the code is written to QEMU's `isa-debug-exit` device at port
+//! 0xf4. QEMU exits with `(value << 1) | 1` for a written value, so `code + 1`
+//! is written, leaving QEMU's own failure status of 1 distinguishable, and
+//! `selfe` reports `(status - 3) / 2`. That only fits a process exit status
+//! for codes up to 126, so larger ones are reported as 126. On arm and
+//! aarch64, the code is handed to QEMU's semihosting `SYS_EXIT`, which QEMU
+//! exits with as-is. That needs QEMU 7.1 or later, to allow semihosting from
+//! user mode.
+//!
+//! These devices only exist in simulation, and QEMU only provides them when
+//! the platform's `[simulate.*]` table sets `exit_device = true`, so this
+//! module is behind the `simulation` feature, to keep it out of hardware
+//! builds.
+
+use selfe_sys::*;
+
+/// End the simulation with `code`, which `selfe` exits with in turn.
+///
+/// `bootinfo` is that of the root task, which is the only thread holding the
+/// capabilities needed on x86_64. Its last empty slot is used there.
+pub fn exit(code: u8, bootinfo: &seL4_BootInfo) -> ! {
+    imp::exit(code, bootinfo);
+    loop {
+        unsafe { seL4_TCB_Suspend(seL4_CapInitThreadTCB as seL4_CPtr) };
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod imp {
+    use selfe_sys::*;
+
+    const DEBUG_EXIT_PORT: seL4_Word = 0xf4;
+
+    pub fn exit(code: u8, bootinfo: &seL4_BootInfo) {
+        let slot = bootinfo.empty.end - 1;
+        let depth = (core::mem::size_of::<seL4_Word>() * 8) as u8;
+        unsafe {
+            let err = seL4_X86_IOPortControl_Issue(
+                seL4_CapIOPortControl as seL4_CPtr,
+                DEBUG_EXIT_PORT,
+                DEBUG_EXIT_PORT + 3,
+                seL4_CapInitThreadCNode as seL4_CPtr,
+                slot,
+                depth,
+            );
+            if err == 0 {
+                let value = code.min(126) as seL4_Word + 1;
+                seL4_X86_IOPort_Out32(slot, DEBUG_EXIT_PORT, value);
+            }
+        }
+    }
+}
+
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+mod imp {
+    use core::arch::asm;
+    use selfe_sys::seL4_BootInfo;
+
+    #[cfg(target_arch = "aarch64")]
+    const SYS_EXIT: usize = 0x18;
+    #[cfg(target_arch = "arm")]
+    const SYS_EXIT_EXTENDED: usize = 0x20;
+    const ADP_STOPPED_APPLICATION_EXIT: usize = 0x20026;
+
+    pub fn exit(code: u8, _bootinfo: &seL4_BootInfo) {
+        let block: [usize; 2] = [ADP_STOPPED_APPLICATION_EXIT, code as usize];
+        unsafe { semihosting_call(operation(), block.as_ptr() as usize) };
+    }
+
+    /// On aarch32, only the extended call carries an exit code
+    #[cfg(target_arch = "arm")]
+    fn operation() -> usize {
+        SYS_EXIT_EXTENDED
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn operation() -> usize {
+        SYS_EXIT
+    }
+
+    #[cfg(target_arch = "arm")]
+    unsafe fn semihosting_call(op: usize, param: usize) -> usize {
+        let result;
+        asm!("svc #0x123456", inout("r0") op => result, in("r1") param);
+        result
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe fn semihosting_call(op: usize, param: usize) -> usize {
+        let result;
+        asm!("hlt #0xf000", inout("x0") op => result, in("x1") param);
+        result
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "arm", target_arch = "aarch64")))]
+mod imp {
+    use selfe_sys::seL4_BootInfo;
+
+    /// No simulated exit device here, so the thread is only suspended
+    pub fn exit(_code: u8, _bootinfo: &seL4_BootInfo) {}
+}
@@ -2,6 +2,11 @@
 #![feature(core_intrinsics)]
 
 pub mod debug;
+#[cfg(feature = "simulation")]
+pub mod exit;
+
+#[cfg(feature = "simulation")]
+pub use exit::exit;
 
 #[cfg(feature = "panic_handler")]
 mod panic;